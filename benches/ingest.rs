@@ -0,0 +1,75 @@
+//! Ingest throughput for 100k posts, run with `cargo bench --bench ingest`
+use std::time::{Duration, Instant};
+
+use scatterbrain_flutter::api::db::{
+    connection::{Crud, OnConflict, SubrosaDb},
+    entities::{NewsGroup, Posts},
+    migrations::run_migrations,
+};
+use uuid::Uuid;
+
+const POSTS: usize = 100_000;
+/// Autocommit inserts wait for a sync on every row, so only a sample is timed
+const AUTOCOMMIT_POSTS: usize = 5_000;
+
+fn posts(db: &SubrosaDb, n: usize) -> Vec<Posts> {
+    let group = NewsGroup::new(
+        Uuid::new_v4(),
+        "".to_owned(),
+        None,
+        "bench".to_owned(),
+        false,
+    );
+    db.insert_group(&group).unwrap();
+    (0..n)
+        .map(|i| Posts::new(format!("post {}", i), "x".repeat(256), &group.uuid))
+        .collect()
+}
+
+fn bench(name: &str, n: usize, f: impl FnOnce(&SubrosaDb, &[Posts]) -> anyhow::Result<()>) {
+    let path = std::env::temp_dir().join(format!("subrosa-bench-{}.db", Uuid::new_v4()));
+    let db = SubrosaDb::new(path.to_str().unwrap()).unwrap();
+    run_migrations(&db).unwrap();
+    let posts = posts(&db, n);
+
+    let start = Instant::now();
+    f(&db, &posts).unwrap();
+    report(name, n, start.elapsed());
+
+    drop(db);
+    for ext in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", path.display(), ext));
+    }
+}
+
+fn report(name: &str, n: usize, elapsed: Duration) {
+    println!(
+        "{:<28} {:>7} posts {:>9.1} ms {:>10.0} posts/s",
+        name,
+        n,
+        elapsed.as_secs_f64() * 1000.0,
+        n as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    bench("insert_on_conflict", AUTOCOMMIT_POSTS, |db, posts| {
+        for post in posts {
+            post.insert_on_conflict(db, OnConflict::Ignore)?;
+        }
+        Ok(())
+    });
+
+    bench("insert_on_conflict in tx", POSTS, |db, posts| {
+        db.transaction(|tx| {
+            for post in posts {
+                post.insert_on_conflict(tx, OnConflict::Ignore)?;
+            }
+            Ok(())
+        })
+    });
+
+    bench("insert_many_on_conflict", POSTS, |db, posts| {
+        Posts::insert_many_on_conflict(posts, db, OnConflict::Ignore)
+    });
+}
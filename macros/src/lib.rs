@@ -1,12 +1,18 @@
 use core::panic;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 use convert_case::{Case, Casing};
 use proc_macro::TokenStream;
 use quote::{quote, ToTokens};
+use rusqlite::Connection;
 use syn::{
-    parse_macro_input, AngleBracketedGenericArguments, DeriveInput, FnArg, GenericArgument, Ident,
-    ItemTrait, LitStr, Pat, PatType, PathArguments, PathSegment, Receiver, ReturnType, TraitItem,
-    Type, TypePath, TypeReference,
+    ext::IdentExt, parse_macro_input, AngleBracketedGenericArguments, Attribute, Block,
+    DeriveInput, Expr, Field, FnArg, GenericArgument, Ident, ItemTrait, LitStr, Pat, PatType,
+    PathArguments, PathSegment, Receiver, ReturnType, Signature, TraitItem, TraitItemFn, Type,
+    TypePath, TypeReference,
 };
 
 fn get_table_attr(input: &DeriveInput, name: &str) -> String {
@@ -34,110 +40,189 @@ fn get_table_attr(input: &DeriveInput, name: &str) -> String {
 //         .unwrap_or_else(|| name.to_case(Case::Snake))
 // }
 
-fn generate_parameter_list(st: &[ColumnField], delimeter: &str, prefix: Option<&str>) -> String {
-    let mut insert = String::with_capacity(
-        st.iter()
-            .map(|v| v.get_ident())
-            .map(|v| v.span().source_text().map(|v| v.len()).unwrap_or(0))
-            .sum::<usize>()
-            + delimeter.len() * st.len(),
-    );
-    for (c, name) in st.iter().enumerate() {
-        if let Some(prefix) = prefix {
-            insert.push_str(prefix);
-        }
-        insert.push_str(&name.get_ident().to_string());
-        if c < st.len() - 1 {
-            insert.push_str(delimeter);
-        }
-    }
-    insert
+fn generate_parameter_list(st: &[&ColumnField], delimeter: &str, prefix: Option<&str>) -> String {
+    st.iter()
+        .map(|v| format!("{}{}", prefix.unwrap_or(""), v.column))
+        .collect::<Vec<String>>()
+        .join(delimeter)
 }
 
 fn generate_crud_impl(st: &[ColumnField], name: &Ident, table_attr: &str) -> impl ToTokens {
-    let types = generate_parameter_list(st, ", ", None);
-    let values = generate_parameter_list(st, ", ", Some(":"));
-    let primary = st
+    let st = st.iter().filter(|v| !v.skip).collect::<Vec<_>>();
+    let types = generate_parameter_list(&st, ", ", None);
+    let values = generate_parameter_list(&st, ", ", Some(":"));
+    let primary_fields = st.iter().copied().filter(|p| p.primary).collect::<Vec<_>>();
+    if primary_fields.is_empty() {
+        panic!("cannot update without primary key");
+    }
+    let primary = generate_parameter_list(&primary_fields, ", ", None);
+    let primary_idents = primary_fields.iter().map(|v| &v.ident);
+    let primary_strs = primary_fields.iter().map(|v| format!(":{}", v.column));
+    let primary_match = primary_fields
         .iter()
-        .find(|p| p.is_primary())
-        .map(|v| v.get_ident())
-        .expect("cannot update without primary key");
+        .map(|v| format!("{} = :{}", v.column, v.column))
+        .collect::<Vec<String>>()
+        .join(" AND ");
 
-    let primary_str = format!(":{}", primary);
     let cols = st
         .iter()
-        .map(|v| v.get_ident())
-        .map(|v| format!("{} = :{}", v, v))
+        .map(|v| format!("{} = :{}", v.column, v.column))
         .collect::<Vec<String>>()
         .join(",");
 
     let insert = format!("INSERT INTO {} ({}) values ({})", table_attr, types, values);
 
+    // an upsert rather than REPLACE, which deletes the old row and with it
+    // anything referencing it through a cascading foreign key
+    let excluded = st
+        .iter()
+        .map(|v| format!("{} = excluded.{}", v.column, v.column))
+        .collect::<Vec<String>>()
+        .join(",");
     let insert_update = format!(
-        "INSERT OR REPLACE INTO {} ({}) values ({})",
-        table_attr, types, values
+        "INSERT INTO {} ({}) values ({}) ON CONFLICT({}) DO UPDATE SET {}",
+        table_attr, types, values, primary, excluded
     );
     let insert_ignore = format!(
         "INSERT INTO {} ({}) values ({}) ON CONFLICT({}) DO NOTHING",
         table_attr, types, values, primary
     );
 
-    let update = format!(
-        "UPDATE {} set {} where {} = :{}",
-        table_attr, cols, primary, primary
-    );
+    let update = format!("UPDATE {} set {} where {}", table_attr, cols, primary_match);
 
-    let delete = format!(
-        "DELETE FROM {}  where {} = :{}",
-        table_attr, primary, primary
-    );
+    let delete = format!("DELETE FROM {}  where {}", table_attr, primary_match);
 
     // panic!("{}", insert);
     quote! {
         #[automatically_derived]
         impl crate::api::db::connection::Crud for #name {
-            fn insert(&self, conn: &crate::api::db::connection::SubrosaDb) -> anyhow::Result<()> {
+            fn insert(&self, conn: &impl crate::api::db::connection::Dao) -> anyhow::Result<()> {
                 use crate::api::db::entities::GetParams;
-                conn.0.conn.lock().unwrap().execute(#insert, self.get_params().as_slice())?;
+                conn.get_connection().prepare_cached(#insert)?.execute(self.get_params().as_slice())?;
                 Ok(())
             }
 
-            fn insert_on_conflict(&self, conn: &crate::api::db::connection::SubrosaDb, on_conflict: crate::api::db::connection::OnConflict) -> anyhow::Result<()> {
+            fn insert_on_conflict(&self, conn: &impl crate::api::db::connection::Dao, on_conflict: crate::api::db::connection::OnConflict) -> anyhow::Result<()> {
                 use crate::api::db::entities::GetParams;
-                match on_conflict {
-                    crate::api::db::connection::OnConflict::Abort => conn.0.conn.lock().unwrap().execute(#insert, self.get_params().as_slice())?,
-                    crate::api::db::connection::OnConflict::Ignore => conn.0.conn.lock().unwrap().execute(#insert_ignore, self.get_params().as_slice())?,
-                    crate::api::db::connection::OnConflict::Update => conn.0.conn.lock().unwrap().execute(#insert_update, self.get_params().as_slice())?
+                let sql = match on_conflict {
+                    crate::api::db::connection::OnConflict::Abort => #insert,
+                    crate::api::db::connection::OnConflict::Ignore => #insert_ignore,
+                    crate::api::db::connection::OnConflict::Update => #insert_update,
                 };
+                conn.get_connection().prepare_cached(sql)?.execute(self.get_params().as_slice())?;
                 Ok(())
             }
 
-            fn update(&self, conn: &crate::api::db::connection::SubrosaDb) -> anyhow::Result<()> {
+            fn insert_many_on_conflict(items: &[Self], conn: &impl crate::api::db::connection::Dao, on_conflict: crate::api::db::connection::OnConflict) -> anyhow::Result<()> {
                 use crate::api::db::entities::GetParams;
-                conn.0.conn.lock().unwrap().execute(#update, self.get_params().as_slice())?;
-                Ok(())
+                let sql = match on_conflict {
+                    crate::api::db::connection::OnConflict::Abort => #insert,
+                    crate::api::db::connection::OnConflict::Ignore => #insert_ignore,
+                    crate::api::db::connection::OnConflict::Update => #insert_update,
+                };
+                let conn = conn.get_connection();
+                crate::api::db::connection::savepoint(&conn, || {
+                    let mut st = conn.prepare_cached(sql)?;
+                    for item in items {
+                        st.execute(item.get_params().as_slice())?;
+                    }
+                    Ok(())
+                })
             }
 
-            fn delete(self, conn: &crate::api::db::connection::SubrosaDb) -> anyhow::Result<()> {
+            fn update(&self, conn: &impl crate::api::db::connection::Dao) -> anyhow::Result<()> {
                 use crate::api::db::entities::GetParams;
-                conn.0.conn.lock().unwrap().execute(#delete, &[(#primary_str, &self . #primary)])?;
+                conn.get_connection().prepare_cached(#update)?.execute(self.get_params().as_slice())?;
+                Ok(())
+            }
+
+            fn delete(self, conn: &impl crate::api::db::connection::Dao) -> anyhow::Result<()> {
+                let params: &[(&str, &dyn ::rusqlite::ToSql)] = &[ #( (#primary_strs, &self . #primary_idents) ),* ];
+                conn.get_connection().prepare_cached(#delete)?.execute(params)?;
                 Ok(())
             }
         }
     }
 }
 
-fn generate_from_row(st: &[ColumnField], name: &Ident) -> impl ToTokens {
-    let rows = st.iter().map(|v| v.get_ident()).enumerate().map(|(n, f)| {
+fn generate_table_impl(st: &[ColumnField], name: &Ident, table_attr: &str) -> impl ToTokens {
+    let columns = st.iter().filter(|v| !v.skip).map(|v| {
+        let ColumnField {
+            ty,
+            column,
+            primary,
+            ..
+        } = v;
         quote! {
-            #f: row.get(#n)?
+            crate::api::db::entities::Column {
+                name: #column,
+                sql_type: <#ty as crate::api::db::entities::SqlType>::SQL_TYPE,
+                nullable: <#ty as crate::api::db::entities::SqlType>::NULLABLE,
+                primary: #primary,
+            }
         }
     });
+
+    quote! {
+        #[automatically_derived]
+        impl crate::api::db::entities::Table for #name {
+            const TABLE: &'static str = #table_attr;
+
+            fn columns() -> Vec<crate::api::db::entities::Column> {
+                vec![#( #columns ),*]
+            }
+        }
+    }
+}
+
+fn generate_from_row(st: &[ColumnField], name: &Ident, positional: bool) -> impl ToTokens {
+    let entity = name.to_string();
+    let mut n = 0usize;
+    let rows = st.iter().map(|v| {
+        let f = &v.ident;
+        let column = &v.column;
+        if v.skip {
+            let default = v.default_value();
+            quote! {
+                #f: #default
+            }
+        } else if positional {
+            let idx = n;
+            n += 1;
+            quote! {
+                #f: row.get(#idx)?
+            }
+        } else if let Some(ref default) = v.default {
+            quote! {
+                #f: match stmt.column_index(#column) {
+                    Ok(idx) => row.get(idx)?,
+                    Err(_) => #default,
+                }
+            }
+        } else {
+            quote! {
+                #f: row.get(crate::db_helpers::column_index(stmt, #column, #entity)?)?
+            }
+        }
+    });
+    let rows = rows.collect::<Vec<_>>();
+
+    let check = if positional {
+        quote! {}
+    } else {
+        let columns = st.iter().filter(|v| !v.skip).map(|v| &v.column);
+        quote! {
+            let stmt = row.as_ref();
+            crate::db_helpers::check_columns(stmt, &[ #( #columns ),* ], #entity)?;
+        }
+    };
+
     quote! {
         #[automatically_derived]
         #[flutter_rust_bridge::frb(ignore)]
         impl crate::api::db::entities::FromRow for #name {
             fn from_row(row: &::rusqlite::Row) -> crate::error::Result<Self> {
+                #check
                 let s = Self { #( #rows ),* };
                 Ok(s)
             }
@@ -146,8 +231,9 @@ fn generate_from_row(st: &[ColumnField], name: &Ident) -> impl ToTokens {
 }
 
 fn generate_getparams(st: &[ColumnField], name: &Ident) -> impl ToTokens {
-    let params = st.iter().map(|v| v.get_ident());
-    let names = st.iter().map(|f| format!(":{}", f.get_ident().to_string()));
+    let st = st.iter().filter(|v| !v.skip).collect::<Vec<_>>();
+    let params = st.iter().map(|v| &v.ident);
+    let names = st.iter().map(|f| format!(":{}", f.column));
     quote! {
         #[automatically_derived]
         #[flutter_rust_bridge::frb(ignore)]
@@ -160,68 +246,108 @@ fn generate_getparams(st: &[ColumnField], name: &Ident) -> impl ToTokens {
     }
 }
 
-enum ColumnField {
-    PrimaryKey(Ident),
-    Column(Ident),
-    Nullable(Ident),
+struct ColumnField {
+    ident: Ident,
+    ty: Type,
+    /// Name of the backing column, the field name unless renamed with `#[column]`
+    column: String,
+    primary: bool,
+    /// Not persisted, always filled from `default`
+    skip: bool,
+    default: Option<Expr>,
 }
 
 impl ColumnField {
-    fn get_ident(&self) -> &'_ Ident {
-        match self {
-            Self::PrimaryKey(ref i) => i,
-            Self::Column(ref i) => i,
-            Self::Nullable(ref i) => i,
+    fn from_field(field: Field) -> Self {
+        let ident = field.ident.expect("tuple structs are not supported");
+        let ty = field.ty;
+        let column = field
+            .attrs
+            .iter()
+            .find(|v| v.path().is_ident("column"))
+            .map(|v| {
+                v.parse_args::<LitStr>()
+                    .expect("The column attribute needs a single string parameter")
+                    .value()
+            })
+            .unwrap_or_else(|| ident.to_string());
+        let default = field
+            .attrs
+            .iter()
+            .find(|v| v.path().is_ident("default"))
+            .map(|v| {
+                v.parse_args::<Expr>()
+                    .expect("The default attribute needs an expression")
+            });
+        let primary = field.attrs.iter().any(|v| v.path().is_ident("primary"));
+        let skip = field.attrs.iter().any(|v| v.path().is_ident("skip"));
+        if primary && skip {
+            panic!("primary key {} cannot be skipped", ident);
+        }
+
+        ColumnField {
+            ident,
+            ty,
+            column,
+            primary,
+            skip,
+            default,
         }
     }
 
-    fn is_primary(&self) -> bool {
-        match self {
-            Self::PrimaryKey(_) => true,
-            Self::Column(_) => false,
-            Self::Nullable(_) => false,
+    fn default_value(&self) -> impl ToTokens {
+        match self.default {
+            Some(ref default) => quote! { #default },
+            None => quote! { ::std::default::Default::default() },
         }
     }
 }
 
-#[proc_macro_derive(FromRow, attributes(table, primary))]
+/// Derives `FromRow`, `GetParams` and `Crud` for an entity struct.
+///
+/// Columns are matched to fields by name, so a query may return them in any
+/// order but must return exactly the struct's fields. Structs marked
+/// `#[positional]` read column `n` into field `n` instead.
+///
+/// Field attributes:
+/// - `#[primary]` the primary key used by update and delete, on several fields for a
+///   composite key
+/// - `#[column("name")]` the column backing the field if it differs from the field name
+/// - `#[skip]` the field is not persisted and is filled with `Default` or `#[default]`
+/// - `#[default(expr)]` used when a query doesn't return the column
+///
+/// Entities with a `#[table]` attribute also implement `Table`, describing the
+/// columns of their table and generating its `CREATE TABLE` statement. Those
+/// marked `#[view]` are read from a view and don't implement `Crud`.
+#[proc_macro_derive(
+    FromRow,
+    attributes(table, view, primary, positional, column, skip, default)
+)]
 pub fn from_row(item: TokenStream) -> TokenStream {
     let input = parse_macro_input!(item as DeriveInput);
     let table_attr = get_table_attr(&input, &input.ident.to_string());
+    let positional = input.attrs.iter().any(|v| v.path().is_ident("positional"));
     let st = match input.data {
         syn::Data::Enum(_) => panic!("FromRow does not work on enums"),
         syn::Data::Union(_) => panic!("FromRow does not work on unions"),
         syn::Data::Struct(s) => s
             .fields
             .into_iter()
-            .map(|f| {
-                let ident = f.ident.expect("tuple structs are not supported");
-                let mut nullable = false;
-                if let Type::Path(TypePath { path, .. }) = f.ty {
-                    if path.is_ident("Option") {
-                        nullable = true;
-                    }
-                }
-                if f.attrs
-                    .iter()
-                    .find(|v| v.path().is_ident("primary"))
-                    .is_some()
-                {
-                    ColumnField::PrimaryKey(ident)
-                } else {
-                    if nullable {
-                        ColumnField::Nullable(ident)
-                    } else {
-                        ColumnField::Column(ident)
-                    }
-                }
-            })
+            .map(ColumnField::from_field)
             .collect::<Vec<_>>(),
     };
 
     let get_params = generate_getparams(&st, &input.ident);
-    let from_row = generate_from_row(&st, &input.ident);
-    let crud_impl = generate_crud_impl(&st, &input.ident, &table_attr);
+    let from_row = generate_from_row(&st, &input.ident, positional);
+    // views aren't written to, writes go through the queries of the tables behind them
+    let crud_impl = (!input.attrs.iter().any(|v| v.path().is_ident("view")))
+        .then(|| generate_crud_impl(&st, &input.ident, &table_attr));
+    // entities without a table only describe query results
+    let table_impl = input
+        .attrs
+        .iter()
+        .any(|v| v.path().is_ident("table"))
+        .then(|| generate_table_impl(&st, &input.ident, &table_attr));
 
     quote! {
         #get_params
@@ -229,6 +355,8 @@ pub fn from_row(item: TokenStream) -> TokenStream {
         #from_row
 
         #crud_impl
+
+        #table_impl
     }
     .into()
 }
@@ -267,6 +395,53 @@ pub fn query(_attr: TokenStream, item: TokenStream) -> TokenStream {
     item
 }
 
+/// Chooses the methods `#[dao]` generates for a query: `sync` (the default),
+/// `async` or `both`. Async variants run the query on a blocking thread and
+/// return a future; with `both` they are named `<fn>_async`.
+#[proc_macro_attribute]
+pub fn query_mode(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    item
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum QueryMode {
+    Sync,
+    Async,
+    Both,
+}
+
+impl QueryMode {
+    fn from_attrs(attrs: &[Attribute]) -> syn::Result<Self> {
+        let Some(attr) = attrs.iter().find(|a| a.path().is_ident("query_mode")) else {
+            return Ok(QueryMode::Sync);
+        };
+        let mode = attr.parse_args_with(Ident::parse_any)?;
+        match mode.to_string().as_str() {
+            "sync" => Ok(QueryMode::Sync),
+            "async" => Ok(QueryMode::Async),
+            "both" => Ok(QueryMode::Both),
+            _ => Err(syn::Error::new_spanned(
+                mode,
+                "expected one of `sync`, `async` or `both`",
+            )),
+        }
+    }
+}
+
+/// Signature of the async variant of a dao function
+fn async_signature(sig: &Signature, ident: Ident, ret: &Type) -> Signature {
+    let mut sig = sig.clone();
+    sig.ident = ident;
+    sig.output = syn::parse_quote! {
+        -> impl ::std::future::Future<Output = #ret> + Send
+    };
+    sig.generics
+        .make_where_clause()
+        .predicates
+        .push(syn::parse_quote! { Self: Clone + Send + 'static });
+    sig
+}
+
 // struct QueryArgs {
 //     query: LitStr,
 //     middle: Token![,],
@@ -299,9 +474,59 @@ enum RetVal {
     Nullable(Type),
     Many(Type),
     One(Type),
+    /// `Result<Page<T>>`, keyset paginated on `(receive_date, post_id)`
+    Page(Type),
+    /// Rows passed lazily to an `impl FnMut(T) -> Result<()>` argument
+    Stream(Type, Ident),
     Unit,
 }
 
+/// Keyset used by paginated queries, newest first
+const PAGE_KEYSET: &str = "ORDER BY receive_date DESC, post_id DESC";
+
+/// Wraps a query so it returns the first page and, separately, the page after
+/// the position in `:cursor_date` and `:cursor_id`, fetching `:fetch` rows.
+/// Two statements keep the row value comparison a plain range the planner can
+/// use an index for.
+fn page_query(query: &str) -> (String, String) {
+    (
+        format!("SELECT * FROM ({}) {} LIMIT :fetch", query, PAGE_KEYSET),
+        format!(
+            "SELECT * FROM ({}) WHERE (receive_date, post_id) < (:cursor_date, :cursor_id) {} LIMIT :fetch",
+            query, PAGE_KEYSET
+        ),
+    )
+}
+
+/// Row type and name of a streaming callback argument, `each: impl FnMut(T) -> Result<()>`
+fn stream_arg(arg: &FnArg) -> Option<(Type, Ident)> {
+    let FnArg::Typed(PatType { pat, ty, .. }) = arg else {
+        return None;
+    };
+    let (Type::ImplTrait(ty), Pat::Ident(ident)) = (ty.as_ref(), pat.as_ref()) else {
+        return None;
+    };
+    ty.bounds.iter().find_map(|bound| match bound {
+        syn::TypeParamBound::Trait(bound) => match &bound.path.segments.last()?.arguments {
+            PathArguments::Parenthesized(args) => {
+                Some((args.inputs.first()?.clone(), ident.ident.clone()))
+            }
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+fn arg_name(arg: &FnArg) -> Option<String> {
+    match arg {
+        FnArg::Typed(PatType { pat, .. }) => match pat.as_ref() {
+            Pat::Ident(ident) => Some(ident.ident.to_string()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 enum VecOr {
     Ident(Ident),
     Vec(Ident),
@@ -399,59 +624,139 @@ fn get_fn_arg_type(arg: &FnArg, require: Option<&str>) -> VecOr {
 //     get_fn_arg_type(f.sig.inputs.get(0).unwrap(), require)
 // }
 
+/// Migrations directory used by `#[dao]` when none is given, relative to the
+/// crate root
+const DEFAULT_SCHEMA: &str = "src/api/db/migrations";
+
+/// In-memory database with every migration applied, queries are prepared
+/// against it to catch mistakes at build time
+struct Schema {
+    conn: Connection,
+    files: Vec<PathBuf>,
+}
+
+impl Schema {
+    fn load(dir: &str) -> Result<Self, String> {
+        let root = std::env::var("CARGO_MANIFEST_DIR").map_err(|e| e.to_string())?;
+        let dir = Path::new(&root).join(dir);
+        let mut files = fs::read_dir(&dir)
+            .map_err(|e| format!("failed to read migrations in {}: {}", dir.display(), e))?
+            .filter_map(|v| v.ok())
+            .map(|v| v.path())
+            .filter(|v| v.extension().is_some_and(|e| e == "sql"))
+            .collect::<Vec<_>>();
+        // migrations are numbered so that they apply in file name order
+        files.sort();
+
+        let conn = Connection::open_in_memory().map_err(|e| e.to_string())?;
+        rusqlite::vtab::array::load_module(&conn).map_err(|e| e.to_string())?;
+        for file in files.iter() {
+            let sql = fs::read_to_string(file)
+                .map_err(|e| format!("failed to read {}: {}", file.display(), e))?;
+            conn.execute_batch(&sql)
+                .map_err(|e| format!("migration {} failed: {}", file.display(), e))?;
+        }
+
+        Ok(Self { conn, files })
+    }
+
+    /// Prepares a query and checks that its named parameters match the
+    /// arguments of the dao function. Returns whether the query is read only.
+    fn check(&self, attr: &Attribute, query: &str, args: &[String]) -> syn::Result<bool> {
+        let st = self
+            .conn
+            .prepare(query)
+            .map_err(|e| syn::Error::new_spanned(attr, format!("invalid query: {}", e)))?;
+
+        let params = (1..=st.parameter_count())
+            .map(|i| st.parameter_name(i).unwrap_or("?"))
+            .collect::<Vec<_>>();
+
+        let mut errors = Vec::new();
+        for param in params.iter() {
+            if !param.starts_with(':') {
+                errors.push(format!(
+                    "query parameter `{}` must be named like `:arg`",
+                    param
+                ));
+            } else if !args.iter().any(|a| a == param) {
+                errors.push(format!(
+                    "query parameter `{}` does not match any argument",
+                    param
+                ));
+            }
+        }
+        for arg in args.iter() {
+            if !params.contains(&arg.as_str()) {
+                errors.push(format!("argument `{}` is not used by the query", &arg[1..]));
+            }
+        }
+
+        match errors
+            .into_iter()
+            .map(|e| syn::Error::new_spanned(attr, e))
+            .reduce(|mut a, b| {
+                a.combine(b);
+                a
+            }) {
+            Some(err) => Err(err),
+            None => Ok(st.readonly()),
+        }
+    }
+
+    /// Makes cargo rebuild the dao when a migration changes
+    fn track(&self) -> impl ToTokens {
+        let files = self.files.iter().map(|v| v.display().to_string());
+        quote! {
+            #( const _: &str = include_str!(#files); )*
+        }
+    }
+}
+
+/// Generates default implementations for the `#[query]` functions of a trait.
+/// Every query is prepared against the schema built from the migrations in
+/// the given directory (`src/api/db/migrations` by default), so syntax
+/// errors, unknown tables or columns and parameters not matching the
+/// function arguments fail the build.
 #[proc_macro_attribute]
-pub fn dao(_attr: TokenStream, item: TokenStream) -> TokenStream {
+pub fn dao(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let dir = if attr.is_empty() {
+        DEFAULT_SCHEMA.to_owned()
+    } else {
+        parse_macro_input!(attr as LitStr).value()
+    };
     let mut i = parse_macro_input!(item as ItemTrait);
+    let schema = match Schema::load(&dir) {
+        Ok(schema) => schema,
+        Err(err) => {
+            return syn::Error::new(proc_macro::Span::call_site().into(), err)
+                .to_compile_error()
+                .into()
+        }
+    };
+    let mut errors = Vec::new();
+    let mut async_variants = Vec::new();
     i.supertraits
         .push(syn::TypeParamBound::Trait(syn::parse_quote! {
             crate::api::db::connection::Dao
         }));
     for traititem in i.items.iter_mut() {
         if let TraitItem::Fn(f) = traititem {
-            if let Some(attr) = f
-                .attrs
-                .iter()
-                .find(|a| a.path().is_ident("query"))
-                .map(|v| {
-                    v.parse_args::<LitStr>()
-                        .expect("query attribute requires litstr parameter")
-                })
-            {
+            if let Some(query_attr) = f.attrs.iter().find(|a| a.path().is_ident("query")) {
+                let attr = query_attr
+                    .parse_args::<LitStr>()
+                    .expect("query attribute requires litstr parameter");
                 if f.sig.inputs.len() < 1 {
                     panic!("dao functions should take a single argument")
                 }
 
-                let parameters = &f
-                    .sig
-                    .inputs
-                    .iter()
-                    .skip(1)
-                    .map(|v| get_fn_arg_type(v, None))
-                    .collect::<Vec<VecOr>>();
-
-                let pnames = parameters
-                    .iter()
-                    .map(|v| v.ident())
-                    .map(|v| format!(":{}", v));
-
-                let ptransforms = parameters.iter().map(|v| match v {
-                    VecOr::Vec(v) => {
-                        quote! {
-                            let #v = ::std::rc::Rc::new(#v);
-                        }
-                    }
-                    VecOr::Ident(_) => quote! {},
-                });
-
-                let parameters = parameters.iter().map(|p| p.ident());
-
-                let query = attr.value();
-                let ret = match f.sig.output {
+                let ret_ty = match f.sig.output {
                     ReturnType::Default => panic!("function should have a return type"),
-                    ReturnType::Type(_, ref ty) => match ty.as_ref() {
-                        Type::Path(ty) => &ty.path,
-                        _ => panic!("return type must be an owned type"),
-                    },
+                    ReturnType::Type(_, ref ty) => ty.as_ref().clone(),
+                };
+                let ret = match ret_ty {
+                    Type::Path(ref ty) => &ty.path,
+                    _ => panic!("return type must be an owned type"),
                 };
 
                 let r = match ret.segments.last() {
@@ -469,12 +774,15 @@ pub fn dao(_attr: TokenStream, item: TokenStream) -> TokenStream {
                                         "Option" => {
                                             RetVal::Nullable(get_type_from_arguments(arguments))
                                         }
+                                        "Page" => RetVal::Page(get_type_from_arguments(arguments)),
                                         _ => {
                                             RetVal::One(Type::Path(TypePath { qself: None, path }))
                                         }
                                     }
                                 }
                             }
+                        } else if let Some((row, each)) = f.sig.inputs.last().and_then(stream_arg) {
+                            RetVal::Stream(row, each)
                         } else {
                             RetVal::Unit
                         }
@@ -483,13 +791,117 @@ pub fn dao(_attr: TokenStream, item: TokenStream) -> TokenStream {
                     _ => panic!("there needs to be a return value"),
                 };
 
-                let q = match r {
-                    RetVal::Many(r) => syn::parse_quote! {
+                // arguments consumed by the generated body rather than bound to the query
+                let special: &[&str] = match r {
+                    RetVal::Page(_) => &["cursor", "limit"],
+                    _ => &[],
+                };
+                for name in special {
+                    if !f
+                        .sig
+                        .inputs
+                        .iter()
+                        .any(|v| arg_name(v).as_deref() == Some(name))
+                    {
+                        errors.push(syn::Error::new_spanned(
+                            &f.sig,
+                            format!("paginated queries need a `{}` argument", name),
+                        ));
+                    }
+                }
+
+                let parameters = &f
+                    .sig
+                    .inputs
+                    .iter()
+                    .skip(1)
+                    .filter(|v| stream_arg(v).is_none())
+                    .filter(|v| !special.contains(&arg_name(v).unwrap_or_default().as_str()))
+                    .map(|v| get_fn_arg_type(v, None))
+                    .collect::<Vec<VecOr>>();
+
+                let mut pnames = parameters
+                    .iter()
+                    .map(|v| v.ident())
+                    .map(|v| format!(":{}", v))
+                    .collect::<Vec<_>>();
+
+                let ptransforms = parameters.iter().map(|v| match v {
+                    VecOr::Vec(v) => {
+                        quote! {
+                            let #v = ::std::rc::Rc::new(#v);
+                        }
+                    }
+                    VecOr::Ident(_) => quote! {},
+                });
+
+                let mut parameters = parameters
+                    .iter()
+                    .map(|p| p.ident().clone())
+                    .collect::<Vec<_>>();
+
+                let mut query = attr.value();
+                // paginated queries also have a statement for the first page
+                let mut first_page = None;
+                if let RetVal::Page(_) = r {
+                    let (first, after) = page_query(&query);
+                    let fetch = Ident::new("fetch", proc_macro::Span::call_site().into());
+                    let mut first_pnames = pnames.clone();
+                    first_pnames.push(":fetch".to_owned());
+                    let mut first_parameters = parameters.clone();
+                    first_parameters.push(fetch);
+                    if let Err(err) = schema.check(query_attr, &first, &first_pnames) {
+                        errors.push(err);
+                    }
+                    first_page = Some((first, first_pnames, first_parameters));
+
+                    query = after;
+                    for name in ["cursor_date", "cursor_id", "fetch"] {
+                        pnames.push(format!(":{}", name));
+                        parameters.push(Ident::new(name, proc_macro::Span::call_site().into()));
+                    }
+                }
+                // selects run on a pooled reader so they don't wait for writes
+                let conn = match schema.check(query_attr, &query, &pnames) {
+                    Ok(true) => quote! { db.get_reader()? },
+                    Ok(false) => quote! { db.get_connection() },
+                    Err(err) => {
+                        errors.push(err);
+                        quote! { db.get_connection() }
+                    }
+                };
+                let mode = QueryMode::from_attrs(&f.attrs).unwrap_or_else(|err| {
+                    errors.push(err);
+                    QueryMode::Sync
+                });
+                if matches!(r, RetVal::Stream(..)) && mode != QueryMode::Sync {
+                    errors.push(syn::Error::new_spanned(
+                        &f.sig,
+                        "streaming queries can only be sync",
+                    ));
+                }
+                // async variants move their arguments to another thread
+                let owned = f
+                    .sig
+                    .inputs
+                    .iter()
+                    .skip(1)
+                    .filter_map(|v| match v {
+                        FnArg::Typed(PatType { pat, ty, .. })
+                            if matches!(ty.as_ref(), Type::Reference(_)) =>
+                        {
+                            Some(pat.clone())
+                        }
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                let body = match r {
+                    RetVal::Many(r) => quote! {
                         {
                             use crate::api::db::entities::FromRow;
-                            let mut conn = self . get_connection().connection();
+                            let mut conn = #conn;
                             #( #ptransforms )*
-                            let mut st = conn.prepare(#query)?;
+                            let mut st = conn.prepare_cached(#query)?;
                             let mut i = st.query_map(::rusqlite::named_params!(#(  #pnames: #parameters ),*), |row| Ok(#r :: from_row(row)?))?;
                             let mut res = Vec::new();
                             for v in i {
@@ -498,45 +910,169 @@ pub fn dao(_attr: TokenStream, item: TokenStream) -> TokenStream {
                             Ok(res)
                         }
                     },
-                    RetVal::Nullable(r) => syn::parse_quote! {
+                    RetVal::Nullable(r) => quote! {
                         {
                             use rusqlite::OptionalExtension;
                             use crate::api::db::entities::FromRow;
-                            let mut conn = self . get_connection().connection();
+                            let mut conn = #conn;
                             #( #ptransforms )*
 
-                            let mut st = conn.prepare(#query)?;
+                            let mut st = conn.prepare_cached(#query)?;
                             let i = st.query_row(::rusqlite::named_params!(#(  #pnames: #parameters ),*), |row| Ok(#r :: from_row(row)?)).optional()?;
                             Ok(i)
                         }
                     },
-                    RetVal::One(r) => syn::parse_quote! {
+                    RetVal::One(r) => quote! {
                         {
                             use crate::api::db::entities::FromRow;
-                            let mut conn = self . get_connection().connection();
+                            let mut conn = #conn;
                             #( #ptransforms )*
 
-                            let mut st = conn.prepare(#query)?;
+                            let mut st = conn.prepare_cached(#query)?;
                             let i = st.query_row(::rusqlite::named_params!(#(  #pnames: #parameters ),*), |row| Ok(#r :: from_row(row)?))?;
                             Ok(i)
                         }
                     },
-                    RetVal::Unit => syn::parse_quote! {
+                    RetVal::Page(r) => {
+                        let (first, first_pnames, first_parameters) = first_page.take().unwrap();
+                        quote! {
+                        {
+                            use crate::api::db::entities::FromRow;
+                            // an empty page couldn't carry a cursor to the next one
+                            if limit == 0 {
+                                return Err(crate::error::SubrosaErr::InvalidPageLimit);
+                            }
+                            let mut conn = #conn;
+                            #( #ptransforms )*
+                            let [cursor_date, cursor_id] = crate::db_helpers::decode_cursor(cursor.as_deref())?;
+                            let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+                            // one extra row tells whether there is another page
+                            let fetch = i64::try_from(limit).unwrap_or(i64::MAX).saturating_add(1);
+
+                            let map = |row: &::rusqlite::Row| {
+                                let key: [::rusqlite::types::Value; 2] = [row.get("receive_date")?, row.get("post_id")?];
+                                Ok((#r :: from_row(row)?, key))
+                            };
+                            let mut st;
+                            let i = if cursor.is_none() {
+                                st = conn.prepare_cached(#first)?;
+                                st.query_map(::rusqlite::named_params!(#(  #first_pnames: #first_parameters ),*), &map)?
+                            } else {
+                                st = conn.prepare_cached(#query)?;
+                                st.query_map(::rusqlite::named_params!(#(  #pnames: #parameters ),*), &map)?
+                            };
+                            let mut items = Vec::new();
+                            let mut last = None;
+                            let mut next = None;
+                            for v in i {
+                                let (item, key) = v?;
+                                if items.len() == limit {
+                                    next = last.take().map(|v: [::rusqlite::types::Value; 2]| crate::db_helpers::encode_cursor(&v));
+                                    break;
+                                }
+                                items.push(item);
+                                last = Some(key);
+                            }
+                            Ok(crate::api::db::entities::Page { items, next })
+                        }
+                        }
+                    }
+                    RetVal::Stream(r, each) => quote! {
+                        {
+                            use ::fallible_iterator::FallibleIterator;
+                            use crate::api::db::entities::FromRow;
+                            let mut #each = #each;
+                            let mut conn = #conn;
+                            #( #ptransforms )*
+
+                            let mut st = conn.prepare_cached(#query)?;
+                            let rows = st.query(::rusqlite::named_params!(#(  #pnames: #parameters ),*))?;
+                            let mut rows = #r :: from_rows(rows);
+                            while let Some(v) = rows.next()? {
+                                #each(v)?;
+                            }
+                            Ok(())
+                        }
+                    },
+                    RetVal::Unit => quote! {
                         {
                             use crate::api::db::entities::FromRow;
-                            let mut conn = self . get_connection().connection();
+                            let mut conn = #conn;
                             #( #ptransforms )*
 
-                            let mut st = conn.prepare(#query)?;
+                            let mut st = conn.prepare_cached(#query)?;
                             st.execute(::rusqlite::named_params!(#(  #pnames: #parameters ),*))?;
                             Ok(())
                         }
                     },
                 };
-                f.default = Some(q)
+
+                let async_body: Block = syn::parse_quote! {
+                    {
+                        let db = ::std::clone::Clone::clone(self);
+                        #( let #owned = ::std::borrow::ToOwned::to_owned(#owned); )*
+                        async move {
+                            let task = ::flutter_rust_bridge::spawn_blocking_with(
+                                move || -> #ret_ty {
+                                    let db = &db;
+                                    #body
+                                },
+                                crate::frb_generated::FLUTTER_RUST_BRIDGE_HANDLER.thread_pool(),
+                            );
+                            match task.await {
+                                Ok(res) => res,
+                                Err(err) => Err(err.into()),
+                            }
+                        }
+                    }
+                };
+
+                match mode {
+                    QueryMode::Sync => {
+                        f.default = Some(syn::parse_quote! {
+                            {
+                                let db = self;
+                                #body
+                            }
+                        })
+                    }
+                    QueryMode::Async => {
+                        f.sig = async_signature(&f.sig, f.sig.ident.clone(), &ret_ty);
+                        f.default = Some(async_body);
+                    }
+                    QueryMode::Both => {
+                        f.default = Some(syn::parse_quote! {
+                            {
+                                let db = self;
+                                #body
+                            }
+                        });
+                        let ident =
+                            Ident::new(&format!("{}_async", f.sig.ident), f.sig.ident.span());
+                        async_variants.push(TraitItem::Fn(TraitItemFn {
+                            attrs: f
+                                .attrs
+                                .iter()
+                                .filter(|a| a.path().is_ident("doc"))
+                                .cloned()
+                                .collect(),
+                            sig: async_signature(&f.sig, ident, &ret_ty),
+                            default: Some(async_body),
+                            semi_token: None,
+                        }));
+                    }
+                }
             }
         }
     }
+    i.items.extend(async_variants);
 
-    quote! { #i }.into()
+    let errors = errors.iter().map(|e| e.to_compile_error());
+    let track = schema.track();
+    quote! {
+        #i
+        #track
+        #( #errors )*
+    }
+    .into()
 }
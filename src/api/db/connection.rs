@@ -1,14 +1,27 @@
 use std::{
-    collections::{BTreeMap, HashMap},
-    sync::{Arc, Mutex, MutexGuard, RwLock},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::{Deref, DerefMut},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, RwLock,
+    },
 };
 
 use flutter_rust_bridge::{frb, BaseAsyncRuntime, DartFnFuture};
-use rusqlite::Connection;
+use rusqlite::{Connection, OpenFlags, TransactionBehavior};
+use uuid::Uuid;
 
-use crate::frb_generated::FLUTTER_RUST_BRIDGE_HANDLER;
+use crate::{db_helpers::fts_query, error::SubrosaErr, frb_generated::FLUTTER_RUST_BRIDGE_HANDLER};
 
-use super::entities::NewsGroup;
+use super::{
+    entities::{
+        Attachment, AttachmentData, GroupKey, IdentityKey, KeyGrant, NewsGroup, PostEdit, Posts,
+        SearchResult, SubrosaDao, Tombstone, User,
+    },
+    retention::RetentionPolicy,
+    subscription::UnsubscribedPolicy,
+};
 
 #[derive(Copy, Clone)]
 pub enum OnConflict {
@@ -17,11 +30,39 @@ pub enum OnConflict {
     Abort,
 }
 
-pub trait Crud {
-    fn insert(&self, conn: &SubrosaDb) -> anyhow::Result<()>;
-    fn update(&self, conn: &SubrosaDb) -> anyhow::Result<()>;
-    fn delete(self, conn: &SubrosaDb) -> anyhow::Result<()>;
-    fn insert_on_conflict(&self, conn: &SubrosaDb, on_conflict: OnConflict) -> anyhow::Result<()>;
+pub trait Crud: Sized {
+    fn insert(&self, conn: &impl Dao) -> anyhow::Result<()>;
+    fn update(&self, conn: &impl Dao) -> anyhow::Result<()>;
+    fn delete(self, conn: &impl Dao) -> anyhow::Result<()>;
+    fn insert_on_conflict(&self, conn: &impl Dao, on_conflict: OnConflict) -> anyhow::Result<()>;
+    /// Inserts all of `items` with one statement, either all of them or none
+    fn insert_many(items: &[Self], conn: &impl Dao) -> anyhow::Result<()> {
+        Self::insert_many_on_conflict(items, conn, OnConflict::Abort)
+    }
+    fn insert_many_on_conflict(
+        items: &[Self],
+        conn: &impl Dao,
+        on_conflict: OnConflict,
+    ) -> anyhow::Result<()>;
+}
+
+/// Runs `f` in a savepoint on `conn`. This starts a transaction in autocommit
+/// mode and nests inside an open one, where a failure only undoes `f`.
+pub(crate) fn savepoint<T>(
+    conn: &Connection,
+    f: impl FnOnce() -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    conn.execute_batch("SAVEPOINT subrosa")?;
+    match f() {
+        Ok(v) => {
+            conn.execute_batch("RELEASE subrosa")?;
+            Ok(v)
+        }
+        Err(err) => {
+            conn.execute_batch("ROLLBACK TO subrosa; RELEASE subrosa")?;
+            Err(err)
+        }
+    }
 }
 
 type WatcherCbs =
@@ -68,9 +109,91 @@ impl Drop for WatcherInner {
 }
 
 pub(crate) struct SubrosaDbInner {
-    pub(crate) conn: Mutex<Connection>,
+    writer: Mutex<Connection>,
+    readers: ReaderPool,
     pub(crate) watchers: RwLock<BTreeMap<u32, WatcherCbs>>,
+    /// Tables written since the last commit, whose watchers are notified once
+    /// the writer is released
+    changed: Mutex<BTreeSet<String>>,
     watcher_idx: RwLock<u32>,
+    bundle_size: AtomicUsize,
+    pub(crate) retention: Mutex<RetentionPolicy>,
+    pub(crate) unsubscribed: Mutex<UnsubscribedPolicy>,
+}
+
+/// Suggested upper bound in bytes for bundled records sent in one scatterbrain
+/// message, once every peer understands bundles
+pub const DEFAULT_BUNDLE_SIZE: usize = 64 * 1024;
+
+/// Number of idle read only connections kept open
+const MAX_IDLE_READERS: usize = 4;
+
+/// Prepared statements cached per connection, enough for every generated query
+const STATEMENT_CACHE_CAPACITY: usize = 128;
+
+/// Read only connections to the database file. Thanks to WAL they don't wait
+/// for the writer, so UI reads stay responsive during a long sync.
+struct ReaderPool {
+    /// `None` for in-memory databases, which can't be shared between
+    /// connections and read through the writer instead
+    path: Option<PathBuf>,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl ReaderPool {
+    fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn get(&self) -> rusqlite::Result<Option<Connection>> {
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+
+        if let Some(conn) = self
+            .idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop()
+        {
+            return Ok(Some(conn));
+        }
+
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        rusqlite::vtab::array::load_module(&conn)?;
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        Ok(Some(conn))
+    }
+
+    fn put(&self, conn: Connection) {
+        let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        if idle.len() < MAX_IDLE_READERS {
+            idle.push(conn);
+        }
+    }
+}
+
+/// Read only connection borrowed from the pool, returned to it on drop
+#[frb(ignore)]
+pub struct PooledConnection<'a> {
+    pool: &'a ReaderPool,
+    conn: Option<Connection>,
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.put(conn);
+        }
+    }
 }
 
 pub struct SubrosaDb(pub(crate) Arc<SubrosaDbInner>);
@@ -81,24 +204,127 @@ impl Clone for SubrosaDb {
     }
 }
 
+/// Source of the connections that `Crud` and `#[dao]` methods run against.
+/// Writes use [`Dao::get_connection`] while read only queries use
+/// [`Dao::get_reader`].
 pub trait Dao {
-    fn get_connection(&self) -> &'_ SubrosaDb;
+    fn get_connection(&self) -> DbConnection<'_>;
+
+    fn get_reader(&self) -> crate::error::Result<DbConnection<'_>> {
+        Ok(self.get_connection())
+    }
 }
 
 impl Dao for SubrosaDb {
-    fn get_connection(&self) -> &'_ SubrosaDb {
-        self
+    fn get_connection(&self) -> DbConnection<'_> {
+        self.connection()
+    }
+
+    fn get_reader(&self) -> crate::error::Result<DbConnection<'_>> {
+        match self.0.readers.get()? {
+            Some(conn) => Ok(DbConnection::Reader(PooledConnection {
+                pool: &self.0.readers,
+                conn: Some(conn),
+            })),
+            None => Ok(self.connection()),
+        }
+    }
+}
+
+/// The locked writer of a [`SubrosaDb`] in autocommit mode, a pooled read only
+/// connection or the connection of an open transaction
+#[frb(ignore)]
+pub enum DbConnection<'a> {
+    Locked(Writer<'a>),
+    Reader(PooledConnection<'a>),
+    Transaction(&'a Connection),
+}
+
+impl Deref for DbConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match self {
+            DbConnection::Locked(conn) => conn,
+            DbConnection::Reader(conn) => conn.conn.as_ref().unwrap(),
+            DbConnection::Transaction(conn) => conn,
+        }
+    }
+}
+
+/// The locked writer connection. Watchers of the tables changed through it are
+/// notified when it is released outside a transaction, so a watcher that
+/// queries again sees the committed change.
+#[frb(ignore)]
+pub struct Writer<'a> {
+    conn: MutexGuard<'a, Connection>,
+    db: &'a SubrosaDb,
+}
+
+impl Deref for Writer<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+impl DerefMut for Writer<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+}
+
+impl Drop for Writer<'_> {
+    fn drop(&mut self) {
+        if self.conn.is_autocommit() {
+            self.db.notify_watchers();
+        }
+    }
+}
+
+/// Handle passed to [`SubrosaDb::transaction`]. `Crud` and dao methods called on
+/// it are committed together when the closure returns `Ok`.
+#[frb(ignore)]
+pub struct SubrosaTransaction<'a> {
+    conn: &'a Connection,
+    pub(crate) unsubscribed: UnsubscribedPolicy,
+}
+
+impl Dao for SubrosaTransaction<'_> {
+    fn get_connection(&self) -> DbConnection<'_> {
+        DbConnection::Transaction(self.conn)
+    }
+}
+
+impl SubrosaTransaction<'_> {
+    /// Runs `f` in a savepoint, undoing only its changes if it fails while
+    /// leaving the rest of the transaction intact
+    pub(crate) fn savepoint<T>(
+        &self,
+        f: impl FnOnce(&Self) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        savepoint(self.conn, || f(self))
     }
 }
-pub(crate) type DbConnection<'a> = MutexGuard<'a, Connection>;
 
 impl SubrosaDb {
     #[cfg(test)]
     pub(crate) fn from_conn(conn: Connection) -> SubrosaDb {
+        Self::with_writer(conn, None)
+    }
+
+    fn with_writer(conn: Connection, path: Option<PathBuf>) -> SubrosaDb {
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
         SubrosaDb(Arc::new(SubrosaDbInner {
-            conn: Mutex::new(conn),
+            writer: Mutex::new(conn),
+            readers: ReaderPool::new(path),
             watchers: RwLock::new(BTreeMap::new()),
+            changed: Mutex::new(BTreeSet::new()),
             watcher_idx: RwLock::new(0),
+            bundle_size: AtomicUsize::new(0),
+            retention: Mutex::new(RetentionPolicy::default()),
+            unsubscribed: Mutex::new(UnsubscribedPolicy::default()),
         }))
     }
 
@@ -111,42 +337,58 @@ impl SubrosaDb {
         wl.insert(*idx, Arc::clone(&w.0.cbs));
 
         let s = self.clone();
-        let c = self.0.conn.lock().unwrap();
+        let c = self.connection();
+        // the hooks run before the write is committed, so changes are only
+        // recorded here and announced by `notify_watchers`
         c.update_hook(Some(move |_, _: &str, tablename: &str, _| {
-            for watcher in s.0.watchers.read().unwrap().values() {
-                for (tb, cb) in watcher.read().unwrap().iter() {
-                    if tb != tablename {
-                        continue;
-                    }
-                    FLUTTER_RUST_BRIDGE_HANDLER
-                        .async_runtime()
-                        .spawn(cb(s.clone()));
-                }
-            }
+            s.0.changed
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(tablename.to_owned());
+        }));
+        let s = self.clone();
+        c.rollback_hook(Some(move || {
+            s.0.changed
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
         }));
         w
     }
 
+    /// Opens the database at `path` with one writer connection. Read only
+    /// queries use a pool of separate connections opened as needed.
     #[frb(sync)]
     pub fn new(path: &str) -> anyhow::Result<SubrosaDb> {
         let conn = Connection::open(path)?;
         rusqlite::vtab::array::load_module(&conn)?;
-        Ok(SubrosaDb(Arc::new(SubrosaDbInner {
-            conn: Mutex::new(conn),
-            watchers: RwLock::new(BTreeMap::new()),
-            watcher_idx: RwLock::new(0),
-        })))
+        conn.pragma_update(None, "foreign_keys", true)?;
+        let path = match path {
+            "" | ":memory:" => None,
+            path => Some(PathBuf::from(path)),
+        };
+        Ok(Self::with_writer(conn, path))
     }
 
     #[frb(sync)]
     pub fn new_in_memory() -> anyhow::Result<SubrosaDb> {
         let conn = Connection::open_in_memory()?;
         rusqlite::vtab::array::load_module(&conn)?;
-        Ok(SubrosaDb(Arc::new(SubrosaDbInner {
-            conn: Mutex::new(conn),
-            watchers: RwLock::new(BTreeMap::new()),
-            watcher_idx: RwLock::new(0),
-        })))
+        conn.pragma_update(None, "foreign_keys", true)?;
+        Ok(Self::with_writer(conn, None))
+    }
+
+    /// Sets the maximum size of a bundle of records sent during sync. Records
+    /// larger than this are still sent, one per message. Clients older than
+    /// bundles drop them, so this is 0 and records are sent one per message
+    /// until set, for example to [`DEFAULT_BUNDLE_SIZE`].
+    #[frb(sync)]
+    pub fn set_bundle_size(&self, size: usize) {
+        self.0.bundle_size.store(size, Ordering::Relaxed);
+    }
+
+    pub(crate) fn bundle_size(&self) -> usize {
+        self.0.bundle_size.load(Ordering::Relaxed)
     }
 
     pub fn insert_group(&self, group: &NewsGroup) -> anyhow::Result<()> {
@@ -154,7 +396,228 @@ impl SubrosaDb {
         Ok(())
     }
 
-    pub(crate) fn connection<'a>(&'a self) -> DbConnection<'a> {
-        self.0.conn.lock().unwrap()
+    /// Creates a newsgroup whose posts are encrypted with a fresh group key.
+    /// Members are added with [`SubrosaDb::add_group_member`], their keys are
+    /// signed by `owner`, one of our identities. The group is stored under a
+    /// uuid derived from `owner`, the stored group is returned.
+    pub fn insert_private_group(
+        &self,
+        group: &NewsGroup,
+        owner: Uuid,
+    ) -> anyhow::Result<NewsGroup> {
+        self.get_signing_key(&owner)?
+            .ok_or(SubrosaErr::MissingKey)?;
+        let mut group = group.clone();
+        group.set_owner(owner);
+        group.insert(self)?;
+        GroupKey::new(group.uuid).insert(self)?;
+        Ok(group)
+    }
+
+    /// Sends the key of a private group to an identity, sealed to its public key
+    /// and signed by the group's owner
+    pub fn add_group_member(&self, group: Uuid, identity: Uuid) -> anyhow::Result<()> {
+        let key = self.get_group_key(&group)?.ok_or(SubrosaErr::MissingKey)?;
+        let owner = self
+            .get_group(group)?
+            .and_then(|v| v.owner)
+            .map(|v| self.get_signing_key(&v))
+            .transpose()?
+            .flatten()
+            .ok_or(SubrosaErr::MissingKey)?;
+        let public_key = self
+            .get_cached_identity(&identity)?
+            .and_then(|v| v.public_key)
+            .ok_or(SubrosaErr::MissingKey)?;
+        let mut grant = KeyGrant::new(&key, identity, &public_key)?;
+        grant.sign(&owner.secret_key)?;
+        grant.insert(self)?;
+        Ok(())
+    }
+
+    /// Updates the profile of an owned identity. The new version is published
+    /// on the next sync and replaces older copies cached by peers.
+    pub fn update_profile(
+        &self,
+        identity: Uuid,
+        user_name: String,
+        bio: String,
+        image_bytes: Vec<u8>,
+    ) -> anyhow::Result<User> {
+        let previous = self.get_user(identity)?.map(|v| v.version).unwrap_or(0);
+        let user = User {
+            identity,
+            user_name,
+            bio,
+            owned: true,
+            image_bytes,
+            // timestamps keep versions increasing across devices sharing an identity
+            version: chrono::Utc::now()
+                .timestamp_millis()
+                .max(previous.saturating_add(1)),
+            sent: false,
+        };
+        self.set_profile(
+            &user.identity,
+            &user.user_name,
+            &user.bio,
+            &user.image_bytes,
+            user.version,
+        )?;
+        Ok(user)
+    }
+
+    /// Stores the secret key used to sign posts written by an owned identity
+    pub fn set_signing_key(&self, identity: Uuid, secret_key: Vec<u8>) -> anyhow::Result<()> {
+        IdentityKey {
+            identity,
+            secret_key,
+        }
+        .insert_on_conflict(self, OnConflict::Update)?;
+        Ok(())
+    }
+
+    /// Attaches a file to a post, it is sent alongside the post on the next sync
+    pub fn add_attachment(
+        &self,
+        post_id: Uuid,
+        file_name: String,
+        mime: String,
+        body: Vec<u8>,
+    ) -> anyhow::Result<Attachment> {
+        let data = AttachmentData::new(body);
+        let attachment = Attachment::new(&post_id, file_name, mime, &data);
+        data.insert_on_conflict(self, OnConflict::Ignore)?;
+        attachment.insert(self)?;
+        Ok(attachment)
+    }
+
+    /// Replaces the header and body of one of our own posts. The edit is signed
+    /// with the author's key and sent on the next sync.
+    pub fn edit_post(
+        &self,
+        post_id: Uuid,
+        header: String,
+        body: String,
+    ) -> anyhow::Result<PostEdit> {
+        let post = self.get_post(&post_id)?.ok_or(SubrosaErr::UnknownPost)?;
+        let mut edit = PostEdit::new(&post, header, body)?;
+        edit.sign(&self.author_key(&post)?.secret_key)?;
+        edit.insert(self)?;
+        Ok(edit)
+    }
+
+    /// Searches post headers and bodies for every word of `text`, best matches
+    /// first. With a `group` only that group and its subgroups are searched.
+    pub fn search(
+        &self,
+        text: String,
+        group: Option<Uuid>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let Some(query) = fts_query(&text) else {
+            return Ok(Vec::new());
+        };
+        let results = match group {
+            Some(group) => self.search_posts_in_group(&group, &query, limit)?,
+            None => self.search_posts(&query, limit)?,
+        };
+        Ok(results)
+    }
+
+    /// Deletes one of our own posts and sends a signed retraction so peers
+    /// delete it as well
+    pub fn retract_post(&self, post_id: Uuid) -> anyhow::Result<()> {
+        let post = self.get_post(&post_id)?.ok_or(SubrosaErr::UnknownPost)?;
+        let mut tombstone = Tombstone::new(&post)?;
+        tombstone.sign(&self.author_key(&post)?.secret_key)?;
+        self.transaction(|tx| {
+            tombstone.insert_on_conflict(tx, OnConflict::Update)?;
+            tx.apply_tombstone(&tombstone)
+        })
+    }
+
+    fn author_key(&self, post: &Posts) -> anyhow::Result<IdentityKey> {
+        let key = post
+            .identity
+            .map(|v| self.get_signing_key(&v))
+            .transpose()?
+            .flatten()
+            .ok_or(SubrosaErr::MissingKey)?;
+        Ok(key)
+    }
+
+    /// Runs `f` in a single transaction. Changes are committed if it returns
+    /// `Ok` and rolled back if it returns an error or panics.
+    ///
+    /// The writer stays locked until `f` returns, so `f` must only use the
+    /// transaction handle. Only file databases can still be read through this
+    /// `SubrosaDb` meanwhile, without seeing the uncommitted changes.
+    #[frb(ignore)]
+    pub fn transaction<T>(
+        &self,
+        f: impl FnOnce(&SubrosaTransaction<'_>) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let unsubscribed = self.unsubscribed_policy();
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // dropping tx without committing, including while unwinding, rolls back
+        let res = f(&SubrosaTransaction {
+            conn: &tx,
+            unsubscribed,
+        })?;
+        tx.commit()?;
+        Ok(res)
+    }
+
+    /// [`SubrosaDb::transaction`] run on a blocking thread
+    #[frb(ignore)]
+    pub async fn transaction_async<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&SubrosaTransaction<'_>) -> anyhow::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let db = self.clone();
+        flutter_rust_bridge::spawn_blocking_with(
+            move || db.transaction(f),
+            FLUTTER_RUST_BRIDGE_HANDLER.thread_pool(),
+        )
+        .await?
+    }
+
+    pub(crate) fn connection(&self) -> DbConnection<'_> {
+        DbConnection::Locked(self.writer())
+    }
+
+    pub(crate) fn writer(&self) -> Writer<'_> {
+        // a panic inside a transaction rolls it back before the lock is
+        // released, so a poisoned connection is still consistent
+        Writer {
+            conn: self.0.writer.lock().unwrap_or_else(PoisonError::into_inner),
+            db: self,
+        }
+    }
+
+    /// Runs the callbacks watching tables changed by the last commit
+    fn notify_watchers(&self) {
+        let changed = std::mem::take(
+            &mut *self
+                .0
+                .changed
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        if changed.is_empty() {
+            return;
+        }
+        for watcher in self.0.watchers.read().unwrap().values() {
+            for (tb, cb) in watcher.read().unwrap().iter() {
+                if changed.contains(tb) {
+                    FLUTTER_RUST_BRIDGE_HANDLER
+                        .async_runtime()
+                        .spawn(cb(self.clone()));
+                }
+            }
+        }
     }
 }
@@ -1,18 +1,25 @@
-use super::connection::SubrosaDb;
+use super::connection::{SubrosaDb, SubrosaTransaction};
 pub use crate::scatterbrain::types::Identity;
 use crate::{
     api::proto::ToUuid,
+    crypto::{self, signing_key},
     error::{Result, SubrosaErr},
     proto::{self, news_group::ParentOption, post::AuthorOr, user::Image},
 };
 use chrono::{NaiveDateTime, Utc};
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
 use fallible_iterator::FallibleIterator;
 use flutter_rust_bridge::frb;
-use macros::{dao, query, FromRow};
+use macros::{dao, query, query_mode, FromRow};
+use prost::Message as _;
 pub use rusqlite::types::Value;
 pub use rusqlite::vtab::array::Array;
-use rusqlite::{Row, Rows, ToSql};
+use rusqlite::{
+    types::{FromSql, FromSqlResult, ToSqlOutput, ValueRef},
+    Row, Rows, ToSql,
+};
 use sha2::Sha256;
+use std::path::Path;
 pub use uuid::Uuid;
 
 use sha1::{Digest, Sha1};
@@ -36,11 +43,176 @@ pub trait FromRow: Sized {
     #[frb(ignore)]
     fn from_row(row: &Row) -> Result<Self>;
     #[frb(ignore)]
-    fn from_rows(rows: Rows) -> impl FallibleIterator<Item = Self> {
+    fn from_rows(rows: Rows) -> impl FallibleIterator<Item = Self, Error = rusqlite::Error> {
         rows.map(|thing| Ok(Self::from_row(thing)?))
     }
 }
 
+/// SQL type of an entity field, used to generate table definitions
+#[frb(ignore)]
+pub trait SqlType {
+    const SQL_TYPE: &'static str;
+    const NULLABLE: bool = false;
+}
+
+impl<T: SqlType> SqlType for Option<T> {
+    const SQL_TYPE: &'static str = T::SQL_TYPE;
+    const NULLABLE: bool = true;
+}
+
+macro_rules! sql_type {
+    ($sql:literal: $($ty:ty),+) => {
+        $(impl SqlType for $ty {
+            const SQL_TYPE: &'static str = $sql;
+        })+
+    };
+}
+
+// rusqlite stores a Uuid as a 16 byte blob. The columns are declared TEXT as
+// in the original schema; TEXT affinity leaves blobs as they are, so the
+// declared type doesn't change what is stored or how keys compare.
+sql_type!("TEXT": String, Uuid, NaiveDateTime);
+sql_type!("BLOB": Vec<u8>);
+sql_type!("INTEGER": i64, i32, u32, SigStatus);
+sql_type!("REAL": f64);
+sql_type!("BOOLEAN": bool);
+
+/// Column of an entity's table
+#[frb(ignore)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub name: &'static str,
+    pub sql_type: &'static str,
+    pub nullable: bool,
+    pub primary: bool,
+}
+
+/// Table backing an entity, implemented by `#[derive(FromRow)]` for entities
+/// with a `#[table]` attribute
+#[frb(ignore)]
+pub trait Table {
+    const TABLE: &'static str;
+
+    fn columns() -> Vec<Column>;
+
+    /// `CREATE TABLE` statement for the entity's fields
+    fn create_table() -> String {
+        let columns = Self::columns();
+        let mut defs = columns
+            .iter()
+            .map(|v| {
+                let null = if v.nullable { "" } else { " NOT NULL" };
+                format!("`{}` {}{}", v.name, v.sql_type, null)
+            })
+            .collect::<Vec<_>>();
+        let primary = columns
+            .iter()
+            .filter(|v| v.primary)
+            .map(|v| format!("`{}`", v.name))
+            .collect::<Vec<_>>();
+        if !primary.is_empty() {
+            defs.push(format!("PRIMARY KEY({})", primary.join(", ")));
+        }
+
+        format!(
+            "CREATE TABLE IF NOT EXISTS `{}` (\n    {}\n);",
+            Self::TABLE,
+            defs.join(",\n    ")
+        )
+    }
+}
+
+/// One page of a paginated query. `next` is an opaque token to pass as the
+/// cursor for the following page, `None` on the last page.
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<String>,
+}
+
+/// Result of checking a post signature against its author's public key
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SigStatus {
+    UnknownKey,
+    Valid,
+    Invalid,
+}
+
+impl ToSql for SigStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let v: i64 = match self {
+            SigStatus::UnknownKey => 0,
+            SigStatus::Valid => 1,
+            SigStatus::Invalid => 2,
+        };
+        Ok(v.into())
+    }
+}
+
+impl FromSql for SigStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        Ok(match value.as_i64()? {
+            1 => SigStatus::Valid,
+            2 => SigStatus::Invalid,
+            _ => SigStatus::UnknownKey,
+        })
+    }
+}
+
+fn verify_digest(public_key: &[u8], sig: Option<&[u8]>, digest: &[u8]) -> SigStatus {
+    let Ok(key) = public_key
+        .try_into()
+        .map_err(|_| ())
+        .and_then(|v| VerifyingKey::from_bytes(v).map_err(|_| ()))
+    else {
+        return SigStatus::Invalid;
+    };
+
+    let Some(sig) = sig.and_then(|v| Signature::from_slice(v).ok()) else {
+        return SigStatus::Invalid;
+    };
+
+    match key.verify(digest, &sig) {
+        Ok(()) => SigStatus::Valid,
+        Err(_) => SigStatus::Invalid,
+    }
+}
+
+fn hash_text(hasher: &mut Sha256, field: &str) {
+    hasher.update((field.len() as u64).to_be_bytes());
+    hasher.update(field.as_bytes());
+}
+
+/// Hashes a presence byte before `field` so a missing value and an empty one differ
+fn hash_optional<T>(hasher: &mut Sha256, field: Option<T>, hash: impl FnOnce(&mut Sha256, T)) {
+    match field {
+        Some(value) => {
+            hasher.update([1]);
+            hash(hasher, value);
+        }
+        None => hasher.update([0]),
+    }
+}
+
+/// Digest covered by the signature on a profile. A missing image and an empty
+/// one are the same profile.
+fn profile_digest(identity: &Uuid, version: i64, name: &str, bio: &str, image: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"profile");
+    hasher.update(identity.as_bytes());
+    hasher.update(version.to_be_bytes());
+    hash_text(&mut hasher, name);
+    hash_text(&mut hasher, bio);
+    hasher.update((image.len() as u64).to_be_bytes());
+    hasher.update(image);
+    hasher.finalize().to_vec()
+}
+
+fn from_millis(millis: i64) -> Result<NaiveDateTime> {
+    chrono::DateTime::from_timestamp_millis(millis)
+        .map(|v| v.naive_utc())
+        .ok_or(SubrosaErr::ParseError)
+}
+
 #[derive(FromRow, Debug, Clone)]
 #[table("newsgroup")]
 #[frb(opaque)]
@@ -52,6 +224,57 @@ pub struct NewsGroup {
     pub parent: Option<Uuid>,
     pub group_name: String,
     pub sent: bool,
+    /// Identity whose key signs the key grants of a private group
+    pub owner: Option<Uuid>,
+    /// Salt the uuid of a private group is derived from along with `owner`
+    pub owner_salt: Option<Vec<u8>>,
+}
+
+/// A newsgroup received before its parent, held until the parent hash chain
+/// can be checked
+#[derive(FromRow, Debug, Clone)]
+#[table("pending_newsgroup")]
+#[frb(opaque)]
+pub struct PendingNewsGroup {
+    #[primary]
+    pub uuid: Uuid,
+    pub description: String,
+    pub parent_hash: Option<Vec<u8>>,
+    pub parent: Option<Uuid>,
+    pub group_name: String,
+    pub sent: bool,
+    pub owner: Option<Uuid>,
+    pub owner_salt: Option<Vec<u8>>,
+}
+
+impl From<NewsGroup> for PendingNewsGroup {
+    fn from(value: NewsGroup) -> Self {
+        PendingNewsGroup {
+            uuid: value.uuid,
+            description: value.description,
+            parent_hash: value.parent_hash,
+            parent: value.parent,
+            group_name: value.group_name,
+            sent: value.sent,
+            owner: value.owner,
+            owner_salt: value.owner_salt,
+        }
+    }
+}
+
+impl From<PendingNewsGroup> for NewsGroup {
+    fn from(value: PendingNewsGroup) -> Self {
+        NewsGroup {
+            uuid: value.uuid,
+            description: value.description,
+            parent_hash: value.parent_hash,
+            parent: value.parent,
+            group_name: value.group_name,
+            sent: value.sent,
+            owner: value.owner,
+            owner_salt: value.owner_salt,
+        }
+    }
 }
 
 #[dao]
@@ -64,6 +287,26 @@ pub trait TestDao {
 
     #[query("select * from newsgroup")]
     fn test_one(&self) -> Result<NewsGroup>;
+
+    #[query(
+        "select owner_salt, owner, sent, group_name, parent, parent_hash, description, uuid
+        from newsgroup"
+    )]
+    fn test_reordered(&self) -> Result<Vec<NewsGroup>>;
+
+    #[query("select uuid, description from newsgroup")]
+    fn test_missing_column(&self) -> Result<Vec<NewsGroup>>;
+
+    #[query("select *, 1 AS extra from newsgroup")]
+    fn test_unknown_column(&self) -> Result<Vec<NewsGroup>>;
+
+    #[query("select * from newsgroup where uuid = :uuid")]
+    #[query_mode(async)]
+    fn test_async(&self, uuid: &Uuid) -> Result<Option<NewsGroup>>;
+
+    #[query("select * from newsgroup where parent = :parent")]
+    #[query_mode(both)]
+    fn test_both(&self, parent: Uuid) -> Result<Vec<NewsGroup>>;
 }
 
 impl FromRow for NaiveDateTime {
@@ -72,6 +315,12 @@ impl FromRow for NaiveDateTime {
     }
 }
 
+impl FromRow for Uuid {
+    fn from_row(row: &rusqlite::Row) -> Result<Self> {
+        Ok(row.get(0)?)
+    }
+}
+
 #[dao]
 pub trait SubrosaDao {
     #[query("SELECT * FROM newsgroup WHERE uuid = :uuid")]
@@ -87,20 +336,361 @@ pub trait SubrosaDao {
     fn get_all_users_by_ownership(&self, owned: bool) -> Result<Vec<User>>;
 
     #[query("SELECT * FROM newsgroup WHERE parent IS NULL")]
+    #[query_mode(both)]
     fn get_root_groups(&self) -> Result<Vec<NewsGroup>>;
 
     #[query("SELECT * FROM newsgroup WHERE parent = :parent")]
+    #[query_mode(both)]
     fn get_groups_for_parent(&self, parent: &Uuid) -> Result<Vec<NewsGroup>>;
 
     #[query("DELETE FROM newsgroup WHERE uuid = :uuid")]
     fn delete_group(&self, uuid: Uuid) -> Result<()>;
 
-    #[query("DELETE FROM posts WHERE uuid = :uuid")]
-    fn delete_post(&self, uuid: Uuid) -> Result<()>;
+    #[query("SELECT * FROM pending_newsgroup WHERE parent = :parent")]
+    fn get_pending_children(&self, parent: &Uuid) -> Result<Vec<PendingNewsGroup>>;
+
+    #[query("DELETE FROM pending_newsgroup WHERE uuid = :uuid")]
+    fn delete_pending_group(&self, uuid: &Uuid) -> Result<()>;
+
+    // held groups have no receive date, but their rowids grow as they arrive
+    #[query(
+        "DELETE FROM pending_newsgroup WHERE rowid IN (
+            SELECT rowid FROM pending_newsgroup ORDER BY rowid DESC LIMIT -1 OFFSET :max
+        )"
+    )]
+    fn trim_pending_groups(&self, max: u32) -> Result<()>;
+
+    #[query("SELECT * FROM pending_post WHERE parent_group = :parent_group")]
+    fn get_pending_posts(&self, parent_group: &Uuid) -> Result<Vec<PendingPost>>;
+
+    #[query("SELECT * FROM pending_post WHERE post_id = :post_id")]
+    fn get_pending_post(&self, post_id: &Uuid) -> Result<Option<PendingPost>>;
+
+    #[query("DELETE FROM pending_post WHERE post_id = :post_id")]
+    fn delete_pending_post(&self, post_id: &Uuid) -> Result<()>;
+
+    #[query(
+        "SELECT post_id FROM pending_post ORDER BY receive_date DESC, post_id LIMIT -1 OFFSET :max"
+    )]
+    fn get_excess_pending_posts(&self, max: u32) -> Result<Vec<Uuid>>;
+
+    #[query("DELETE FROM pending_post WHERE post_id IN rarray(:ids)")]
+    fn delete_pending_posts(&self, ids: Vec<Value>) -> Result<()>;
+
+    #[query("SELECT * FROM attachments WHERE post_id = :post_id")]
+    fn get_attachments(&self, post_id: &Uuid) -> Result<Vec<Attachment>>;
+
+    #[query("DELETE FROM attachments WHERE post_id = :post_id")]
+    fn delete_attachments(&self, post_id: &Uuid) -> Result<()>;
+
+    #[query("SELECT EXISTS (SELECT 1 FROM attachments WHERE hash = :hash)")]
+    fn is_attachment_referenced(&self, hash: &Vec<u8>) -> Result<i64>;
+
+    #[query(
+        "
+        INSERT INTO attachment_data (hash, body)
+        SELECT hash, body FROM pending_attachment_data
+        WHERE hash IN (SELECT hash FROM attachments WHERE post_id = :post_id)
+        ON CONFLICT(hash) DO NOTHING
+        "
+    )]
+    fn store_pending_attachment_data(&self, post_id: &Uuid) -> Result<()>;
+
+    #[query(
+        "
+        DELETE FROM pending_attachment_data
+        WHERE hash IN (SELECT hash FROM attachments WHERE post_id = :post_id)
+        "
+    )]
+    fn delete_pending_attachment_data(&self, post_id: &Uuid) -> Result<()>;
+
+    #[query(
+        "
+        DELETE FROM pending_attachment_data WHERE hash IN (
+            SELECT hash FROM (
+                SELECT hash, SUM(length(body)) OVER (ORDER BY receive_date DESC, hash) AS total
+                FROM pending_attachment_data
+            )
+            WHERE total > :max_bytes
+        )
+        "
+    )]
+    fn trim_pending_attachment_data(&self, max_bytes: i64) -> Result<()>;
+
+    #[query("SELECT * FROM attachment_data WHERE hash = :hash")]
+    fn get_attachment_data(&self, hash: &Vec<u8>) -> Result<Option<AttachmentData>>;
+
+    #[query("SELECT * FROM attachments WHERE sent = '0'")]
+    fn get_unsent_attachments(&self) -> Result<Vec<Attachment>>;
+
+    #[query("UPDATE attachments SET sent = '1' WHERE attachment_id IN rarray(:ids)")]
+    fn mark_sent_attachments(&self, ids: Vec<Value>) -> Result<()>;
+
+    #[query("SELECT * FROM unknown_message WHERE sent = '0'")]
+    fn get_unsent_unknown_messages(&self) -> Result<Vec<UnknownMessage>>;
+
+    #[query("UPDATE unknown_message SET sent = '1' WHERE hash IN rarray(:ids)")]
+    fn mark_sent_unknown_messages(&self, ids: Vec<Value>) -> Result<()>;
+
+    #[query("DELETE FROM posts WHERE post_id = :post_id")]
+    fn delete_post(&self, post_id: Uuid) -> Result<()>;
+
+    #[query("SELECT * FROM forward_message")]
+    fn get_forward_messages(&self) -> Result<Vec<ForwardMessage>>;
+
+    #[query("DELETE FROM forward_message WHERE hash IN rarray(:ids)")]
+    fn delete_forward_messages(&self, ids: Vec<Value>) -> Result<()>;
+
+    #[query("SELECT * FROM forward_attachment WHERE hash = :hash")]
+    fn get_forward_attachment(&self, hash: &Vec<u8>) -> Result<Option<ForwardAttachment>>;
+
+    #[query("SELECT * FROM pending_attachment_data WHERE hash = :hash")]
+    fn get_pending_attachment_data(&self, hash: &Vec<u8>) -> Result<Option<PendingAttachmentData>>;
+
+    /// Group of a post that was stored, held or forwarded
+    #[query(
+        "
+        SELECT parent_group FROM posts WHERE post_id = :post_id
+        UNION ALL SELECT parent_group FROM pending_post WHERE post_id = :post_id
+        UNION ALL SELECT parent_group FROM sealed_post WHERE post_id = :post_id
+        UNION ALL SELECT group_id FROM forward_post WHERE post_id = :post_id
+        LIMIT 1
+        "
+    )]
+    fn get_post_group(&self, post_id: &Uuid) -> Result<Option<Uuid>>;
+
+    #[query("INSERT INTO subscription (group_id) VALUES (:group) ON CONFLICT(group_id) DO NOTHING")]
+    fn subscribe_group(&self, group: &Uuid) -> Result<()>;
+
+    #[query("DELETE FROM subscription WHERE group_id = :group")]
+    fn unsubscribe_group(&self, group: &Uuid) -> Result<()>;
+
+    #[query(
+        "
+        WITH RECURSIVE
+           ancestors(n) AS (
+               VALUES(:group)
+               UNION
+               SELECT parent FROM newsgroup, ancestors
+               WHERE newsgroup.uuid = ancestors.n AND parent IS NOT NULL
+           )
+           SELECT EXISTS (SELECT 1 FROM subscription WHERE group_id IN ancestors)
+        "
+    )]
+    fn is_subscribed(&self, group: &Uuid) -> Result<i64>;
+
+    #[query(
+        "
+        WITH RECURSIVE
+           subscribed(n) AS (
+               SELECT group_id FROM subscription
+               UNION
+               SELECT uuid FROM newsgroup, subscribed
+               WHERE newsgroup.parent = subscribed.n
+           )
+           SELECT * FROM newsgroup WHERE uuid IN subscribed ORDER BY group_name
+        "
+    )]
+    #[query_mode(both)]
+    fn get_subscribed_groups(&self) -> Result<Vec<NewsGroup>>;
+
+    #[query("SELECT * FROM posts WHERE post_id = :post_id")]
+    fn get_post(&self, post_id: &Uuid) -> Result<Option<Posts>>;
+
+    #[query(
+        "
+        SELECT post_edit.* FROM post_edit
+        JOIN posts ON posts.post_id = post_edit.post_id AND posts.identity = post_edit.identity
+        WHERE post_edit.post_id = :post_id AND post_edit.verification = 1
+        ORDER BY post_edit.edit_date
+        "
+    )]
+    fn get_post_history(&self, post_id: &Uuid) -> Result<Vec<PostEdit>>;
+
+    #[query(
+        "
+        SELECT post_edit.* FROM post_edit
+        JOIN posts ON posts.post_id = post_edit.post_id AND posts.identity = post_edit.identity
+        WHERE post_edit.post_id = :post_id AND post_edit.verification = 1
+        ORDER BY post_edit.edit_date DESC LIMIT 1
+        "
+    )]
+    fn get_latest_edit(&self, post_id: &Uuid) -> Result<Option<PostEdit>>;
+
+    #[query("SELECT * FROM post_edit WHERE identity = :identity AND verification = 0")]
+    fn get_unverified_edits(&self, identity: &Uuid) -> Result<Vec<PostEdit>>;
+
+    #[query("UPDATE post_edit SET verification = :verification WHERE edit_id = :edit_id")]
+    fn set_edit_verification(&self, edit_id: &Uuid, verification: SigStatus) -> Result<()>;
+
+    #[query("SELECT * FROM post_edit WHERE sent = '0'")]
+    fn get_unsent_edits(&self) -> Result<Vec<PostEdit>>;
+
+    #[query("UPDATE post_edit SET sent = '1' WHERE edit_id IN rarray(:ids)")]
+    fn mark_sent_edits(&self, ids: Vec<Value>) -> Result<()>;
+
+    #[query("DELETE FROM post_edit WHERE post_id = :post_id")]
+    fn delete_post_edits(&self, post_id: &Uuid) -> Result<()>;
+
+    #[query("SELECT * FROM tombstone WHERE post_id = :post_id AND identity = :identity")]
+    fn get_tombstone(&self, post_id: &Uuid, identity: &Uuid) -> Result<Option<Tombstone>>;
+
+    #[query("SELECT * FROM tombstone WHERE identity = :identity AND verification = 0")]
+    fn get_unverified_tombstones(&self, identity: &Uuid) -> Result<Vec<Tombstone>>;
+
+    #[query(
+        "UPDATE tombstone SET verification = :verification
+         WHERE post_id = :post_id AND identity = :identity"
+    )]
+    fn set_tombstone_verification(
+        &self,
+        post_id: &Uuid,
+        identity: &Uuid,
+        verification: SigStatus,
+    ) -> Result<()>;
+
+    #[query("SELECT * FROM tombstone WHERE sent = '0'")]
+    fn get_unsent_tombstones(&self) -> Result<Vec<Tombstone>>;
+
+    // tombstones are kept per signer, so both key columns are needed
+    #[query("UPDATE tombstone SET sent = '1' WHERE post_id = :post_id AND identity = :identity")]
+    fn mark_sent_tombstone(&self, post_id: &Uuid, identity: &Uuid) -> Result<()>;
+
+    #[query("DELETE FROM posts WHERE post_id = :post_id AND identity = :identity")]
+    fn delete_retracted_post(&self, post_id: &Uuid, identity: &Uuid) -> Result<()>;
+
+    #[query("DELETE FROM attachments WHERE post_id = :post_id")]
+    fn delete_post_attachments(&self, post_id: &Uuid) -> Result<()>;
+
+    #[query("INSERT INTO starred_post (post_id) VALUES (:post_id) ON CONFLICT(post_id) DO NOTHING")]
+    fn star_post(&self, post_id: &Uuid) -> Result<()>;
+
+    #[query("DELETE FROM starred_post WHERE post_id = :post_id")]
+    fn unstar_post(&self, post_id: &Uuid) -> Result<()>;
+
+    #[query(
+        "SELECT posts.* FROM posts JOIN starred_post ON starred_post.post_id = posts.post_id
+         ORDER BY receive_date DESC"
+    )]
+    #[query_mode(both)]
+    fn get_starred_posts(&self) -> Result<Vec<Posts>>;
+
+    #[query(
+        "SELECT post_id FROM prunable_post WHERE receive_date < :before
+         ORDER BY receive_date, post_id LIMIT :limit"
+    )]
+    fn get_expired_posts(&self, before: NaiveDateTime, limit: u32) -> Result<Vec<Uuid>>;
+
+    #[query(
+        "
+        SELECT post_id FROM (
+            SELECT post_id, receive_date, ROW_NUMBER() OVER (
+                PARTITION BY parent_group ORDER BY receive_date DESC, post_id DESC
+            ) AS n
+            FROM posts
+        )
+        WHERE n > :max AND post_id IN (SELECT post_id FROM prunable_post)
+        ORDER BY receive_date, post_id LIMIT :limit
+        "
+    )]
+    fn get_excess_posts(&self, max: u32, limit: u32) -> Result<Vec<Uuid>>;
+
+    #[query("SELECT post_id FROM prunable_post ORDER BY receive_date, post_id LIMIT :limit")]
+    fn get_oldest_prunable_posts(&self, limit: u32) -> Result<Vec<Uuid>>;
+
+    #[query(
+        "SELECT (page_count - freelist_count) * page_size
+         FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()"
+    )]
+    fn get_used_bytes(&self) -> Result<i64>;
+
+    #[query("SELECT COUNT(*) FROM attachments WHERE post_id IN rarray(:ids)")]
+    fn count_attachments_of(&self, ids: Vec<Value>) -> Result<i64>;
+
+    #[query(
+        "DELETE FROM attachment_data
+         WHERE hash IN (SELECT hash FROM attachments WHERE post_id IN rarray(:ids))
+            AND hash NOT IN (SELECT hash FROM attachments WHERE post_id NOT IN rarray(:ids))"
+    )]
+    fn delete_attachment_data_of(&self, ids: Vec<Value>) -> Result<()>;
+
+    #[query("DELETE FROM attachments WHERE post_id IN rarray(:ids)")]
+    fn delete_attachments_of(&self, ids: Vec<Value>) -> Result<()>;
+
+    #[query("DELETE FROM post_edit WHERE post_id IN rarray(:ids)")]
+    fn delete_edits_of(&self, ids: Vec<Value>) -> Result<()>;
+
+    #[query("DELETE FROM posts WHERE post_id IN rarray(:ids)")]
+    fn delete_posts(&self, ids: Vec<Value>) -> Result<()>;
+
+    #[query("INSERT OR IGNORE INTO pruned_post (post_id) SELECT value FROM rarray(:ids)")]
+    fn remember_pruned_posts(&self, ids: Vec<Value>) -> Result<()>;
+
+    #[query("SELECT EXISTS (SELECT 1 FROM pruned_post WHERE post_id = :post_id)")]
+    fn is_post_pruned(&self, post_id: &Uuid) -> Result<i64>;
+
+    #[query("SELECT * FROM group_key WHERE group_id = :group_id")]
+    fn get_group_key(&self, group_id: &Uuid) -> Result<Option<GroupKey>>;
+
+    #[query("SELECT * FROM key_grant WHERE sent = '0'")]
+    fn get_unsent_key_grants(&self) -> Result<Vec<KeyGrant>>;
+
+    #[query("UPDATE key_grant SET sent = '1' WHERE grant_id IN rarray(:ids)")]
+    fn mark_sent_key_grants(&self, ids: Vec<Value>) -> Result<()>;
+
+    #[query("SELECT * FROM sealed_post WHERE parent_group = :parent_group")]
+    fn get_sealed_posts(&self, parent_group: &Uuid) -> Result<Vec<SealedPost>>;
+
+    #[query("DELETE FROM sealed_post WHERE post_id = :post_id")]
+    fn delete_sealed_post(&self, post_id: &Uuid) -> Result<()>;
+
+    #[query(
+        "DELETE FROM sealed_post WHERE rowid IN (
+            SELECT rowid FROM sealed_post ORDER BY rowid DESC LIMIT -1 OFFSET :max
+        )"
+    )]
+    fn trim_sealed_posts(&self, max: u32) -> Result<()>;
+
+    #[query("SELECT * FROM sealed_post WHERE sent = '0'")]
+    fn get_unsent_sealed_posts(&self) -> Result<Vec<SealedPost>>;
+
+    #[query("UPDATE sealed_post SET sent = '1' WHERE post_id IN rarray(:ids)")]
+    fn mark_sent_sealed_posts(&self, ids: Vec<Value>) -> Result<()>;
+
+    /// Grants addressed to one of our identities that are waiting on the
+    /// group or its owner's key to be checked
+    #[query(
+        "SELECT key_grant.* FROM key_grant
+        INNER JOIN signing_key ON signing_key.identity = key_grant.recipient
+        WHERE key_grant.group_id = :group_id"
+    )]
+    fn get_received_key_grants(&self, group_id: &Uuid) -> Result<Vec<KeyGrant>>;
+
+    #[query(
+        "DELETE FROM key_grant WHERE group_id = :group_id
+        AND recipient IN (SELECT identity FROM signing_key)"
+    )]
+    fn delete_received_key_grants(&self, group_id: &Uuid) -> Result<()>;
+
+    #[query("SELECT * FROM newsgroup WHERE owner = :owner")]
+    fn get_owned_groups(&self, owner: &Uuid) -> Result<Vec<NewsGroup>>;
 
     #[query("SELECT * FROM posts WHERE parent_group = :parent ORDER BY receive_date DESC")]
+    #[query_mode(both)]
     fn get_posts(&self, parent: &Uuid) -> Result<Vec<Posts>>;
 
+    #[query("SELECT * FROM posts WHERE parent_group = :parent")]
+    #[query_mode(both)]
+    fn get_posts_page(
+        &self,
+        parent: &Uuid,
+        cursor: Option<String>,
+        limit: u32,
+    ) -> Result<Page<Posts>>;
+
+    #[query("SELECT * FROM posts WHERE parent_group = :parent ORDER BY receive_date DESC")]
+    fn stream_posts(&self, parent: &Uuid, each: impl FnMut(Posts) -> Result<()>) -> Result<()>;
+
     #[query("SELECT * FROM posts WHERE sent = '0'")]
     fn get_unsent_posts(&self) -> Result<Vec<Posts>>;
 
@@ -110,18 +700,156 @@ pub trait SubrosaDao {
     #[query("SELECT receive_date FROM posts ORDER BY receive_date LIMIT 1")]
     fn get_last_sync_date(&self) -> Result<Option<NaiveDateTime>>;
 
-    #[query("SELECT * FROM posts LEFT JOIN user ON user.identity = posts.identity WHERE parent_group = (:parent) ORDER BY receive_date DESC")]
+    #[query(
+        "
+        SELECT posts.*, identity.user_name AS author, identity.uuid AS fingerprint,
+            identity.user_name, identity.bio, identity.owned, identity.image_bytes
+        FROM posts LEFT JOIN identity ON identity.uuid = posts.identity
+        WHERE parent_group = :parent
+        ORDER BY receive_date DESC
+        "
+    )]
+    #[query_mode(both)]
     fn get_posts_with_identity(&self, parent: &Uuid) -> Result<Vec<PostWithIdentity>>;
 
+    #[query(
+        "
+        SELECT posts.*, identity.user_name AS author, identity.uuid AS fingerprint,
+            identity.user_name, identity.bio, identity.owned, identity.image_bytes
+        FROM posts LEFT JOIN identity ON identity.uuid = posts.identity
+        WHERE parent_group = :parent
+        "
+    )]
+    #[query_mode(both)]
+    fn get_posts_with_identity_page(
+        &self,
+        parent: &Uuid,
+        cursor: Option<String>,
+        limit: u32,
+    ) -> Result<Page<PostWithIdentity>>;
+
     #[query("UPDATE posts SET sent = '1' WHERE post_id IN rarray(:ids)")]
     fn mark_sent_posts(&self, ids: Vec<Value>) -> Result<()>;
 
     #[query("UPDATE newsgroup SET sent = '1' WHERE uuid IN rarray(:ids)")]
     fn mark_sent_groups(&self, ids: Vec<Value>) -> Result<()>;
 
-    #[query("SELECT * FOM identity where uuid = :uuid")]
+    #[query("SELECT * FROM identity WHERE uuid = :uuid")]
     fn get_identity(&self, uuid: &Uuid) -> Result<CachedIdentity>;
 
+    #[query("SELECT * FROM identity WHERE uuid = :uuid")]
+    fn get_cached_identity(&self, uuid: &Uuid) -> Result<Option<CachedIdentity>>;
+
+    #[query(
+        "INSERT INTO identity (uuid, fingerprint, owned, public_key) VALUES (:uuid, :uuid, :owned, :public_key)
+         ON CONFLICT(uuid) DO UPDATE SET public_key = excluded.public_key"
+    )]
+    fn set_public_key(&self, uuid: &Uuid, owned: bool, public_key: &Vec<u8>) -> Result<()>;
+
+    #[query(
+        "UPDATE identity SET user_name = :user_name, bio = :bio, image_bytes = :image_bytes, version = :version
+         WHERE uuid = :uuid AND (version < :version OR user_name IS NULL)"
+    )]
+    fn update_cached_profile(
+        &self,
+        uuid: &Uuid,
+        user_name: &Option<String>,
+        bio: &Option<String>,
+        image_bytes: &Option<Vec<u8>>,
+        version: i64,
+    ) -> Result<()>;
+
+    #[query("SELECT * FROM user WHERE owned = 1 AND sent = '0'")]
+    fn get_unsent_users(&self) -> Result<Vec<User>>;
+
+    #[query(
+        "INSERT INTO identity (uuid, fingerprint, user_name, bio, owned, image_bytes, version, sent)
+         VALUES (:identity, :identity, :user_name, :bio, 1, :image_bytes, :version, '0')
+         ON CONFLICT(uuid) DO UPDATE SET user_name = excluded.user_name, bio = excluded.bio,
+            owned = 1, image_bytes = excluded.image_bytes, version = excluded.version, sent = '0'"
+    )]
+    fn set_profile(
+        &self,
+        identity: &Uuid,
+        user_name: &str,
+        bio: &str,
+        image_bytes: &Vec<u8>,
+        version: i64,
+    ) -> Result<()>;
+
+    #[query("UPDATE identity SET sent = '1' WHERE uuid IN rarray(:ids)")]
+    fn mark_sent_users(&self, ids: Vec<Value>) -> Result<()>;
+
+    #[query("SELECT * FROM pending_profile WHERE uuid = :uuid ORDER BY version DESC")]
+    fn get_pending_profiles(&self, uuid: &Uuid) -> Result<Vec<PendingProfile>>;
+
+    #[query("DELETE FROM pending_profile WHERE uuid = :uuid")]
+    fn delete_pending_profiles(&self, uuid: &Uuid) -> Result<()>;
+
+    #[query(
+        "DELETE FROM pending_profile WHERE rowid IN (
+            SELECT rowid FROM pending_profile ORDER BY rowid DESC LIMIT -1 OFFSET :max
+        )"
+    )]
+    fn trim_pending_profiles(&self, max: u32) -> Result<()>;
+
+    #[query("SELECT * FROM signing_key WHERE identity = :identity")]
+    fn get_signing_key(&self, identity: &Uuid) -> Result<Option<IdentityKey>>;
+
+    // posts stored without their body can't be verified any more
+    #[query(
+        "SELECT * FROM posts WHERE identity = :identity AND verification = 0 AND body IS NOT NULL"
+    )]
+    fn get_unverified_posts(&self, identity: &Uuid) -> Result<Vec<Posts>>;
+
+    #[query("UPDATE posts SET verification = :verification WHERE post_id = :post_id")]
+    fn set_verification(&self, post_id: &Uuid, verification: SigStatus) -> Result<()>;
+
+    #[query("SELECT * FROM posts WHERE reply_to = :post ORDER BY receive_date")]
+    #[query_mode(both)]
+    fn get_replies(&self, post: &Uuid) -> Result<Vec<Posts>>;
+
+    #[query("SELECT COUNT(*) FROM posts WHERE reply_to = :post")]
+    fn get_reply_count(&self, post: &Uuid) -> Result<i64>;
+
+    #[query(
+        "
+        SELECT posts.*, 0 AS depth,
+            (SELECT COUNT(*) FROM posts AS r WHERE r.reply_to = posts.post_id) AS reply_count
+        FROM posts
+        WHERE parent_group = :parent
+        AND (reply_to IS NULL OR reply_to NOT IN (SELECT post_id FROM posts))
+        ORDER BY receive_date DESC
+        "
+    )]
+    #[query_mode(both)]
+    fn get_thread_roots(&self, parent: &Uuid) -> Result<Vec<ThreadPost>>;
+
+    // dates are stored without trailing zeros in the fraction, so they are
+    // padded to nanoseconds to sort siblings by age
+    #[query(
+        "
+        WITH RECURSIVE
+           thread(id, depth, path) AS (
+               SELECT post_id, 0, substr(receive_date || IIF(instr(receive_date, '.'), '', '.')
+                   || '000000000', 1, 29) || hex(post_id)
+               FROM posts WHERE post_id = :root
+               UNION ALL
+               SELECT posts.post_id, thread.depth + 1, thread.path || '/'
+                   || substr(posts.receive_date || IIF(instr(posts.receive_date, '.'), '', '.')
+                   || '000000000', 1, 29) || hex(posts.post_id)
+               FROM posts, thread
+               WHERE posts.reply_to = thread.id AND thread.depth < 512
+           )
+           SELECT posts.*, thread.depth,
+               (SELECT COUNT(*) FROM posts AS r WHERE r.reply_to = posts.post_id) AS reply_count
+           FROM posts JOIN thread ON posts.post_id = thread.id
+           ORDER BY thread.path
+        "
+    )]
+    #[query_mode(both)]
+    fn get_thread(&self, root: &Uuid) -> Result<Vec<ThreadPost>>;
+
     #[query(
         "
         WITH RECURSIVE
@@ -131,32 +859,245 @@ pub trait SubrosaDao {
                SELECT uuid FROM newsgroup, post_count
                WHERE newsgroup.parent=post_count.n
            )
-           SELECT COUNT(posts.id) FROM posts
-           WHERE uuid IN post_count
+           SELECT COUNT(*) FROM posts
+           WHERE parent_group IN post_count
         "
     )]
     fn get_total_posts(&self, group: &Uuid) -> Result<i64>;
 
-    #[query(
-        "
-        WITH RECURSIVE
-           parent(id) AS (
-               select parent from newsgroup where uuid = :group
-               UNION ALL
-               SELECT parent FROM newsgroup, parent
-               WHERE newsgroup.uuid=parent.id
-            )
-            SELECT * FROM newsgroup where uuid in parent OR uuid = :group
-        "
-    )]
-    fn get_parents(&self, group: &Uuid) -> Result<Vec<NewsGroup>>;
+    #[query(
+        "
+        SELECT posts.*,
+            highlight(posts_fts, 0, '<mark>', '</mark>') AS header_snippet,
+            snippet(posts_fts, 1, '<mark>', '</mark>', '…', 32) AS body_snippet,
+            bm25(posts_fts, 2.0, 1.0) AS rank
+        FROM posts_fts JOIN posts ON posts.rowid = posts_fts.rowid
+        WHERE posts_fts MATCH :query
+        ORDER BY rank
+        LIMIT :limit
+        "
+    )]
+    #[query_mode(both)]
+    fn search_posts(&self, query: &str, limit: u32) -> Result<Vec<SearchResult>>;
+
+    #[query(
+        "
+        WITH RECURSIVE
+           subtree(n) AS (
+               VALUES(:group)
+               UNION
+               SELECT uuid FROM newsgroup, subtree
+               WHERE newsgroup.parent=subtree.n
+           )
+           SELECT posts.*,
+               highlight(posts_fts, 0, '<mark>', '</mark>') AS header_snippet,
+               snippet(posts_fts, 1, '<mark>', '</mark>', '…', 32) AS body_snippet,
+               bm25(posts_fts, 2.0, 1.0) AS rank
+           FROM posts_fts JOIN posts ON posts.rowid = posts_fts.rowid
+           WHERE posts_fts MATCH :query AND parent_group IN subtree
+           ORDER BY rank
+           LIMIT :limit
+        "
+    )]
+    #[query_mode(both)]
+    fn search_posts_in_group(
+        &self,
+        group: &Uuid,
+        query: &str,
+        limit: u32,
+    ) -> Result<Vec<SearchResult>>;
+
+    #[query(
+        "
+        WITH RECURSIVE
+           parent(id) AS (
+               select parent from newsgroup where uuid = :group
+               UNION ALL
+               SELECT parent FROM newsgroup, parent
+               WHERE newsgroup.uuid=parent.id
+            )
+            SELECT * FROM newsgroup where uuid in parent OR uuid = :group
+        "
+    )]
+    fn get_parents(&self, group: &Uuid) -> Result<Vec<NewsGroup>>;
+
+    #[query("INSERT INTO read_post (post_id) VALUES (:post_id) ON CONFLICT(post_id) DO NOTHING")]
+    fn mark_post_read(&self, post_id: &Uuid) -> Result<()>;
+
+    #[query("DELETE FROM read_post WHERE post_id = :post_id")]
+    fn mark_post_unread(&self, post_id: &Uuid) -> Result<()>;
+
+    #[query(
+        "
+        WITH RECURSIVE
+           thread(id) AS (
+               VALUES(:root)
+               UNION
+               SELECT posts.post_id FROM posts, thread
+               WHERE posts.reply_to = thread.id
+           )
+           INSERT INTO read_post (post_id)
+           SELECT post_id FROM posts WHERE post_id IN thread
+           ON CONFLICT(post_id) DO NOTHING
+        "
+    )]
+    fn mark_thread_read(&self, root: &Uuid) -> Result<()>;
+
+    #[query(
+        "
+        WITH RECURSIVE
+           thread(id) AS (
+               VALUES(:root)
+               UNION
+               SELECT posts.post_id FROM posts, thread
+               WHERE posts.reply_to = thread.id
+           )
+           DELETE FROM read_post WHERE post_id IN thread
+        "
+    )]
+    fn mark_thread_unread(&self, root: &Uuid) -> Result<()>;
+
+    #[query(
+        "
+        WITH RECURSIVE
+           subtree(n) AS (
+               VALUES(:group)
+               UNION
+               SELECT uuid FROM newsgroup, subtree
+               WHERE newsgroup.parent=subtree.n
+           )
+           INSERT INTO read_post (post_id)
+           SELECT post_id FROM posts WHERE parent_group IN subtree
+           ON CONFLICT(post_id) DO NOTHING
+        "
+    )]
+    fn mark_group_read(&self, group: &Uuid) -> Result<()>;
+
+    #[query(
+        "
+        WITH RECURSIVE
+           subtree(n) AS (
+               VALUES(:group)
+               UNION
+               SELECT uuid FROM newsgroup, subtree
+               WHERE newsgroup.parent=subtree.n
+           )
+           DELETE FROM read_post
+           WHERE post_id IN (SELECT post_id FROM posts WHERE parent_group IN subtree)
+        "
+    )]
+    fn mark_group_unread(&self, group: &Uuid) -> Result<()>;
+
+    #[query(
+        "
+        SELECT posts.* FROM posts
+        WHERE parent_group = :parent
+            AND NOT EXISTS (SELECT 1 FROM read_post WHERE read_post.post_id = posts.post_id)
+        ORDER BY receive_date DESC
+        "
+    )]
+    #[query_mode(both)]
+    fn get_unread_posts(&self, parent: &Uuid) -> Result<Vec<Posts>>;
+
+    #[query(
+        "
+        WITH RECURSIVE
+           ancestry(descendant, ancestor) AS (
+               SELECT uuid, uuid FROM newsgroup
+               UNION
+               SELECT ancestry.descendant, newsgroup.parent FROM ancestry, newsgroup
+               WHERE newsgroup.uuid = ancestry.ancestor AND newsgroup.parent IS NOT NULL
+           ),
+           unread(group_id, n) AS (
+               SELECT parent_group, COUNT(*) FROM posts
+               WHERE NOT EXISTS (SELECT 1 FROM read_post WHERE read_post.post_id = posts.post_id)
+               GROUP BY parent_group
+           )
+           SELECT ancestry.ancestor AS group_id,
+               COALESCE(SUM(unread.n) FILTER (WHERE ancestry.descendant = ancestry.ancestor), 0)
+                   AS unread,
+               COALESCE(SUM(unread.n), 0) AS unread_total
+           FROM ancestry LEFT JOIN unread ON unread.group_id = ancestry.descendant
+           GROUP BY ancestry.ancestor
+        "
+    )]
+    #[query_mode(both)]
+    fn get_unread_counts(&self) -> Result<Vec<UnreadCount>>;
+}
+
+pub trait TestTestDao {}
+
+#[derive(FromRow)]
+#[table("posts")]
+pub struct Posts {
+    pub header: Option<String>,
+    pub body: Option<String>,
+    pub sig: Option<Vec<u8>>,
+    pub receive_date: NaiveDateTime,
+    #[primary]
+    pub post_id: Uuid,
+    pub identity: Option<Uuid>,
+    pub parent_group: Uuid,
+    pub sent: bool,
+    pub verification: SigStatus,
+    pub reply_to: Option<Uuid>,
+}
+
+/// A post received before its newsgroup, held until the group arrives
+#[derive(FromRow)]
+#[table("pending_post")]
+pub struct PendingPost {
+    pub header: Option<String>,
+    pub body: Option<String>,
+    pub sig: Option<Vec<u8>>,
+    pub receive_date: NaiveDateTime,
+    #[primary]
+    pub post_id: Uuid,
+    pub identity: Option<Uuid>,
+    pub parent_group: Uuid,
+    pub sent: bool,
+    pub verification: SigStatus,
+    pub reply_to: Option<Uuid>,
+}
+
+impl From<Posts> for PendingPost {
+    fn from(value: Posts) -> Self {
+        PendingPost {
+            header: value.header,
+            body: value.body,
+            sig: value.sig,
+            receive_date: value.receive_date,
+            post_id: value.post_id,
+            identity: value.identity,
+            parent_group: value.parent_group,
+            sent: value.sent,
+            verification: value.verification,
+            reply_to: value.reply_to,
+        }
+    }
 }
 
-pub trait TestTestDao {}
+impl From<PendingPost> for Posts {
+    fn from(value: PendingPost) -> Self {
+        Posts {
+            header: value.header,
+            body: value.body,
+            sig: value.sig,
+            receive_date: value.receive_date,
+            post_id: value.post_id,
+            identity: value.identity,
+            parent_group: value.parent_group,
+            sent: value.sent,
+            verification: value.verification,
+            reply_to: value.reply_to,
+        }
+    }
+}
 
+/// A post within a reply thread. `depth` is 0 for the thread root and rows
+/// are returned depth first, so a thread can be rendered as a tree in order.
 #[derive(FromRow)]
-#[table("posts")]
-pub struct Posts {
+pub struct ThreadPost {
     pub header: Option<String>,
     pub body: Option<String>,
     pub sig: Option<Vec<u8>>,
@@ -166,6 +1107,10 @@ pub struct Posts {
     pub identity: Option<Uuid>,
     pub parent_group: Uuid,
     pub sent: bool,
+    pub verification: SigStatus,
+    pub reply_to: Option<Uuid>,
+    pub depth: i64,
+    pub reply_count: i64,
 }
 
 #[derive(FromRow)]
@@ -180,6 +1125,8 @@ pub struct PostWithIdentity {
     pub identity: Option<Uuid>,
     pub parent_group: Uuid,
     pub sent: bool,
+    pub verification: SigStatus,
+    pub reply_to: Option<Uuid>,
     pub fingerprint: Option<Uuid>,
     pub user_name: Option<String>,
     pub bio: Option<String>,
@@ -187,6 +1134,35 @@ pub struct PostWithIdentity {
     pub image_bytes: Option<Vec<u8>>,
 }
 
+/// Unread posts of a group, `unread_total` also counts its subgroups
+#[derive(FromRow)]
+pub struct UnreadCount {
+    #[primary]
+    pub group_id: Uuid,
+    pub unread: i64,
+    pub unread_total: i64,
+}
+
+/// A post matching a full text search. Matched terms in the snippets are
+/// wrapped in `<mark>` tags and a lower `rank` is a better match.
+#[derive(FromRow)]
+pub struct SearchResult {
+    pub header: Option<String>,
+    pub body: Option<String>,
+    pub sig: Option<Vec<u8>>,
+    pub receive_date: NaiveDateTime,
+    #[primary]
+    pub post_id: Uuid,
+    pub identity: Option<Uuid>,
+    pub parent_group: Uuid,
+    pub sent: bool,
+    pub verification: SigStatus,
+    pub reply_to: Option<Uuid>,
+    pub header_snippet: Option<String>,
+    pub body_snippet: Option<String>,
+    pub rank: f64,
+}
+
 #[derive(FromRow)]
 #[table("identity")]
 pub struct CachedIdentity {
@@ -197,7 +1173,535 @@ pub struct CachedIdentity {
     pub bio: Option<String>,
     pub owned: Option<bool>,
     pub image_bytes: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+    pub version: i64,
+    pub sent: bool,
+}
+
+/// Signed profile of a peer, held until the peer's key is known to check it
+#[derive(FromRow)]
+#[table("pending_profile")]
+pub struct PendingProfile {
+    #[primary]
+    pub uuid: Uuid,
+    #[primary]
+    pub version: i64,
+    pub user_name: String,
+    pub bio: String,
+    pub image_bytes: Option<Vec<u8>>,
+    pub sig: Vec<u8>,
+}
+
+/// Local secret key used to sign posts from an owned identity. Never synced.
+#[derive(FromRow)]
+#[table("signing_key")]
+#[frb(opaque)]
+pub struct IdentityKey {
+    #[primary]
+    pub identity: Uuid,
+    pub secret_key: Vec<u8>,
+}
+
+/// File attached to a post. The contents travel as a separate scatterbrain
+/// file message and are matched back up by `hash`.
+#[derive(FromRow, Clone)]
+#[table("attachments")]
+pub struct Attachment {
+    #[primary]
+    pub attachment_id: Uuid,
+    pub post_id: Uuid,
+    pub hash: Vec<u8>,
+    pub mime: String,
+    pub file_name: String,
+    pub size: i64,
+    pub sent: bool,
+}
+
+/// Symmetric key of a private newsgroup. Never synced directly, members get
+/// it through a [`KeyGrant`].
+#[derive(FromRow)]
+#[table("group_key")]
+#[frb(opaque)]
+pub struct GroupKey {
+    #[primary]
+    pub group_id: Uuid,
+    pub key: Vec<u8>,
+}
+
+/// A group key sealed to one member's identity key
+#[derive(FromRow)]
+#[table("key_grant")]
+pub struct KeyGrant {
+    #[primary]
+    pub grant_id: Uuid,
+    pub group_id: Uuid,
+    pub recipient: Uuid,
+    pub ephemeral_key: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    pub sent: bool,
+    pub sig: Option<Vec<u8>>,
+}
+
+/// Encoded post from a private group we don't have the key for, opened once
+/// the key arrives. Passed on to peers like any other post.
+#[derive(FromRow)]
+#[table("sealed_post")]
+pub struct SealedPost {
+    #[primary]
+    pub post_id: Uuid,
+    pub parent_group: Uuid,
+    pub body: Vec<u8>,
+    pub sent: bool,
+}
+
+/// Author-signed replacement for the header and body of a post. The post keeps
+/// its original signed content, every edit is kept as history and the newest
+/// valid one is shown.
+#[derive(FromRow, Clone, Debug)]
+#[table("post_edit")]
+pub struct PostEdit {
+    #[primary]
+    pub edit_id: Uuid,
+    pub post_id: Uuid,
+    pub identity: Uuid,
+    pub header: String,
+    pub body: String,
+    pub edit_date: NaiveDateTime,
+    pub sig: Option<Vec<u8>>,
+    pub verification: SigStatus,
+    pub sent: bool,
+}
+
+/// Author-signed retraction of a post. Kept after the post is deleted so a
+/// later re-delivery of the original isn't stored again. Only the one signed
+/// by the post's author retracts it, so they are kept per signer.
+#[derive(FromRow, Clone, Debug)]
+#[table("tombstone")]
+pub struct Tombstone {
+    #[primary]
+    pub post_id: Uuid,
+    #[primary]
+    pub identity: Uuid,
+    pub retract_date: NaiveDateTime,
+    pub sig: Option<Vec<u8>>,
+    pub verification: SigStatus,
+    pub sent: bool,
+}
+
+#[derive(FromRow)]
+#[table("attachment_data")]
+pub struct AttachmentData {
+    #[primary]
+    pub hash: Vec<u8>,
+    pub body: Vec<u8>,
+}
+
+impl AttachmentData {
+    pub fn new(body: Vec<u8>) -> Self {
+        AttachmentData {
+            hash: Sha256::digest(&body).to_vec(),
+            body,
+        }
+    }
+}
+
+/// Contents of a file message that no stored attachment refers to yet. Only
+/// the most recent are kept, see `trim_pending_attachment_data`.
+#[derive(FromRow)]
+#[table("pending_attachment_data")]
+pub struct PendingAttachmentData {
+    #[primary]
+    pub hash: Vec<u8>,
+    pub body: Vec<u8>,
+    pub receive_date: NaiveDateTime,
+}
+
+impl From<AttachmentData> for PendingAttachmentData {
+    fn from(value: AttachmentData) -> Self {
+        PendingAttachmentData {
+            hash: value.hash,
+            body: value.body,
+            receive_date: Utc::now().naive_utc(),
+        }
+    }
+}
+
+impl Attachment {
+    #[frb(sync)]
+    pub fn new(post_id: &Uuid, file_name: String, mime: String, data: &AttachmentData) -> Self {
+        Attachment {
+            attachment_id: Uuid::new_v4(),
+            post_id: *post_id,
+            hash: data.hash.clone(),
+            mime,
+            file_name,
+            size: data.body.len() as i64,
+            sent: false,
+        }
+    }
+
+    #[frb(sync)]
+    pub fn extension(&self) -> String {
+        Path::new(&self.file_name)
+            .extension()
+            .map(|v| v.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn from_proto(proto: proto::Attachment, post_id: Uuid) -> Result<Self> {
+        Ok(Attachment {
+            attachment_id: proto.uuid.ok_or(SubrosaErr::ParseError)?.as_uuid(),
+            post_id,
+            hash: proto.hash,
+            mime: proto.mime,
+            file_name: proto.file_name,
+            size: proto.size as i64,
+            sent: true,
+        })
+    }
+
+    pub(crate) fn to_proto(&self) -> proto::Attachment {
+        proto::Attachment {
+            uuid: Some(self.attachment_id.as_proto()),
+            hash: self.hash.clone(),
+            mime: self.mime.clone(),
+            file_name: self.file_name.clone(),
+            size: self.size as u64,
+        }
+    }
+}
+
+/// Message with a post type or capability this client doesn't support, kept so
+/// it can be forwarded to newer peers
+#[derive(FromRow)]
+#[table("unknown_message")]
+pub struct UnknownMessage {
+    #[primary]
+    pub hash: Vec<u8>,
+    pub post_type: i64,
+    pub version: i64,
+    pub body: Vec<u8>,
+    pub sent: bool,
+}
+
+/// Message from an unsubscribed group, held only until the next sync sends it on.
+/// File messages have a file name.
+#[derive(FromRow)]
+#[table("forward_message")]
+pub struct ForwardMessage {
+    #[primary]
+    pub hash: Vec<u8>,
+    pub body: Vec<u8>,
+    pub file_name: Option<String>,
+    pub mime: Option<String>,
+}
+
+/// Post that was forwarded rather than stored
+#[derive(FromRow)]
+#[table("forward_post")]
+pub struct ForwardPost {
+    #[primary]
+    pub post_id: Uuid,
+    pub group_id: Uuid,
+}
+
+/// Attachment of a forwarded post, its contents are forwarded once they arrive
+#[derive(FromRow)]
+#[table("forward_attachment")]
+pub struct ForwardAttachment {
+    #[primary]
+    pub hash: Vec<u8>,
+}
+
+fn sealed_aad(id: &Uuid, group: &Uuid) -> Vec<u8> {
+    [id.as_bytes().as_slice(), group.as_bytes()].concat()
+}
+
+impl GroupKey {
+    pub(crate) fn new(group_id: Uuid) -> Self {
+        GroupKey {
+            group_id,
+            key: crypto::group_key(),
+        }
+    }
+}
+
+impl KeyGrant {
+    /// Seals the group key to a member's ed25519 identity key
+    pub(crate) fn new(key: &GroupKey, recipient: Uuid, public_key: &[u8]) -> Result<Self> {
+        let (ephemeral_key, sealed) =
+            crypto::seal_to(public_key, &key.key, &sealed_aad(&recipient, &key.group_id))?;
+        Ok(KeyGrant {
+            grant_id: Uuid::new_v4(),
+            group_id: key.group_id,
+            recipient,
+            ephemeral_key,
+            nonce: sealed.nonce,
+            ciphertext: sealed.ciphertext,
+            sent: false,
+            sig: None,
+        })
+    }
+
+    fn signed_digest(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(b"grant");
+        hasher.update(self.grant_id.as_bytes());
+        hasher.update(self.group_id.as_bytes());
+        hasher.update(self.recipient.as_bytes());
+        for field in [&self.ephemeral_key, &self.nonce, &self.ciphertext] {
+            hasher.update((field.len() as u64).to_be_bytes());
+            hasher.update(field);
+        }
+        hasher.finalize().to_vec()
+    }
+
+    /// Signs the grant with the group owner's identity key
+    pub(crate) fn sign(&mut self, secret_key: &[u8]) -> Result<()> {
+        self.sig = Some(
+            signing_key(secret_key)?
+                .sign(&self.signed_digest())
+                .to_vec(),
+        );
+        Ok(())
+    }
+
+    pub(crate) fn verify(&self, public_key: &[u8]) -> SigStatus {
+        verify_digest(public_key, self.sig.as_deref(), &self.signed_digest())
+    }
+
+    /// Opens a grant addressed to us with the recipient's secret key
+    pub(crate) fn open(&self, secret_key: &[u8]) -> Result<GroupKey> {
+        let sealed = proto::Sealed {
+            nonce: self.nonce.clone(),
+            ciphertext: self.ciphertext.clone(),
+        };
+        let key = crypto::open_from(
+            secret_key,
+            &self.ephemeral_key,
+            &sealed,
+            &sealed_aad(&self.recipient, &self.group_id),
+        )?;
+        Ok(GroupKey {
+            group_id: self.group_id,
+            key,
+        })
+    }
+
+    pub(crate) fn from_proto(proto: proto::GroupKey) -> Result<Self> {
+        let sealed = proto.key.ok_or(SubrosaErr::ParseError)?;
+        Ok(KeyGrant {
+            grant_id: proto.uuid.ok_or(SubrosaErr::ParseError)?.as_uuid(),
+            group_id: proto.group.ok_or(SubrosaErr::ParseError)?.as_uuid(),
+            recipient: proto.recipient.ok_or(SubrosaErr::ParseError)?.as_uuid(),
+            ephemeral_key: proto.ephemeral_key,
+            nonce: sealed.nonce,
+            ciphertext: sealed.ciphertext,
+            sent: true,
+            sig: Some(proto.sig),
+        })
+    }
+
+    pub(crate) fn to_proto(&self) -> proto::GroupKey {
+        proto::GroupKey {
+            group: Some(self.group_id.as_proto()),
+            recipient: Some(self.recipient.as_proto()),
+            ephemeral_key: self.ephemeral_key.clone(),
+            key: Some(proto::Sealed {
+                nonce: self.nonce.clone(),
+                ciphertext: self.ciphertext.clone(),
+            }),
+            sig: self.sig.clone().unwrap_or_default(),
+            uuid: Some(self.grant_id.as_proto()),
+        }
+    }
+}
+
+impl PostEdit {
+    pub(crate) fn new(post: &Posts, header: String, body: String) -> Result<Self> {
+        Ok(PostEdit {
+            edit_id: Uuid::new_v4(),
+            post_id: post.post_id,
+            identity: post.identity.ok_or(SubrosaErr::MissingKey)?,
+            header,
+            body,
+            edit_date: Utc::now().naive_utc(),
+            sig: None,
+            verification: SigStatus::UnknownKey,
+            sent: false,
+        })
+    }
+
+    fn signed_digest(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(b"edit");
+        hasher.update(self.edit_id.as_bytes());
+        hasher.update(self.post_id.as_bytes());
+        hasher.update(self.identity.as_bytes());
+        hasher.update(self.edit_date.and_utc().timestamp_millis().to_be_bytes());
+        hash_text(&mut hasher, &self.header);
+        hash_text(&mut hasher, &self.body);
+        hasher.finalize().to_vec()
+    }
+
+    pub fn sign(&mut self, secret_key: &[u8]) -> Result<()> {
+        self.sig = Some(
+            signing_key(secret_key)?
+                .sign(&self.signed_digest())
+                .to_vec(),
+        );
+        self.verification = SigStatus::Valid;
+        Ok(())
+    }
+
+    #[frb(sync)]
+    pub fn verify(&self, public_key: &[u8]) -> SigStatus {
+        verify_digest(public_key, self.sig.as_deref(), &self.signed_digest())
+    }
+
+    pub(crate) fn from_proto(proto: proto::PostEdit) -> Result<Self> {
+        Ok(PostEdit {
+            edit_id: proto.uuid.ok_or(SubrosaErr::ParseError)?.as_uuid(),
+            post_id: proto.post.ok_or(SubrosaErr::ParseError)?.as_uuid(),
+            identity: proto.author.ok_or(SubrosaErr::ParseError)?.as_uuid(),
+            header: proto.header,
+            body: proto.body,
+            edit_date: from_millis(proto.timestamp)?,
+            sig: Some(proto.sig).filter(|v| !v.is_empty()),
+            verification: SigStatus::UnknownKey,
+            sent: true,
+        })
+    }
+
+    pub(crate) fn to_proto(&self) -> proto::PostEdit {
+        proto::PostEdit {
+            uuid: Some(self.edit_id.as_proto()),
+            post: Some(self.post_id.as_proto()),
+            author: Some(self.identity.as_proto()),
+            header: self.header.clone(),
+            body: self.body.clone(),
+            timestamp: self.edit_date.and_utc().timestamp_millis(),
+            sig: self.sig.clone().unwrap_or_default(),
+            sealed: None,
+        }
+    }
+
+    /// Like [`PostEdit::to_proto`] but with the header and body sealed with the
+    /// key of the post's private group
+    pub(crate) fn to_sealed_proto(&self, key: &GroupKey) -> Result<proto::PostEdit> {
+        let mut r = self.to_proto();
+        let content = proto::PostContent {
+            header: std::mem::take(&mut r.header),
+            body: std::mem::take(&mut r.body),
+        };
+        let aad = sealed_aad(&self.edit_id, &key.group_id);
+        r.sealed = Some(crypto::seal(&key.key, &content.encode_to_vec(), &aad)?);
+        Ok(r)
+    }
+
+    pub(crate) fn open_proto(proto: &mut proto::PostEdit, key: &GroupKey) -> Result<()> {
+        let Some(sealed) = proto.sealed.take() else {
+            return Ok(());
+        };
+        let edit_id = proto.uuid.ok_or(SubrosaErr::ParseError)?.as_uuid();
+        let content = crypto::open(&key.key, &sealed, &sealed_aad(&edit_id, &key.group_id))?;
+        let content = proto::PostContent::decode(content.as_slice())?;
+        proto.header = content.header;
+        proto.body = content.body;
+        Ok(())
+    }
+}
+
+impl Tombstone {
+    pub(crate) fn new(post: &Posts) -> Result<Self> {
+        Ok(Tombstone {
+            post_id: post.post_id,
+            identity: post.identity.ok_or(SubrosaErr::MissingKey)?,
+            retract_date: Utc::now().naive_utc(),
+            sig: None,
+            verification: SigStatus::UnknownKey,
+            sent: false,
+        })
+    }
+
+    fn signed_digest(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(b"retract");
+        hasher.update(self.post_id.as_bytes());
+        hasher.update(self.identity.as_bytes());
+        hasher.update(self.retract_date.and_utc().timestamp_millis().to_be_bytes());
+        hasher.finalize().to_vec()
+    }
+
+    pub fn sign(&mut self, secret_key: &[u8]) -> Result<()> {
+        self.sig = Some(
+            signing_key(secret_key)?
+                .sign(&self.signed_digest())
+                .to_vec(),
+        );
+        self.verification = SigStatus::Valid;
+        Ok(())
+    }
+
+    #[frb(sync)]
+    pub fn verify(&self, public_key: &[u8]) -> SigStatus {
+        verify_digest(public_key, self.sig.as_deref(), &self.signed_digest())
+    }
+
+    pub(crate) fn from_proto(proto: proto::Retraction) -> Result<Self> {
+        Ok(Tombstone {
+            post_id: proto.post.ok_or(SubrosaErr::ParseError)?.as_uuid(),
+            identity: proto.author.ok_or(SubrosaErr::ParseError)?.as_uuid(),
+            retract_date: from_millis(proto.timestamp)?,
+            sig: Some(proto.sig).filter(|v| !v.is_empty()),
+            verification: SigStatus::UnknownKey,
+            sent: true,
+        })
+    }
+
+    pub(crate) fn to_proto(&self) -> proto::Retraction {
+        proto::Retraction {
+            post: Some(self.post_id.as_proto()),
+            author: Some(self.identity.as_proto()),
+            timestamp: self.retract_date.and_utc().timestamp_millis(),
+            sig: self.sig.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl UnknownMessage {
+    pub(crate) fn new(post_type: i32, version: u32, body: Vec<u8>) -> Self {
+        UnknownMessage {
+            hash: Sha256::digest(&body).to_vec(),
+            post_type: post_type.into(),
+            version: version.into(),
+            body,
+            sent: false,
+        }
+    }
+}
+
+impl ForwardMessage {
+    pub(crate) fn new(body: Vec<u8>) -> Self {
+        ForwardMessage {
+            hash: Sha256::digest(&body).to_vec(),
+            body,
+            file_name: None,
+            mime: None,
+        }
+    }
+
+    pub(crate) fn file(data: AttachmentData, file_name: String, mime: String) -> Self {
+        ForwardMessage {
+            hash: data.hash,
+            body: data.body,
+            file_name: Some(file_name),
+            mime: Some(mime),
+        }
+    }
 }
+
 #[frb(opaque)]
 pub struct Parent {
     uuid: Uuid,
@@ -232,6 +1736,8 @@ impl NewsGroup {
             parent,
             group_name,
             sent,
+            owner: None,
+            owner_salt: None,
         }
     }
 
@@ -244,10 +1750,31 @@ impl NewsGroup {
         } else {
             hasher.update(&[]);
         }
+        // public groups hash as they did before private groups existed
+        if let Some(owner) = self.owner {
+            hasher.update(owner.into_bytes());
+        }
 
         hasher.finalize().to_vec()
     }
 
+    /// Makes this a private group owned by `owner`, which gives it a new uuid
+    pub(crate) fn set_owner(&mut self, owner: Uuid) {
+        let salt = crypto::group_salt();
+        self.uuid = crypto::owned_group_uuid(&owner, &salt);
+        self.owner = Some(owner);
+        self.owner_salt = Some(salt);
+    }
+
+    /// Whether the uuid of a private group was derived from its owner
+    pub(crate) fn owner_matches(&self) -> bool {
+        match (self.owner, &self.owner_salt) {
+            (None, _) => true,
+            (Some(owner), Some(salt)) => crypto::owned_group_uuid(&owner, salt) == self.uuid,
+            (Some(_), None) => false,
+        }
+    }
+
     pub(crate) fn from_proto(proto: proto::NewsGroup) -> Result<NewsGroup> {
         let (parent, parent_hash) = match proto.parent_option {
             Some(ParentOption::Toplevel(_)) => (None, None),
@@ -263,6 +1790,8 @@ impl NewsGroup {
             parent,
             group_name: proto.name,
             sent: true,
+            owner: proto.owner.map(|v| v.as_uuid()),
+            owner_salt: (!proto.owner_salt.is_empty()).then_some(proto.owner_salt),
         };
 
         Ok(ng)
@@ -281,32 +1810,56 @@ impl NewsGroup {
             description: self.description,
             parent_option: parent,
             name: self.group_name,
+            owner: self.owner.map(|v| v.as_proto()),
+            owner_salt: self.owner_salt.unwrap_or_default(),
         }
     }
 }
 
-impl CachedIdentity {
+impl PendingProfile {
+    pub(crate) fn verify(&self, public_key: &[u8]) -> SigStatus {
+        let digest = profile_digest(
+            &self.uuid,
+            self.version,
+            &self.user_name,
+            &self.bio,
+            self.image_bytes.as_deref().unwrap_or_default(),
+        );
+        verify_digest(public_key, Some(&self.sig), &digest)
+    }
+
     pub(crate) fn from_proto(proto: proto::User) -> Result<Self> {
-        let v = CachedIdentity {
+        Ok(PendingProfile {
             uuid: proto
                 .identity
                 .ok_or_else(|| SubrosaErr::ParseError)?
                 .as_uuid(),
-            fingerprint: Some(
-                proto
-                    .identity
-                    .ok_or_else(|| SubrosaErr::ParseError)?
-                    .as_uuid(),
-            ),
-            user_name: Some(proto.name),
-            bio: Some(proto.bio),
-            owned: Some(false),
+            version: proto.version.min(i64::MAX as u64) as i64,
+            user_name: proto.name,
+            bio: proto.bio,
             image_bytes: match proto.image {
                 Some(Image::Imagebytes(bytes)) => Some(bytes),
                 _ => None,
             },
-        };
-        Ok(v)
+            sig: proto.sig,
+        })
+    }
+}
+
+impl From<PendingProfile> for CachedIdentity {
+    fn from(value: PendingProfile) -> Self {
+        CachedIdentity {
+            uuid: value.uuid,
+            fingerprint: Some(value.uuid),
+            user_name: Some(value.user_name),
+            bio: Some(value.bio),
+            owned: Some(false),
+            image_bytes: value.image_bytes,
+            public_key: None,
+            version: value.version,
+            // profiles of peers are never sent on
+            sent: true,
+        }
     }
 }
 
@@ -330,6 +1883,56 @@ impl Posts {
         Uuid::from_bytes(hash.finalize().as_slice()[0..16].try_into().unwrap())
     }
 
+    /// Digest covered by the author signature. Optional fields are preceded by
+    /// a presence byte and text is length prefixed, so a missing field, an
+    /// empty one and bytes moved between header and body all change the digest.
+    /// The names and hashes of the attachments are covered in sorted order.
+    fn signed_digest(&self, attachments: &[Attachment]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(self.post_id.as_bytes());
+        hash_optional(&mut hasher, self.identity, |h, v| h.update(v.as_bytes()));
+        hasher.update(self.parent_group.as_bytes());
+        hash_optional(&mut hasher, self.reply_to, |h, v| h.update(v.as_bytes()));
+        for field in [&self.header, &self.body] {
+            hash_optional(&mut hasher, field.as_deref(), hash_text);
+        }
+
+        let mut attachments = attachments
+            .iter()
+            .map(|v| (v.file_name.as_str(), v.hash.as_slice()))
+            .collect::<Vec<_>>();
+        attachments.sort();
+        hasher.update((attachments.len() as u64).to_be_bytes());
+        for (file_name, hash) in attachments {
+            hash_text(&mut hasher, file_name);
+            hasher.update((hash.len() as u64).to_be_bytes());
+            hasher.update(hash);
+        }
+
+        hasher.finalize().to_vec()
+    }
+
+    /// Signs the post and its attachments with an ed25519 secret key, either a
+    /// 32 byte seed or a 64 byte libsodium style keypair
+    pub fn sign(&mut self, secret_key: &[u8], attachments: &[Attachment]) -> Result<()> {
+        self.sig = Some(
+            signing_key(secret_key)?
+                .sign(&self.signed_digest(attachments))
+                .to_vec(),
+        );
+        self.verification = SigStatus::Valid;
+        Ok(())
+    }
+
+    #[frb(sync)]
+    pub fn verify(&self, public_key: &[u8], attachments: &[Attachment]) -> SigStatus {
+        verify_digest(
+            public_key,
+            self.sig.as_deref(),
+            &self.signed_digest(attachments),
+        )
+    }
+
     pub(crate) fn from_proto(proto: proto::Post) -> Result<Self> {
         let fingerprint = match proto.author_or {
             Some(AuthorOr::Author(v)) => Some(v.as_uuid()),
@@ -344,13 +1947,15 @@ impl Posts {
                 .and_then(|v| v.uuid)
                 .map(|v| v.as_uuid())
                 .ok_or_else(|| SubrosaErr::ParseError)?,
-            sig: Some(proto.sig),
+            sig: Some(proto.sig).filter(|v| !v.is_empty()),
             receive_date: Utc::now().naive_utc(),
             post_id: proto
                 .uuid
                 .map(|v| v.as_uuid())
                 .unwrap_or_else(|| Uuid::new_v4()),
             sent: true,
+            verification: SigStatus::UnknownKey,
+            reply_to: proto.reply_to.map(|v| v.as_uuid()),
         };
 
         if proto.uuid.is_none() {
@@ -363,18 +1968,48 @@ impl Posts {
     pub(crate) fn to_proto(self, db: &SubrosaDb) -> Result<proto::Post> {
         let newsgroup = db.get_group(self.parent_group)?;
 
-        let r = proto::Post {
+        let mut r = proto::Post {
             uuid: Some(self.post_id.as_proto()),
             header: self.header.unwrap_or_else(|| "".to_owned()),
             body: self.body.unwrap_or_else(|| "".to_owned()),
             parent: newsgroup.map(|v| v.to_proto()),
             sig: self.sig.unwrap_or_else(|| Vec::new()),
             author_or: self.identity.map(|v| AuthorOr::Author(v.as_proto())),
+            reply_to: self.reply_to.map(|v| v.as_proto()),
+            attachments: db
+                .get_attachments(&self.post_id)?
+                .iter()
+                .map(|v| v.to_proto())
+                .collect(),
+            sealed: None,
         };
 
+        if let Some(key) = db.get_group_key(&self.parent_group)? {
+            let content = proto::PostContent {
+                header: std::mem::take(&mut r.header),
+                body: std::mem::take(&mut r.body),
+            };
+            let aad = sealed_aad(&self.post_id, &self.parent_group);
+            r.sealed = Some(crypto::seal(&key.key, &content.encode_to_vec(), &aad)?);
+        }
+
         Ok(r)
     }
 
+    /// Replaces the sealed content of a post from a private group with the
+    /// decrypted header and body
+    pub(crate) fn open_proto(proto: &mut proto::Post, key: &GroupKey) -> Result<()> {
+        let Some(sealed) = proto.sealed.take() else {
+            return Ok(());
+        };
+        let post_id = proto.uuid.ok_or(SubrosaErr::ParseError)?.as_uuid();
+        let content = crypto::open(&key.key, &sealed, &sealed_aad(&post_id, &key.group_id))?;
+        let content = proto::PostContent::decode(content.as_slice())?;
+        proto.header = content.header;
+        proto.body = content.body;
+        Ok(())
+    }
+
     #[frb(sync)]
     pub fn new(header: String, body: String, group: &Uuid) -> Posts {
         Posts {
@@ -386,6 +2021,30 @@ impl Posts {
             identity: None,
             parent_group: *group,
             sent: false,
+            verification: SigStatus::UnknownKey,
+            reply_to: None,
+        }
+    }
+
+    /// Creates a reply to `parent` in the same group
+    #[frb(sync)]
+    pub fn new_reply(
+        header: String,
+        body: String,
+        author: Option<Identity>,
+        parent: &Posts,
+    ) -> Posts {
+        Posts {
+            header: Some(header),
+            body: Some(body),
+            sig: None,
+            receive_date: chrono::offset::Utc::now().naive_utc(),
+            post_id: Uuid::new_v4(),
+            identity: author.and_then(|v| v.fingerprint),
+            parent_group: parent.parent_group,
+            sent: false,
+            verification: SigStatus::UnknownKey,
+            reply_to: Some(parent.post_id),
         }
     }
 
@@ -400,11 +2059,16 @@ impl Posts {
             identity: author.fingerprint,
             parent_group: *group,
             sent: false,
+            verification: SigStatus::UnknownKey,
+            reply_to: None,
         }
     }
 }
 
+/// Profile of an identity, read from the `User` view over `identity`. Owned
+/// profiles are written with [`SubrosaDb::update_profile`].
 #[derive(FromRow)]
+#[view]
 pub struct User {
     #[primary]
     pub identity: Uuid,
@@ -412,12 +2076,45 @@ pub struct User {
     pub bio: String,
     pub owned: bool,
     pub image_bytes: Vec<u8>,
+    pub version: i64,
+    pub sent: bool,
+}
+
+impl User {
+    pub(crate) fn to_proto(&self) -> proto::User {
+        proto::User {
+            identity: Some(self.identity.as_proto()),
+            name: self.user_name.clone(),
+            bio: self.bio.clone(),
+            image: Some(Image::Imagebytes(self.image_bytes.clone()))
+                .filter(|_| !self.image_bytes.is_empty()),
+            version: self.version as u64,
+            sig: vec![],
+        }
+    }
+
+    /// Encodes the profile signed with the identity's key
+    pub(crate) fn to_signed_proto(&self, secret_key: &[u8]) -> Result<proto::User> {
+        let digest = profile_digest(
+            &self.identity,
+            self.version,
+            &self.user_name,
+            &self.bio,
+            &self.image_bytes,
+        );
+        Ok(proto::User {
+            sig: signing_key(secret_key)?.sign(&digest).to_vec(),
+            ..self.to_proto()
+        })
+    }
 }
 
 impl TestDao for SubrosaDb {}
 
 impl SubrosaDao for SubrosaDb {}
 
+impl SubrosaDao for SubrosaTransaction<'_> {}
+
 #[cfg(test)]
 mod test {
     use std::time::Duration;
@@ -429,15 +2126,19 @@ mod test {
     use crate::api::db::{
         connection::{Crud, OnConflict, SubrosaDb},
         migrations::run_migrations,
+        testing::{test_db, test_group},
     };
 
-    use super::{NewsGroup, Posts, SubrosaDao, TestDao};
+    use super::{
+        Attachment, AttachmentData, CachedIdentity, ForwardAttachment, ForwardMessage, ForwardPost,
+        GroupKey, IdentityKey, KeyGrant, NewsGroup, PendingAttachmentData, PendingNewsGroup,
+        PendingPost, PendingProfile, PostEdit, Posts, SealedPost, SigStatus, SubrosaDao, Table,
+        TestDao, Tombstone, UnknownMessage,
+    };
 
     #[test]
     fn insert_generated() {
-        let db = rusqlite::Connection::open_in_memory().unwrap();
-        let db = SubrosaDb::from_conn(db);
-        run_migrations(&db).unwrap();
+        let db = test_db();
 
         let ng = NewsGroup {
             uuid: Uuid::new_v4(),
@@ -446,6 +2147,8 @@ mod test {
             parent: None,
             group_name: "test".to_owned(),
             sent: false,
+            owner: None,
+            owner_salt: None,
         };
 
         ng.insert(&db).unwrap();
@@ -453,9 +2156,7 @@ mod test {
 
     #[test]
     fn insert_on_conflict() {
-        let db = rusqlite::Connection::open_in_memory().unwrap();
-        let db = SubrosaDb::from_conn(db);
-        run_migrations(&db).unwrap();
+        let db = test_db();
 
         let ng = NewsGroup {
             uuid: Uuid::new_v4(),
@@ -464,10 +2165,26 @@ mod test {
             parent: None,
             group_name: "test".to_owned(),
             sent: false,
+            owner: None,
+            owner_salt: None,
         };
 
         ng.insert_on_conflict(&db, OnConflict::Ignore).unwrap();
         ng.insert_on_conflict(&db, OnConflict::Update).unwrap();
+
+        // updating a group in place keeps the posts referencing it
+        let post = Posts::new("test".to_owned(), "test".to_owned(), &ng.uuid);
+        post.insert(&db).unwrap();
+        let renamed = NewsGroup {
+            group_name: "renamed".to_owned(),
+            ..ng
+        };
+        renamed.insert_on_conflict(&db, OnConflict::Update).unwrap();
+        assert_eq!(
+            db.get_group(renamed.uuid).unwrap().unwrap().group_name,
+            "renamed"
+        );
+        assert!(db.get_post(&post.post_id).unwrap().is_some());
     }
 
     #[test]
@@ -493,11 +2210,9 @@ mod test {
 
     #[test]
     fn insert_posts() {
-        let db = rusqlite::Connection::open_in_memory().unwrap();
-        let db = SubrosaDb::from_conn(db);
-        run_migrations(&db).unwrap();
+        let db = test_db();
 
-        let group = Uuid::new_v4();
+        let group = test_group(&db);
         let ng = Posts::new("test".to_owned(), "test".to_owned(), &group);
 
         ng.insert_on_conflict(&db, OnConflict::Ignore).unwrap();
@@ -506,11 +2221,9 @@ mod test {
 
     #[test]
     fn sync_date() {
-        let db = rusqlite::Connection::open_in_memory().unwrap();
-        let db = SubrosaDb::from_conn(db);
-        run_migrations(&db).unwrap();
+        let db = test_db();
 
-        let group = Uuid::new_v4();
+        let group = test_group(&db);
         let ng = Posts::new("test".to_owned(), "test".to_owned(), &group);
 
         ng.insert_on_conflict(&db, OnConflict::Ignore).unwrap();
@@ -522,9 +2235,7 @@ mod test {
 
     #[test]
     fn delete_generate_new() {
-        let db = rusqlite::Connection::open_in_memory().unwrap();
-        let db = SubrosaDb::from_conn(db);
-        run_migrations(&db).unwrap();
+        let db = test_db();
 
         let ng = NewsGroup {
             uuid: Uuid::new_v4(),
@@ -533,6 +2244,8 @@ mod test {
             parent: None,
             group_name: "test".to_owned(),
             sent: false,
+            owner: None,
+            owner_salt: None,
         };
 
         ng.insert(&db).unwrap();
@@ -543,6 +2256,171 @@ mod test {
         assert!(g.is_none());
     }
 
+    #[test]
+    fn count_and_delete_posts() {
+        let db = test_db();
+
+        let parent = NewsGroup::new(
+            Uuid::new_v4(),
+            "parent".to_owned(),
+            None,
+            "parent".to_owned(),
+            false,
+        );
+        let child = NewsGroup::new(
+            Uuid::new_v4(),
+            "child".to_owned(),
+            Some(parent.as_parent()),
+            "child".to_owned(),
+            false,
+        );
+        parent.insert(&db).unwrap();
+        child.insert(&db).unwrap();
+
+        let post = Posts::new("a".to_owned(), "a".to_owned(), &parent.uuid);
+        post.insert(&db).unwrap();
+        Posts::new("b".to_owned(), "b".to_owned(), &child.uuid)
+            .insert(&db)
+            .unwrap();
+
+        assert_eq!(db.get_total_posts(&parent.uuid).unwrap(), 2);
+        assert_eq!(db.get_total_posts(&child.uuid).unwrap(), 1);
+
+        db.delete_post(post.post_id).unwrap();
+        assert!(db.get_post(&post.post_id).unwrap().is_none());
+        assert_eq!(db.get_total_posts(&parent.uuid).unwrap(), 1);
+    }
+
+    #[test]
+    fn insert_many() {
+        let db = test_db();
+
+        let group = test_group(&db);
+        let posts = (0..100)
+            .map(|i| Posts::new(format!("{}", i), "body".to_owned(), &group))
+            .collect::<Vec<_>>();
+
+        Posts::insert_many(&posts[..50], &db).unwrap();
+        assert_eq!(db.get_total_posts(&group).unwrap(), 50);
+
+        // a conflict aborts the whole batch, including the rows before it
+        let mut batch = (0..10)
+            .map(|i| Posts::new(format!("new {}", i), "body".to_owned(), &group))
+            .collect::<Vec<_>>();
+        batch.push(db.get_post(&posts[0].post_id).unwrap().unwrap());
+        assert!(Posts::insert_many(&batch, &db).is_err());
+        assert_eq!(db.get_total_posts(&group).unwrap(), 50);
+        assert!(db.get_post(&batch[0].post_id).unwrap().is_none());
+
+        Posts::insert_many_on_conflict(&posts, &db, OnConflict::Ignore).unwrap();
+        assert_eq!(db.get_total_posts(&group).unwrap(), 100);
+
+        // nested in a transaction the batch is undone with it
+        let more = (0..10)
+            .map(|i| Posts::new(format!("{}", i), "body".to_owned(), &group))
+            .collect::<Vec<_>>();
+        let res = db.transaction::<()>(|tx| {
+            Posts::insert_many(&more, tx)?;
+            assert_eq!(tx.get_total_posts(&group)?, 110);
+            anyhow::bail!("undo")
+        });
+        assert!(res.is_err());
+        assert_eq!(db.get_total_posts(&group).unwrap(), 100);
+    }
+
+    #[test]
+    fn transaction_commit_and_rollback() {
+        let db = test_db();
+
+        let group = test_group(&db);
+        let committed = Posts::new("a".to_owned(), "a".to_owned(), &group);
+        let failed = Posts::new("b".to_owned(), "b".to_owned(), &group);
+        let panicked = Posts::new("c".to_owned(), "c".to_owned(), &group);
+
+        db.transaction(|tx| {
+            committed.insert(tx)?;
+            assert!(tx.get_post(&committed.post_id)?.is_some());
+            Ok(())
+        })
+        .unwrap();
+
+        let res = db.transaction(|tx| {
+            failed.insert(tx)?;
+            committed.insert(tx)
+        });
+        assert!(res.is_err());
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            db.transaction::<()>(|tx| {
+                panicked.insert(tx)?;
+                panic!("abort transaction")
+            })
+        }));
+        assert!(res.is_err());
+
+        assert!(db.get_post(&committed.post_id).unwrap().is_some());
+        assert!(db.get_post(&failed.post_id).unwrap().is_none());
+        assert!(db.get_post(&panicked.post_id).unwrap().is_none());
+    }
+
+    #[test]
+    fn read_during_transaction() {
+        let path = std::env::temp_dir().join(format!("subrosa-{}.db", Uuid::new_v4()));
+        let db = SubrosaDb::new(path.to_str().unwrap()).unwrap();
+        run_migrations(&db).unwrap();
+
+        let ng = NewsGroup {
+            uuid: Uuid::new_v4(),
+            description: "Test".to_owned(),
+            parent_hash: None,
+            parent: None,
+            group_name: "test".to_owned(),
+            sent: false,
+            owner: None,
+            owner_salt: None,
+        };
+
+        // selects go to a reader, so they neither block on nor see the open transaction
+        db.transaction(|tx| {
+            ng.insert(tx)?;
+            assert!(tx.get_group(ng.uuid)?.is_some());
+            assert!(db.get_group(ng.uuid)?.is_none());
+            Ok(())
+        })
+        .unwrap();
+        assert!(db.get_group(ng.uuid).unwrap().is_some());
+
+        drop(db);
+        for ext in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), ext));
+        }
+    }
+
+    #[tokio::test]
+    async fn transaction_async() {
+        let db = test_db();
+
+        let post = Posts::new("a".to_owned(), "a".to_owned(), &test_group(&db));
+        let post_id = post.post_id;
+        let count = db
+            .transaction_async(move |tx| {
+                post.insert(tx)?;
+                Ok(tx.get_total_posts(&post.parent_group)?)
+            })
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+
+        let res = db
+            .transaction_async::<(), _>(move |tx| {
+                tx.delete_post(post_id)?;
+                anyhow::bail!("undo")
+            })
+            .await;
+        assert!(res.is_err());
+        assert!(db.get_post(&post_id).unwrap().is_some());
+    }
+
     #[test]
     fn mark_sent() {
         let db = rusqlite::Connection::open_in_memory().unwrap();
@@ -558,6 +2436,8 @@ mod test {
             group_name: "test".to_owned(),
 
             sent: false,
+            owner: None,
+            owner_salt: None,
         };
 
         let nge = NewsGroup {
@@ -568,6 +2448,8 @@ mod test {
             group_name: "test".to_owned(),
 
             sent: false,
+            owner: None,
+            owner_salt: None,
         };
 
         let ngp = NewsGroup {
@@ -578,6 +2460,8 @@ mod test {
             group_name: "test".to_owned(),
 
             sent: false,
+            owner: None,
+            owner_salt: None,
         };
 
         ng.insert(&db).unwrap();
@@ -595,13 +2479,30 @@ mod test {
 
         let sent = db.get_unsent_groups().unwrap();
         assert_eq!(sent.len(), 0);
+
+        // retractions of one post by different signers are marked apart
+        let post_id = Uuid::new_v4();
+        let tombstones = [Uuid::new_v4(), Uuid::new_v4()].map(|identity| Tombstone {
+            post_id,
+            identity,
+            retract_date: chrono::Utc::now().naive_utc(),
+            sig: None,
+            verification: SigStatus::Valid,
+            sent: false,
+        });
+        for tombstone in &tombstones {
+            tombstone.insert(&db).unwrap();
+        }
+        db.mark_sent_tombstone(&post_id, &tombstones[0].identity)
+            .unwrap();
+        let unsent = db.get_unsent_tombstones().unwrap();
+        assert_eq!(unsent.len(), 1);
+        assert_eq!(unsent[0].identity, tombstones[1].identity);
     }
 
     #[test]
     fn select_parents() {
-        let db = rusqlite::Connection::open_in_memory().unwrap();
-        let db = SubrosaDb::from_conn(db);
-        run_migrations(&db).unwrap();
+        let db = test_db();
 
         let ng = NewsGroup {
             uuid: Uuid::new_v4(),
@@ -611,6 +2512,8 @@ mod test {
             group_name: "test".to_owned(),
 
             sent: false,
+            owner: None,
+            owner_salt: None,
         };
 
         let nge = NewsGroup {
@@ -621,6 +2524,8 @@ mod test {
             group_name: "test".to_owned(),
 
             sent: false,
+            owner: None,
+            owner_salt: None,
         };
 
         let ngp = NewsGroup {
@@ -631,6 +2536,8 @@ mod test {
             group_name: "test".to_owned(),
 
             sent: false,
+            owner: None,
+            owner_salt: None,
         };
 
         ng.insert(&db).unwrap();
@@ -644,9 +2551,7 @@ mod test {
 
     #[tokio::test]
     async fn neg_watcher() {
-        let db = rusqlite::Connection::open_in_memory().unwrap();
-        let db = SubrosaDb::from_conn(db);
-        run_migrations(&db).unwrap();
+        let db = test_db();
 
         let uuid = Uuid::new_v4();
 
@@ -673,9 +2578,7 @@ mod test {
 
     #[tokio::test]
     async fn watcher_drop() {
-        let db = rusqlite::Connection::open_in_memory().unwrap();
-        let db = SubrosaDb::from_conn(db);
-        run_migrations(&db).unwrap();
+        let db = test_db();
 
         let uuid = Uuid::new_v4();
         let ng = NewsGroup {
@@ -686,6 +2589,8 @@ mod test {
             group_name: "test".to_owned(),
 
             sent: false,
+            owner: None,
+            owner_salt: None,
         };
 
         let w = db.get_watcher();
@@ -713,9 +2618,7 @@ mod test {
 
     #[tokio::test]
     async fn watcher() {
-        let db = rusqlite::Connection::open_in_memory().unwrap();
-        let db = SubrosaDb::from_conn(db);
-        run_migrations(&db).unwrap();
+        let db = test_db();
 
         let uuid = Uuid::new_v4();
         let ng = NewsGroup {
@@ -726,6 +2629,8 @@ mod test {
             group_name: "test".to_owned(),
 
             sent: false,
+            owner: None,
+            owner_salt: None,
         };
 
         let w = db.get_watcher();
@@ -749,11 +2654,48 @@ mod test {
         //assert!(*t.lock().unwrap());
     }
 
+    #[tokio::test]
+    async fn watcher_reads_committed() {
+        let path = std::env::temp_dir().join(format!("subrosa-{}.db", Uuid::new_v4()));
+        let db = SubrosaDb::new(path.to_str().unwrap()).unwrap();
+        run_migrations(&db).unwrap();
+
+        let uuid = Uuid::new_v4();
+        let ng = NewsGroup::new(uuid, "Test".to_owned(), None, "test".to_owned(), false);
+
+        let w = db.get_watcher();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        w.watch("newsgroup".to_owned(), move |c| {
+            let test = tx.clone();
+            async move {
+                test.send(c.get_group(uuid).unwrap().is_some()).unwrap();
+            }
+            .boxed()
+        });
+        // the initial call made by watch
+        assert!(!rx.recv().await.unwrap());
+
+        // keep the transaction open long enough for an early callback to read
+        db.transaction(|tx| {
+            ng.insert(tx)?;
+            std::thread::sleep(Duration::from_millis(100));
+            Ok(())
+        })
+        .unwrap();
+        // readers use their own connections, so the callback must only run
+        // once the group is committed
+        assert!(rx.recv().await.unwrap());
+
+        drop(w);
+        drop(db);
+        for ext in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), ext));
+        }
+    }
+
     #[test]
     fn post_proto() {
-        let db = rusqlite::Connection::open_in_memory().unwrap();
-        let db = SubrosaDb::from_conn(db);
-        run_migrations(&db).unwrap();
+        let db = test_db();
         let old = NewsGroup {
             uuid: Uuid::new_v4(),
             description: "test".to_owned(),
@@ -761,6 +2703,8 @@ mod test {
             parent: None,
             group_name: "test".to_owned(),
             sent: false,
+            owner: None,
+            owner_salt: None,
         };
 
         let id = old.uuid;
@@ -781,6 +2725,8 @@ mod test {
             parent: None,
             group_name: "test".to_owned(),
             sent: false,
+            owner: None,
+            owner_salt: None,
         };
 
         let p = old.to_proto();
@@ -790,9 +2736,7 @@ mod test {
 
     #[test]
     fn delete_generated() {
-        let db = rusqlite::Connection::open_in_memory().unwrap();
-        let db = SubrosaDb::from_conn(db);
-        run_migrations(&db).unwrap();
+        let db = test_db();
 
         let ng = NewsGroup {
             uuid: Uuid::new_v4(),
@@ -802,6 +2746,8 @@ mod test {
             group_name: "test".to_owned(),
 
             sent: false,
+            owner: None,
+            owner_salt: None,
         };
 
         ng.insert(&db).unwrap();
@@ -811,9 +2757,7 @@ mod test {
 
     #[test]
     fn update_generated() {
-        let db = rusqlite::Connection::open_in_memory().unwrap();
-        let db = SubrosaDb::from_conn(db);
-        run_migrations(&db).unwrap();
+        let db = test_db();
 
         let mut ng = NewsGroup {
             uuid: Uuid::new_v4(),
@@ -822,6 +2766,8 @@ mod test {
             parent: None,
             group_name: "test".to_owned(),
             sent: false,
+            owner: None,
+            owner_salt: None,
         };
 
         ng.insert(&db).unwrap();
@@ -833,9 +2779,7 @@ mod test {
 
     #[test]
     fn dao_select() {
-        let db = rusqlite::Connection::open_in_memory().unwrap();
-        let db = SubrosaDb::from_conn(db);
-        run_migrations(&db).unwrap();
+        let db = test_db();
 
         let uuid = Uuid::new_v4();
 
@@ -846,10 +2790,611 @@ mod test {
             parent: None,
             group_name: "test".to_owned(),
             sent: false,
+            owner: None,
+            owner_salt: None,
         };
 
         ng.insert(&db).unwrap();
 
         db.test(&uuid).unwrap();
     }
+
+    #[test]
+    fn paginate_posts() {
+        let db = test_db();
+
+        let group = test_group(&db);
+        let start = chrono::Utc::now().naive_utc();
+        for i in 0..25 {
+            let mut post = Posts::new(format!("{}", i), "body".to_owned(), &group);
+            // pairs of posts share a receive date so post_id breaks the tie
+            post.receive_date = start + chrono::Duration::seconds(i / 2);
+            post.insert(&db).unwrap();
+        }
+        Posts::new("other".to_owned(), "body".to_owned(), &test_group(&db))
+            .insert(&db)
+            .unwrap();
+
+        let mut pages = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = db.get_posts_page(&group, cursor, 10).unwrap();
+            pages.push(page.items.len());
+            let Some(next) = page.next else {
+                break;
+            };
+            cursor = Some(next);
+        }
+        assert_eq!(pages, vec![10, 10, 5]);
+
+        let mut streamed = Vec::new();
+        db.stream_posts(&group, |post| {
+            streamed.push(post);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(streamed.len(), 25);
+
+        let mut paged = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = db.get_posts_with_identity_page(&group, cursor, 7).unwrap();
+            paged.extend(page.items.into_iter().map(|v| (v.receive_date, v.post_id)));
+            match page.next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        let mut expected = streamed
+            .iter()
+            .map(|v| (v.receive_date, v.post_id))
+            .collect::<Vec<_>>();
+        expected.sort_by(|a, b| b.cmp(a));
+        assert_eq!(paged, expected);
+
+        assert!(db
+            .get_posts_page(&group, Some("not a cursor".to_owned()), 10)
+            .is_err());
+        assert!(matches!(
+            db.get_posts_page(&group, None, 0),
+            Err(crate::error::SubrosaErr::InvalidPageLimit)
+        ));
+
+        // pages after the first are a range scan of the keyset index
+        let plan = db
+            .writer()
+            .prepare(
+                "EXPLAIN QUERY PLAN SELECT * FROM (SELECT * FROM posts WHERE parent_group = ?1)
+                WHERE (receive_date, post_id) < (?2, ?3) ORDER BY receive_date DESC, post_id DESC",
+            )
+            .unwrap()
+            .query_map(rusqlite::params![group, start, group], |row| {
+                row.get::<_, String>("detail")
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        assert!(
+            plan.iter()
+                .any(|v| v.contains("index_posts_parent_group_receive_date")
+                    && v.contains("(receive_date,post_id)<")),
+            "{:?}",
+            plan
+        );
+    }
+
+    #[tokio::test]
+    async fn dao_async() {
+        let db = test_db();
+
+        let parent = NewsGroup::new(
+            Uuid::new_v4(),
+            "parent".to_owned(),
+            None,
+            "parent".to_owned(),
+            false,
+        );
+        let child = NewsGroup::new(
+            Uuid::new_v4(),
+            "child".to_owned(),
+            Some(parent.as_parent()),
+            "child".to_owned(),
+            false,
+        );
+        parent.insert(&db).unwrap();
+        child.insert(&db).unwrap();
+
+        let found = db.test_async(&child.uuid).await.unwrap().unwrap();
+        assert_eq!(found.group_name, "child");
+        assert!(db.test_async(&Uuid::new_v4()).await.unwrap().is_none());
+
+        let children = db.test_both_async(parent.uuid).await.unwrap();
+        assert_eq!(children.len(), 1);
+        assert_eq!(db.test_both(parent.uuid).unwrap().len(), 1);
+
+        let roots = db.get_root_groups_async().await.unwrap();
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].uuid, parent.uuid);
+    }
+
+    #[test]
+    fn sign_verify() {
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        let mut post = Posts::new("test".to_owned(), "body".to_owned(), &Uuid::new_v4());
+        post.identity = Some(Uuid::new_v4());
+
+        post.sign(&key.to_keypair_bytes(), &[]).unwrap();
+        assert_eq!(
+            post.verify(key.verifying_key().as_bytes(), &[]),
+            SigStatus::Valid
+        );
+
+        post.body = Some("forged".to_owned());
+        assert_eq!(
+            post.verify(key.verifying_key().as_bytes(), &[]),
+            SigStatus::Invalid
+        );
+
+        // a missing field doesn't sign the same as an empty one
+        post.body = Some("".to_owned());
+        post.sign(&key.to_keypair_bytes(), &[]).unwrap();
+        post.body = None;
+        assert_eq!(
+            post.verify(key.verifying_key().as_bytes(), &[]),
+            SigStatus::Invalid
+        );
+        post.body = Some("".to_owned());
+        post.reply_to = Some(Uuid::new_v4());
+        assert_eq!(
+            post.verify(key.verifying_key().as_bytes(), &[]),
+            SigStatus::Invalid
+        );
+    }
+
+    #[test]
+    fn reply_thread() {
+        let db = test_db();
+
+        let group = test_group(&db);
+        let root = Posts::new("root".to_owned(), "".to_owned(), &group);
+        let first = Posts::new_reply("first".to_owned(), "".to_owned(), None, &root);
+        let nested = Posts::new_reply("nested".to_owned(), "".to_owned(), None, &first);
+        let second = Posts::new_reply("second".to_owned(), "".to_owned(), None, &root);
+
+        for post in [&root, &first, &nested, &second] {
+            post.insert(&db).unwrap();
+        }
+
+        let thread = db.get_thread(&root.post_id).unwrap();
+        let order = thread
+            .iter()
+            .map(|v| (v.post_id, v.depth))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            vec![
+                (root.post_id, 0),
+                (first.post_id, 1),
+                (nested.post_id, 2),
+                (second.post_id, 1)
+            ]
+        );
+        assert_eq!(thread[0].reply_count, 2);
+
+        assert_eq!(db.get_replies(&root.post_id).unwrap().len(), 2);
+        assert_eq!(db.get_reply_count(&first.post_id).unwrap(), 1);
+
+        let roots = db.get_thread_roots(&group).unwrap();
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].post_id, root.post_id);
+
+        // .1 sorts after .123 as text
+        let date = chrono::NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .and_hms_milli_opt(0, 0, 5, 100)
+            .unwrap();
+        let replies = [0, 23].map(|ms| {
+            let mut reply = Posts::new_reply("".to_owned(), "".to_owned(), None, &second);
+            reply.receive_date = date + chrono::Duration::milliseconds(ms);
+            reply.insert(&db).unwrap();
+            reply.post_id
+        });
+        let thread = db.get_thread(&second.post_id).unwrap();
+        let order = thread.iter().skip(1).map(|v| v.post_id).collect::<Vec<_>>();
+        assert_eq!(order, replies);
+    }
+
+    #[test]
+    fn search_posts() {
+        let db = test_db();
+
+        let parent = NewsGroup::new(Uuid::new_v4(), "".to_owned(), None, "a".to_owned(), false);
+        let child = NewsGroup::new(
+            Uuid::new_v4(),
+            "".to_owned(),
+            Some(parent.as_parent()),
+            "b".to_owned(),
+            false,
+        );
+        db.insert_group(&parent).unwrap();
+        db.insert_group(&child).unwrap();
+        let other = test_group(&db);
+
+        let title = Posts::new(
+            "Rust runtimes".to_owned(),
+            "comparing async executors".to_owned(),
+            &child.uuid,
+        );
+        let mention = Posts::new(
+            "gardening".to_owned(),
+            "my rust coloured tomatoes".to_owned(),
+            &other,
+        );
+        let mut unrelated = Posts::new("hello".to_owned(), "world".to_owned(), &parent.uuid);
+        for post in [&title, &mention, &unrelated] {
+            post.insert(&db).unwrap();
+        }
+
+        // header matches rank above body matches
+        let results = db.search("rust".to_owned(), None, 10).unwrap();
+        let ids = results.iter().map(|v| v.post_id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![title.post_id, mention.post_id]);
+        assert_eq!(
+            results[0].header_snippet.as_deref(),
+            Some("<mark>Rust</mark> runtimes")
+        );
+        assert!(results[1]
+            .body_snippet
+            .as_deref()
+            .unwrap()
+            .contains("<mark>rust</mark>"));
+
+        let results = db.search("rust".to_owned(), Some(parent.uuid), 10).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].post_id, title.post_id);
+
+        // the last word is a prefix and operators are searched literally
+        assert_eq!(
+            db.search("async exec".to_owned(), None, 10).unwrap().len(),
+            1
+        );
+        assert!(db
+            .search("rust OR \"".to_owned(), None, 10)
+            .unwrap()
+            .is_empty());
+        assert!(db.search("  ".to_owned(), None, 10).unwrap().is_empty());
+
+        // the index follows updates and deletes
+        unrelated.body = Some("rust again".to_owned());
+        unrelated.update(&db).unwrap();
+        db.delete_post(title.post_id).unwrap();
+        let results = db.search("rust".to_owned(), None, 10).unwrap();
+        let ids = results.iter().map(|v| v.post_id).collect::<Vec<_>>();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(&unrelated.post_id) && !ids.contains(&title.post_id));
+    }
+
+    #[test]
+    fn read_state() {
+        let db = test_db();
+
+        let parent = NewsGroup::new(Uuid::new_v4(), "".to_owned(), None, "a".to_owned(), false);
+        let child = NewsGroup::new(
+            Uuid::new_v4(),
+            "".to_owned(),
+            Some(parent.as_parent()),
+            "b".to_owned(),
+            false,
+        );
+        db.insert_group(&parent).unwrap();
+        db.insert_group(&child).unwrap();
+
+        let remote = |header: &str, group: &Uuid, reply_to: Option<Uuid>| {
+            let mut post = Posts::new(header.to_owned(), "".to_owned(), group);
+            post.sent = true;
+            post.reply_to = reply_to;
+            post.insert(&db).unwrap();
+            post
+        };
+        let root = remote("root", &child.uuid, None);
+        let reply = remote("reply", &child.uuid, Some(root.post_id));
+        let other = remote("other", &child.uuid, None);
+        let top = remote("top", &parent.uuid, None);
+        // our own posts are never unread, even when received from another device
+        let me = Uuid::new_v4();
+        db.set_public_key(&me, true, &vec![1]).unwrap();
+        for sent in [false, true] {
+            let mut mine = Posts::new("mine".to_owned(), "".to_owned(), &child.uuid);
+            mine.identity = Some(me);
+            mine.sent = sent;
+            mine.insert(&db).unwrap();
+        }
+
+        let counts = |db: &SubrosaDb| {
+            let counts = db.get_unread_counts().unwrap();
+            assert_eq!(counts.len(), 2);
+            let get = |group: &Uuid| {
+                let count = counts.iter().find(|v| &v.group_id == group).unwrap();
+                (count.unread, count.unread_total)
+            };
+            (get(&parent.uuid), get(&child.uuid))
+        };
+        assert_eq!(counts(&db), ((1, 4), (3, 3)));
+
+        db.mark_post_read(&other.post_id).unwrap();
+        db.mark_post_read(&other.post_id).unwrap();
+        assert_eq!(counts(&db), ((1, 3), (2, 2)));
+
+        db.mark_thread_read(&root.post_id).unwrap();
+        assert_eq!(counts(&db), ((1, 1), (0, 0)));
+        assert!(db.get_unread_posts(&child.uuid).unwrap().is_empty());
+
+        db.mark_thread_unread(&root.post_id).unwrap();
+        let unread = db.get_unread_posts(&child.uuid).unwrap();
+        assert_eq!(unread.len(), 2);
+        assert!(unread.iter().all(|v| v.post_id != other.post_id));
+
+        db.mark_group_read(&parent.uuid).unwrap();
+        assert_eq!(counts(&db), ((0, 0), (0, 0)));
+
+        db.mark_group_unread(&child.uuid).unwrap();
+        assert_eq!(counts(&db), ((0, 5), (5, 5)));
+
+        db.mark_post_unread(&top.post_id).unwrap();
+        db.mark_post_read(&reply.post_id).unwrap();
+        assert_eq!(counts(&db), ((1, 5), (4, 4)));
+
+        // read state goes away with the post
+        db.delete_post(reply.post_id).unwrap();
+        let read: i64 = db
+            .connection()
+            .query_row("SELECT COUNT(*) FROM read_post", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(read, 0);
+    }
+
+    #[test]
+    fn map_columns_by_name() {
+        let db = test_db();
+
+        let group = NewsGroup::new(
+            Uuid::new_v4(),
+            "description".to_owned(),
+            None,
+            "name".to_owned(),
+            false,
+        );
+        group.insert(&db).unwrap();
+
+        let groups = db.test_reordered().unwrap();
+        assert_eq!(groups[0].uuid, group.uuid);
+        assert_eq!(groups[0].group_name, "name");
+        assert_eq!(groups[0].description, "description");
+
+        let err = db.test_missing_column().unwrap_err().to_string();
+        assert!(
+            err.contains("parent_hash") && err.contains("NewsGroup"),
+            "{}",
+            err
+        );
+
+        let err = db.test_unknown_column().unwrap_err().to_string();
+        assert!(
+            err.contains("extra") && err.contains("NewsGroup"),
+            "{}",
+            err
+        );
+
+        let identity = Uuid::new_v4();
+        db.update_profile(identity, "author".to_owned(), "".to_owned(), vec![])
+            .unwrap();
+        let mut post = Posts::new("header".to_owned(), "body".to_owned(), &group.uuid);
+        post.identity = Some(identity);
+        post.insert(&db).unwrap();
+
+        let posts = db.get_posts_with_identity(&group.uuid).unwrap();
+        assert_eq!(posts[0].header.as_deref(), Some("header"));
+        assert_eq!(posts[0].author.as_deref(), Some("author"));
+        assert_eq!(posts[0].fingerprint, Some(identity));
+    }
+
+    #[derive(macros::FromRow)]
+    #[table("newsgroup")]
+    struct RenamedGroup {
+        #[primary]
+        #[column("uuid")]
+        id: Uuid,
+        #[column("group_name")]
+        name: String,
+        description: String,
+        parent_hash: Option<Vec<u8>>,
+        parent: Option<Uuid>,
+        #[default(true)]
+        sent: bool,
+        #[skip]
+        children: Vec<Uuid>,
+        #[skip]
+        #[default(7)]
+        computed: i64,
+    }
+
+    #[test]
+    fn column_attributes() {
+        use super::FromRow;
+
+        let db = test_db();
+
+        let mut group = RenamedGroup {
+            id: Uuid::new_v4(),
+            name: "renamed".to_owned(),
+            description: "".to_owned(),
+            parent_hash: None,
+            parent: None,
+            sent: false,
+            children: vec![Uuid::new_v4()],
+            computed: 0,
+        };
+        group.insert(&db).unwrap();
+        group.name = "updated".to_owned();
+        group.update(&db).unwrap();
+
+        let stored = db.get_group(group.id).unwrap().unwrap();
+        assert_eq!(stored.group_name, "updated");
+        assert!(!stored.sent);
+
+        let read = db
+            .connection()
+            .query_row(
+                "SELECT uuid, group_name, description, parent_hash, parent FROM newsgroup",
+                [],
+                |row| Ok(RenamedGroup::from_row(row)?),
+            )
+            .unwrap();
+        assert_eq!(read.id, group.id);
+        assert_eq!(read.name, "updated");
+        assert!(read.sent);
+        assert!(read.children.is_empty());
+        assert_eq!(read.computed, 7);
+
+        group.delete(&db).unwrap();
+        assert!(db.get_group(stored.uuid).unwrap().is_none());
+    }
+
+    /// Storage class SQLite gives values written to a column of the declared type
+    fn affinity(decl: &str) -> &'static str {
+        let decl = decl.to_uppercase();
+        if decl.contains("INT") {
+            "INTEGER"
+        } else if ["CHAR", "CLOB", "TEXT"].iter().any(|v| decl.contains(v)) {
+            "TEXT"
+        } else if decl.contains("BLOB") || decl.is_empty() {
+            "BLOB"
+        } else if ["REAL", "FLOA", "DOUB"].iter().any(|v| decl.contains(v)) {
+            "REAL"
+        } else {
+            // numeric affinity stores the integers and bools we write as integers
+            "INTEGER"
+        }
+    }
+
+    /// Differences between an entity and its table in `db`
+    fn schema_drift<T: Table>(db: &SubrosaDb) -> Vec<String> {
+        let conn = db.connection();
+        let mut st = conn
+            .prepare("SELECT name, type, \"notnull\", pk FROM pragma_table_info(?1)")
+            .unwrap();
+        let table = st
+            .query_map([T::TABLE], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, bool>(2)?,
+                    row.get::<_, i64>(3)? > 0,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        if table.is_empty() {
+            return vec![format!("{}: table missing", T::TABLE)];
+        }
+
+        let columns = T::columns();
+        let mut drift = Vec::new();
+        for column in columns.iter() {
+            let name = format!("{}.{}", T::TABLE, column.name);
+            let Some((_, decl, notnull, primary)) =
+                table.iter().find(|v| v.0.eq_ignore_ascii_case(column.name))
+            else {
+                drift.push(format!("{}: column missing", name));
+                continue;
+            };
+
+            if affinity(decl) != affinity(column.sql_type) {
+                drift.push(format!(
+                    "{}: declared {} for a {} field",
+                    name, decl, column.sql_type
+                ));
+            }
+            if *notnull == column.nullable {
+                drift.push(format!(
+                    "{}: {} but the field is {}",
+                    name,
+                    if *notnull { "NOT NULL" } else { "nullable" },
+                    if column.nullable {
+                        "an Option"
+                    } else {
+                        "required"
+                    }
+                ));
+            }
+            if *primary != column.primary {
+                drift.push(format!("{}: primary key differs", name));
+            }
+        }
+        for (column, ..) in table.iter() {
+            if !columns.iter().any(|v| v.name.eq_ignore_ascii_case(column)) {
+                drift.push(format!("{}.{}: column not in entity", T::TABLE, column));
+            }
+        }
+        drift
+    }
+
+    type TableCheck = (fn() -> String, fn(&SubrosaDb) -> Vec<String>);
+
+    fn table<T: Table>() -> TableCheck {
+        (T::create_table, schema_drift::<T>)
+    }
+
+    /// Every entity backed by a table
+    fn tables() -> Vec<TableCheck> {
+        vec![
+            table::<NewsGroup>(),
+            table::<PendingNewsGroup>(),
+            table::<Posts>(),
+            table::<CachedIdentity>(),
+            table::<IdentityKey>(),
+            table::<Attachment>(),
+            table::<AttachmentData>(),
+            table::<UnknownMessage>(),
+            table::<GroupKey>(),
+            table::<KeyGrant>(),
+            table::<SealedPost>(),
+            table::<PostEdit>(),
+            table::<Tombstone>(),
+            table::<PendingPost>(),
+            table::<ForwardMessage>(),
+            table::<ForwardPost>(),
+            table::<ForwardAttachment>(),
+            table::<PendingAttachmentData>(),
+            table::<PendingProfile>(),
+        ]
+    }
+
+    fn all_schema_drift(db: &SubrosaDb) -> Vec<String> {
+        tables().iter().flat_map(|(_, drift)| drift(db)).collect()
+    }
+
+    #[test]
+    fn no_schema_drift() {
+        let db = test_db();
+
+        let drift = all_schema_drift(&db);
+        assert!(drift.is_empty(), "schema drift:\n{}", drift.join("\n"));
+    }
+
+    #[test]
+    fn generated_tables() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        let db = SubrosaDb::from_conn(db);
+
+        let ddl = tables()
+            .iter()
+            .map(|(create, _)| create())
+            .collect::<Vec<_>>();
+        db.connection().execute_batch(&ddl.join("\n")).unwrap();
+
+        let drift = all_schema_drift(&db);
+        assert!(drift.is_empty(), "schema drift:\n{}", drift.join("\n"));
+    }
 }
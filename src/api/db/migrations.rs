@@ -1,70 +1,349 @@
 use lazy_static::lazy_static;
-use rusqlite_migration::{Migrations, M};
+use rusqlite_migration::{Migrations, SchemaVersion, M};
 
-use crate::error::Result;
+use crate::error::{Result, SubrosaErr};
 
 use super::connection::SubrosaDb;
 
 lazy_static! {
-    static ref MIGRATIONS: Migrations<'static> = Migrations::new(vec![M::up(
-        r#"CREATE TABLE IF NOT EXISTS `newsgroup` (
-            `uuid` TEXT NOT NULL,
-            `description` TEXT NOT NULL DEFAULT '',
-            `parent_hash` BLOB,
-            `parent` TEXT, `group_name`
-            TEXT NOT NULL,
-            `sent` BOOLEAN NOT NULL DEFAULT 'false',
-            PRIMARY KEY(`uuid`)
-            );
-            CREATE INDEX IF NOT EXISTS `index_newsgroup_parent` ON `newsgroup` (`parent`);
-            CREATE TABLE IF NOT EXISTS `posts` (
-                `header` TEXT,
-                `body` TEXT,
-                `sig` BLOB,
-                `receive_date`
-                INTEGER NOT NULL DEFAULT 0,
-                `post_id` TEXT NOT NULL,
-                `identity` TEXT,
-                `parent_group` TEXT NOT NULL,
-                `sent` BOOLEAN NOT NULL DEFAULT 'false',
-                PRIMARY KEY(`post_id`)
-                );
-
-                CREATE TABLE IF NOT EXISTS `identity` (
-                `uuid` TEXT NOT NULL,
-                `fingerprint` TEXT,
-                `user_name` TEXT,
-                `bio` TEXT,
-                `owned` INTEGER,
-                `image_bytes` BLOB,
-                PRIMARY KEY(`uuid`)
-                );
-
-                CREATE TABLE IF NOT EXISTS `User` (
-                    `identity` TEXT NOT NULL,
-                    `user_name` TEXT NOT NULL,
-                    `bio` TEXT NOT NULL,
-                    `owned` INTEGER NOT NULL,
-                    `image_bytes` BLOB, PRIMARY KEY(`identity`)
-                );
-        "#,
-    )]);
+    static ref MIGRATIONS: Migrations<'static> = Migrations::new(vec![
+        M::up(include_str!("migrations/01_initial.sql")),
+        M::up(include_str!("migrations/02_post_signatures.sql")),
+        M::up(include_str!("migrations/03_reply_to.sql")),
+        M::up(include_str!("migrations/04_pending_newsgroup.sql")),
+        M::up(include_str!("migrations/05_attachments.sql")),
+        M::up(include_str!("migrations/06_unknown_message.sql")),
+        M::up(include_str!("migrations/07_post_edits.sql")),
+        M::up(include_str!("migrations/08_private_groups.sql")),
+        M::up(include_str!("migrations/09_user_profile_version.sql")),
+        M::up(include_str!("migrations/10_posts_keyset_index.sql")),
+        M::up(include_str!("migrations/11_schema_drift.sql")),
+        M::up(include_str!("migrations/12_identity_foreign_keys.sql")).foreign_key_check(),
+        M::up(include_str!("migrations/13_posts_search.sql")),
+        M::up(include_str!("migrations/14_identity_sent_default.sql")),
+        M::up(include_str!("migrations/15_retention.sql")),
+        M::up(include_str!("migrations/16_read_state.sql")),
+        M::up(include_str!("migrations/17_subscriptions.sql")),
+        M::up(include_str!("migrations/18_pending_attachment_data.sql")),
+        M::up(include_str!("migrations/19_tombstone_per_signer.sql")),
+        M::up(include_str!("migrations/20_private_group_owner.sql")),
+        M::up(include_str!("migrations/21_pending_profile.sql")),
+        M::up(include_str!("migrations/22_pruned_post.sql")),
+    ]);
 }
 
 pub fn run_migrations(conn: &SubrosaDb) -> Result<()> {
-    let mut conn = conn.0.conn.lock().unwrap();
+    let mut conn = conn.writer();
+    // a newer release may have changed the schema in ways we can't read
+    if let SchemaVersion::Outside(version) = MIGRATIONS.current_version(&conn)? {
+        return Err(SubrosaErr::SchemaTooNew(version.get()));
+    }
     conn.pragma_update_and_check(None, "journal_mode", &"WAL", |_| Ok(()))?;
-    MIGRATIONS.to_latest(&mut conn)?;
+    // tables are rebuilt to add foreign keys, which sqlite only allows with
+    // enforcement off. It can't be toggled inside the migration transaction.
+    conn.pragma_update(None, "foreign_keys", false)?;
+    let migrated = MIGRATIONS.to_latest(&mut conn);
+    conn.pragma_update(None, "foreign_keys", true)?;
+    migrated?;
+
+    // pruning hands free pages back to the filesystem with incremental vacuum,
+    // which an existing database only switches to with a full vacuum
+    if conn.pragma_query_value(None, "auto_vacuum", |row| row.get::<_, i64>(0))? != 2 {
+        conn.pragma_update(None, "auto_vacuum", "INCREMENTAL")?;
+        conn.execute_batch("VACUUM")?;
+        // vacuum may renumber the posts rowids the search index refers to
+        conn.execute_batch("INSERT INTO posts_fts (posts_fts) VALUES ('rebuild')")?;
+    }
 
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::MIGRATIONS;
+    use rusqlite::{Connection, ToSql};
+    use uuid::Uuid;
+
+    use crate::{
+        api::db::{
+            connection::{Crud, OnConflict, SubrosaDb},
+            entities::{CachedIdentity, GetParams, NewsGroup, Posts, SubrosaDao, User},
+        },
+        error::SubrosaErr,
+    };
+
+    use super::{run_migrations, MIGRATIONS};
 
     #[test]
     fn check_migrations() {
         MIGRATIONS.validate().unwrap()
     }
+
+    fn latest_version() -> usize {
+        let db = SubrosaDb::from_conn(Connection::open_in_memory().unwrap());
+        run_migrations(&db).unwrap();
+        let version = db
+            .connection()
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        version
+    }
+
+    fn is_table(conn: &Connection, name: &str) -> bool {
+        conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [name],
+            |row| row.get::<_, i64>(0),
+        )
+        .unwrap()
+            > 0
+    }
+
+    /// Inserts the fields of `entity` that `table` has columns for at the
+    /// connection's schema version
+    fn insert_at_version(conn: &Connection, table: &str, entity: &impl GetParams) {
+        let columns = conn
+            .prepare("SELECT name FROM pragma_table_info(?1)")
+            .unwrap()
+            .query_map([table], |row| row.get::<_, String>(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        let params = entity
+            .get_params()
+            .into_iter()
+            .filter(|(name, _)| columns.iter().any(|v| v.eq_ignore_ascii_case(&name[1..])))
+            .collect::<Vec<(&str, &dyn ToSql)>>();
+
+        let names = params.iter().map(|v| &v.0[1..]).collect::<Vec<_>>();
+        let values = params.iter().map(|v| v.0).collect::<Vec<_>>();
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            table,
+            names.join(", "),
+            values.join(", ")
+        );
+        conn.execute(&sql, params.as_slice()).unwrap();
+    }
+
+    /// Data written by a release at some earlier schema version
+    struct Fixture {
+        root: NewsGroup,
+        child: NewsGroup,
+        post: Posts,
+        reply: Posts,
+        /// Written by the owned identity
+        own: Posts,
+        /// Only written before foreign keys were added
+        orphan: Option<Posts>,
+        owner: Uuid,
+        peer: Uuid,
+    }
+
+    impl Fixture {
+        fn populate(conn: &Connection) -> Fixture {
+            let root = NewsGroup::new(
+                Uuid::new_v4(),
+                "root group".to_owned(),
+                None,
+                "root".to_owned(),
+                true,
+            );
+            let child = NewsGroup::new(
+                Uuid::new_v4(),
+                "".to_owned(),
+                Some(root.as_parent()),
+                "child".to_owned(),
+                false,
+            );
+            let (owner, peer) = (Uuid::new_v4(), Uuid::new_v4());
+
+            let mut post = Posts::new("hello".to_owned(), "upgraded body".to_owned(), &child.uuid);
+            post.identity = Some(peer);
+            post.sig = Some(vec![1; 64]);
+            let reply = Posts::new_reply("re".to_owned(), "reply".to_owned(), None, &post);
+
+            insert_at_version(conn, "newsgroup", &root);
+            insert_at_version(conn, "newsgroup", &child);
+            insert_at_version(conn, "posts", &post);
+            insert_at_version(conn, "posts", &reply);
+
+            let orphan = (!conn
+                .query_row(
+                    "SELECT EXISTS (SELECT 1 FROM pragma_foreign_key_list('posts'))",
+                    [],
+                    |row| row.get::<_, bool>(0),
+                )
+                .unwrap())
+            .then(|| {
+                let orphan = Posts::new("lost".to_owned(), "".to_owned(), &Uuid::new_v4());
+                insert_at_version(conn, "posts", &orphan);
+                orphan
+            });
+
+            insert_at_version(
+                conn,
+                "identity",
+                &CachedIdentity {
+                    uuid: peer,
+                    fingerprint: Some(peer),
+                    user_name: Some("peer".to_owned()),
+                    bio: Some("".to_owned()),
+                    owned: Some(false),
+                    image_bytes: None,
+                    public_key: Some(vec![7; 32]),
+                    version: 1,
+                    sent: true,
+                },
+            );
+            if is_table(conn, "User") {
+                insert_at_version(
+                    conn,
+                    "User",
+                    &User {
+                        identity: owner,
+                        user_name: "me".to_owned(),
+                        bio: "bio".to_owned(),
+                        owned: true,
+                        image_bytes: vec![1, 2],
+                        version: 2,
+                        sent: true,
+                    },
+                );
+            } else {
+                insert_at_version(
+                    conn,
+                    "identity",
+                    &CachedIdentity {
+                        uuid: owner,
+                        fingerprint: Some(owner),
+                        user_name: Some("me".to_owned()),
+                        bio: Some("bio".to_owned()),
+                        owned: Some(true),
+                        image_bytes: Some(vec![1, 2]),
+                        public_key: None,
+                        version: 2,
+                        sent: true,
+                    },
+                );
+            }
+
+            let mut own = Posts::new("mine".to_owned(), "".to_owned(), &child.uuid);
+            own.identity = Some(owner);
+            own.sent = true;
+            insert_at_version(conn, "posts", &own);
+
+            Fixture {
+                root,
+                child,
+                post,
+                reply,
+                own,
+                orphan,
+                owner,
+                peer,
+            }
+        }
+
+        fn check(&self, db: &SubrosaDb, from: usize) {
+            for group in [&self.root, &self.child] {
+                let stored = db.get_group(group.uuid).unwrap().unwrap();
+                assert_eq!(stored.description, group.description, "from v{}", from);
+                assert_eq!(stored.group_name, group.group_name, "from v{}", from);
+                assert_eq!(stored.parent, group.parent, "from v{}", from);
+                assert_eq!(stored.parent_hash, group.parent_hash, "from v{}", from);
+                assert_eq!(stored.sent, group.sent, "from v{}", from);
+            }
+            let children = db.get_groups_for_parent(&self.root.uuid).unwrap();
+            assert_eq!(children.len(), 1, "from v{}", from);
+
+            let stored = db.get_post(&self.post.post_id).unwrap().unwrap();
+            assert_eq!(stored.header, self.post.header, "from v{}", from);
+            assert_eq!(stored.body, self.post.body, "from v{}", from);
+            assert_eq!(stored.sig, self.post.sig, "from v{}", from);
+            assert_eq!(
+                stored.receive_date, self.post.receive_date,
+                "from v{}",
+                from
+            );
+            assert_eq!(stored.identity, self.post.identity, "from v{}", from);
+            assert_eq!(stored.parent_group, self.child.uuid, "from v{}", from);
+            assert!(db.get_post(&self.reply.post_id).unwrap().is_some());
+            assert_eq!(db.get_total_posts(&self.root.uuid).unwrap(), 3);
+
+            // posts by our own identities start out read
+            let unread = db.get_unread_posts(&self.child.uuid).unwrap();
+            assert_eq!(unread.len(), 2, "from v{}", from);
+            assert!(unread.iter().all(|v| v.post_id != self.own.post_id));
+
+            if let Some(ref orphan) = self.orphan {
+                assert!(db.get_post(&orphan.post_id).unwrap().is_none());
+                let pending = db.get_pending_posts(&orphan.parent_group).unwrap();
+                assert_eq!(pending.len(), 1, "from v{}", from);
+            }
+
+            let posts = db.get_posts_with_identity(&self.child.uuid).unwrap();
+            let post = posts
+                .iter()
+                .find(|v| v.post_id == self.post.post_id)
+                .unwrap();
+            assert_eq!(post.author.as_deref(), Some("peer"), "from v{}", from);
+
+            let user = db.get_user(self.owner).unwrap().unwrap();
+            assert_eq!(user.user_name, "me", "from v{}", from);
+            assert_eq!(user.bio, "bio", "from v{}", from);
+            assert_eq!(user.image_bytes, vec![1, 2], "from v{}", from);
+            assert!(user.owned, "from v{}", from);
+            let peer = db.get_cached_identity(&self.peer).unwrap().unwrap();
+            assert_eq!(peer.user_name.as_deref(), Some("peer"), "from v{}", from);
+
+            let results = db.search("upgraded".to_owned(), None, 10).unwrap();
+            assert_eq!(results.len(), 1, "from v{}", from);
+
+            // the upgraded database is usable through the dao
+            let group = NewsGroup::new(
+                Uuid::new_v4(),
+                "".to_owned(),
+                Some(self.child.as_parent()),
+                "new".to_owned(),
+                false,
+            );
+            db.insert_group(&group).unwrap();
+            let post = Posts::new("new".to_owned(), "post".to_owned(), &group.uuid);
+            post.insert(db).unwrap();
+            self.child
+                .clone()
+                .insert_on_conflict(db, OnConflict::Update)
+                .unwrap();
+            assert_eq!(db.get_total_posts(&self.root.uuid).unwrap(), 4);
+            db.delete_group(self.child.uuid).unwrap();
+            assert_eq!(db.get_total_posts(&self.root.uuid).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn upgrade_from_every_version() {
+        let latest = latest_version();
+        for version in 1..=latest {
+            let mut conn = Connection::open_in_memory().unwrap();
+            rusqlite::vtab::array::load_module(&conn).unwrap();
+            MIGRATIONS.to_version(&mut conn, version).unwrap();
+            let fixture = Fixture::populate(&conn);
+
+            let db = SubrosaDb::from_conn(conn);
+            run_migrations(&db).unwrap();
+            fixture.check(&db, version);
+        }
+    }
+
+    #[test]
+    fn refuse_newer_schema() {
+        let latest = latest_version();
+        let conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", latest + 1)
+            .unwrap();
+        let db = SubrosaDb::from_conn(conn);
+
+        let err = run_migrations(&db).unwrap_err();
+        assert!(matches!(err, SubrosaErr::SchemaTooNew(v) if v == latest + 1));
+    }
 }
@@ -0,0 +1,8 @@
+ALTER TABLE `posts` ADD COLUMN `verification` INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE `identity` ADD COLUMN `public_key` BLOB;
+
+CREATE TABLE IF NOT EXISTS `signing_key` (
+    `identity` TEXT NOT NULL,
+    `secret_key` BLOB NOT NULL,
+    PRIMARY KEY(`identity`)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE `posts` ADD COLUMN `reply_to` TEXT;
+CREATE INDEX IF NOT EXISTS `index_posts_reply_to` ON `posts` (`reply_to`);
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS `pending_newsgroup` (
+    `uuid` TEXT NOT NULL,
+    `description` TEXT NOT NULL DEFAULT '',
+    `parent_hash` BLOB,
+    `parent` TEXT,
+    `group_name` TEXT NOT NULL,
+    `sent` BOOLEAN NOT NULL DEFAULT 0,
+    PRIMARY KEY(`uuid`)
+);
+CREATE INDEX IF NOT EXISTS `index_pending_newsgroup_parent` ON `pending_newsgroup` (`parent`);
@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS `attachments` (
+    `attachment_id` TEXT NOT NULL,
+    `post_id` TEXT NOT NULL,
+    `hash` BLOB NOT NULL,
+    `mime` TEXT NOT NULL,
+    `file_name` TEXT NOT NULL,
+    `size` INTEGER NOT NULL,
+    `sent` BOOLEAN NOT NULL DEFAULT 0,
+    PRIMARY KEY(`attachment_id`)
+);
+CREATE INDEX IF NOT EXISTS `index_attachments_post_id` ON `attachments` (`post_id`);
+
+CREATE TABLE IF NOT EXISTS `attachment_data` (
+    `hash` BLOB NOT NULL,
+    `body` BLOB NOT NULL,
+    PRIMARY KEY(`hash`)
+);
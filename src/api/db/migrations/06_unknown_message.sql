@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS `unknown_message` (
+    `hash` BLOB NOT NULL,
+    `post_type` INTEGER NOT NULL,
+    `version` INTEGER NOT NULL,
+    `body` BLOB NOT NULL,
+    `sent` BOOLEAN NOT NULL DEFAULT 0,
+    PRIMARY KEY(`hash`)
+);
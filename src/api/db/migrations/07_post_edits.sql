@@ -0,0 +1,23 @@
+CREATE TABLE IF NOT EXISTS `post_edit` (
+    `edit_id` TEXT NOT NULL,
+    `post_id` TEXT NOT NULL,
+    `identity` TEXT NOT NULL,
+    `header` TEXT NOT NULL,
+    `body` TEXT NOT NULL,
+    `edit_date` TEXT NOT NULL,
+    `sig` BLOB,
+    `verification` INTEGER NOT NULL DEFAULT 0,
+    `sent` BOOLEAN NOT NULL DEFAULT 0,
+    PRIMARY KEY(`edit_id`)
+);
+CREATE INDEX IF NOT EXISTS `index_post_edit_post_id` ON `post_edit` (`post_id`);
+
+CREATE TABLE IF NOT EXISTS `tombstone` (
+    `post_id` TEXT NOT NULL,
+    `identity` TEXT NOT NULL,
+    `retract_date` TEXT NOT NULL,
+    `sig` BLOB,
+    `verification` INTEGER NOT NULL DEFAULT 0,
+    `sent` BOOLEAN NOT NULL DEFAULT 0,
+    PRIMARY KEY(`post_id`)
+);
@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS `group_key` (
+    `group_id` TEXT NOT NULL,
+    `key` BLOB NOT NULL,
+    PRIMARY KEY(`group_id`)
+);
+
+CREATE TABLE IF NOT EXISTS `key_grant` (
+    `grant_id` TEXT NOT NULL,
+    `group_id` TEXT NOT NULL,
+    `recipient` TEXT NOT NULL,
+    `ephemeral_key` BLOB NOT NULL,
+    `nonce` BLOB NOT NULL,
+    `ciphertext` BLOB NOT NULL,
+    `sent` BOOLEAN NOT NULL DEFAULT 0,
+    PRIMARY KEY(`grant_id`)
+);
+
+CREATE TABLE IF NOT EXISTS `sealed_post` (
+    `post_id` TEXT NOT NULL,
+    `parent_group` TEXT NOT NULL,
+    `body` BLOB NOT NULL,
+    PRIMARY KEY(`post_id`)
+);
+CREATE INDEX IF NOT EXISTS `index_sealed_post_parent_group` ON `sealed_post` (`parent_group`);
@@ -0,0 +1,3 @@
+ALTER TABLE `User` ADD COLUMN `version` INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE `User` ADD COLUMN `sent` BOOLEAN NOT NULL DEFAULT 'false';
+ALTER TABLE `identity` ADD COLUMN `version` INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+CREATE INDEX IF NOT EXISTS `index_posts_parent_group_receive_date`
+    ON `posts` (`parent_group`, `receive_date`, `post_id`);
@@ -0,0 +1,40 @@
+-- receive_date has always held chrono's TEXT timestamps
+CREATE TABLE `posts_new` (
+    `header` TEXT,
+    `body` TEXT,
+    `sig` BLOB,
+    `receive_date` TEXT NOT NULL,
+    `post_id` TEXT NOT NULL,
+    `identity` TEXT,
+    `parent_group` TEXT NOT NULL,
+    `sent` BOOLEAN NOT NULL DEFAULT 'false',
+    `verification` INTEGER NOT NULL DEFAULT 0,
+    `reply_to` TEXT,
+    PRIMARY KEY(`post_id`)
+);
+INSERT INTO `posts_new` (`header`, `body`, `sig`, `receive_date`, `post_id`, `identity`,
+    `parent_group`, `sent`, `verification`, `reply_to`)
+SELECT `header`, `body`, `sig`, `receive_date`, `post_id`, `identity`,
+    `parent_group`, `sent`, `verification`, `reply_to` FROM `posts`;
+DROP TABLE `posts`;
+ALTER TABLE `posts_new` RENAME TO `posts`;
+CREATE INDEX IF NOT EXISTS `index_posts_reply_to` ON `posts` (`reply_to`);
+CREATE INDEX IF NOT EXISTS `index_posts_parent_group_receive_date`
+    ON `posts` (`parent_group`, `receive_date`, `post_id`);
+
+-- User.image_bytes is not optional, an empty blob means no image
+CREATE TABLE `User_new` (
+    `identity` TEXT NOT NULL,
+    `user_name` TEXT NOT NULL,
+    `bio` TEXT NOT NULL,
+    `owned` INTEGER NOT NULL,
+    `image_bytes` BLOB NOT NULL DEFAULT X'',
+    `version` INTEGER NOT NULL DEFAULT 0,
+    `sent` BOOLEAN NOT NULL DEFAULT 'false',
+    PRIMARY KEY(`identity`)
+);
+INSERT INTO `User_new` (`identity`, `user_name`, `bio`, `owned`, `image_bytes`, `version`, `sent`)
+SELECT `identity`, `user_name`, `bio`, `owned`, COALESCE(`image_bytes`, X''), `version`, `sent`
+FROM `User`;
+DROP TABLE `User`;
+ALTER TABLE `User_new` RENAME TO `User`;
@@ -0,0 +1,102 @@
+-- profiles of owned identities move from `User` into `identity`, which also
+-- caches the profiles of peers. Profiles saved before `User.sent` existed got
+-- its text default 'false', which can't be read as a bool.
+ALTER TABLE `identity` ADD COLUMN `sent` BOOLEAN NOT NULL DEFAULT 0;
+
+INSERT INTO `identity` (`uuid`, `fingerprint`, `user_name`, `bio`, `owned`, `image_bytes`,
+    `version`, `sent`)
+SELECT `identity`, `identity`, `user_name`, `bio`, `owned`, `image_bytes`, `version`,
+    CASE WHEN `sent` = 'false' THEN 0 ELSE `sent` END
+FROM `User` WHERE true
+ON CONFLICT(`uuid`) DO UPDATE SET
+    `user_name` = excluded.`user_name`,
+    `bio` = excluded.`bio`,
+    `image_bytes` = excluded.`image_bytes`,
+    `version` = excluded.`version`,
+    `sent` = excluded.`sent`
+WHERE excluded.`version` >= `identity`.`version` OR `identity`.`user_name` IS NULL;
+
+UPDATE `identity` SET `owned` = 1
+WHERE `uuid` IN (SELECT `identity` FROM `User` WHERE `owned`);
+
+DROP TABLE `User`;
+
+-- identities with a profile, in the shape of the old `User` table
+CREATE VIEW `User` AS
+SELECT `uuid` AS `identity`, `user_name`, COALESCE(`bio`, '') AS `bio`,
+    COALESCE(`owned`, 0) AS `owned`, COALESCE(`image_bytes`, X'') AS `image_bytes`,
+    `version`, `sent`
+FROM `identity` WHERE `user_name` IS NOT NULL;
+
+-- posts received before their newsgroup, held until it arrives
+CREATE TABLE IF NOT EXISTS `pending_post` (
+    `header` TEXT,
+    `body` TEXT,
+    `sig` BLOB,
+    `receive_date` TEXT NOT NULL,
+    `post_id` TEXT NOT NULL,
+    `identity` TEXT,
+    `parent_group` TEXT NOT NULL,
+    `sent` BOOLEAN NOT NULL DEFAULT 0,
+    `verification` INTEGER NOT NULL DEFAULT 0,
+    `reply_to` TEXT,
+    PRIMARY KEY(`post_id`)
+);
+CREATE INDEX IF NOT EXISTS `index_pending_post_parent_group` ON `pending_post` (`parent_group`);
+
+-- groups whose ancestry is missing go back to pending along with their posts
+WITH RECURSIVE `orphan`(`uuid`) AS (
+    SELECT `uuid` FROM `newsgroup`
+    WHERE `parent` IS NOT NULL AND `parent` NOT IN (SELECT `uuid` FROM `newsgroup`)
+    UNION
+    SELECT `newsgroup`.`uuid` FROM `newsgroup` JOIN `orphan` ON `newsgroup`.`parent` = `orphan`.`uuid`
+)
+INSERT OR IGNORE INTO `pending_newsgroup`
+SELECT * FROM `newsgroup` WHERE `uuid` IN (SELECT `uuid` FROM `orphan`);
+DELETE FROM `newsgroup` WHERE `uuid` IN (SELECT `uuid` FROM `pending_newsgroup`);
+
+INSERT OR IGNORE INTO `pending_post` (`header`, `body`, `sig`, `receive_date`, `post_id`,
+    `identity`, `parent_group`, `sent`, `verification`, `reply_to`)
+SELECT `header`, `body`, `sig`, `receive_date`, `post_id`, `identity`, `parent_group`, `sent`,
+    `verification`, `reply_to`
+FROM `posts` WHERE `parent_group` NOT IN (SELECT `uuid` FROM `newsgroup`);
+DELETE FROM `posts` WHERE `parent_group` NOT IN (SELECT `uuid` FROM `newsgroup`);
+
+-- deleting a group deletes its subgroups and posts
+CREATE TABLE `newsgroup_new` (
+    `uuid` TEXT NOT NULL,
+    `description` TEXT NOT NULL DEFAULT '',
+    `parent_hash` BLOB,
+    `parent` TEXT REFERENCES `newsgroup` (`uuid`) ON DELETE CASCADE,
+    `group_name` TEXT NOT NULL,
+    `sent` BOOLEAN NOT NULL DEFAULT 'false',
+    PRIMARY KEY(`uuid`)
+);
+INSERT INTO `newsgroup_new` (`uuid`, `description`, `parent_hash`, `parent`, `group_name`, `sent`)
+SELECT `uuid`, `description`, `parent_hash`, `parent`, `group_name`, `sent` FROM `newsgroup`;
+DROP TABLE `newsgroup`;
+ALTER TABLE `newsgroup_new` RENAME TO `newsgroup`;
+CREATE INDEX IF NOT EXISTS `index_newsgroup_parent` ON `newsgroup` (`parent`);
+
+CREATE TABLE `posts_new` (
+    `header` TEXT,
+    `body` TEXT,
+    `sig` BLOB,
+    `receive_date` TEXT NOT NULL,
+    `post_id` TEXT NOT NULL,
+    `identity` TEXT,
+    `parent_group` TEXT NOT NULL REFERENCES `newsgroup` (`uuid`) ON DELETE CASCADE,
+    `sent` BOOLEAN NOT NULL DEFAULT 'false',
+    `verification` INTEGER NOT NULL DEFAULT 0,
+    `reply_to` TEXT,
+    PRIMARY KEY(`post_id`)
+);
+INSERT INTO `posts_new` (`header`, `body`, `sig`, `receive_date`, `post_id`, `identity`,
+    `parent_group`, `sent`, `verification`, `reply_to`)
+SELECT `header`, `body`, `sig`, `receive_date`, `post_id`, `identity`,
+    `parent_group`, `sent`, `verification`, `reply_to` FROM `posts`;
+DROP TABLE `posts`;
+ALTER TABLE `posts_new` RENAME TO `posts`;
+CREATE INDEX IF NOT EXISTS `index_posts_reply_to` ON `posts` (`reply_to`);
+CREATE INDEX IF NOT EXISTS `index_posts_parent_group_receive_date`
+    ON `posts` (`parent_group`, `receive_date`, `post_id`);
@@ -0,0 +1,26 @@
+-- full text index over posts, kept in sync by triggers. It refers to posts by
+-- rowid, so it must be rebuilt if posts is ever rebuilt or vacuumed.
+CREATE VIRTUAL TABLE IF NOT EXISTS `posts_fts` USING fts5(
+    `header`,
+    `body`,
+    content = 'posts',
+    content_rowid = 'rowid',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+CREATE TRIGGER IF NOT EXISTS `posts_fts_insert` AFTER INSERT ON `posts` BEGIN
+    INSERT INTO `posts_fts` (`rowid`, `header`, `body`) VALUES (new.`rowid`, new.`header`, new.`body`);
+END;
+
+CREATE TRIGGER IF NOT EXISTS `posts_fts_delete` AFTER DELETE ON `posts` BEGIN
+    INSERT INTO `posts_fts` (`posts_fts`, `rowid`, `header`, `body`)
+    VALUES ('delete', old.`rowid`, old.`header`, old.`body`);
+END;
+
+CREATE TRIGGER IF NOT EXISTS `posts_fts_update` AFTER UPDATE OF `header`, `body` ON `posts` BEGIN
+    INSERT INTO `posts_fts` (`posts_fts`, `rowid`, `header`, `body`)
+    VALUES ('delete', old.`rowid`, old.`header`, old.`body`);
+    INSERT INTO `posts_fts` (`rowid`, `header`, `body`) VALUES (new.`rowid`, new.`header`, new.`body`);
+END;
+
+INSERT INTO `posts_fts` (`posts_fts`) VALUES ('rebuild');
@@ -0,0 +1,4 @@
+-- profiles saved before `User.sent` existed got its text default 'false', which
+-- was carried into `identity` by earlier builds of migration 12 and can't be
+-- read as a bool
+UPDATE `identity` SET `sent` = 0 WHERE `sent` = 'false';
@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS `starred_post` (
+    `post_id` TEXT NOT NULL REFERENCES `posts` (`post_id`) ON DELETE CASCADE,
+    PRIMARY KEY(`post_id`)
+);
+
+CREATE INDEX IF NOT EXISTS `index_posts_receive_date` ON `posts` (`receive_date`);
+
+-- posts retention may remove: already sent, not written by one of our
+-- identities, not starred and without replies, so threads are pruned from
+-- the leaves and never lose a post that is replied to
+CREATE VIEW IF NOT EXISTS `prunable_post` AS
+SELECT `posts`.* FROM `posts`
+WHERE `sent` = 1
+    AND NOT EXISTS (
+        SELECT 1 FROM `identity` WHERE `identity`.`uuid` = `posts`.`identity` AND `identity`.`owned` = 1
+    )
+    AND NOT EXISTS (SELECT 1 FROM `starred_post` WHERE `starred_post`.`post_id` = `posts`.`post_id`)
+    AND NOT EXISTS (SELECT 1 FROM `posts` AS `reply` WHERE `reply`.`reply_to` = `posts`.`post_id`);
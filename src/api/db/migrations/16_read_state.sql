@@ -0,0 +1,17 @@
+-- posts the user has read, local only and never synced
+CREATE TABLE IF NOT EXISTS `read_post` (
+    `post_id` TEXT NOT NULL REFERENCES `posts` (`post_id`) ON DELETE CASCADE,
+    PRIMARY KEY(`post_id`)
+);
+
+-- posts written by one of our identities start out read, including those
+-- that come back from a peer or another device
+CREATE TRIGGER IF NOT EXISTS `read_post_own` AFTER INSERT ON `posts`
+WHEN EXISTS (SELECT 1 FROM `identity` WHERE `identity`.`uuid` = new.`identity` AND `identity`.`owned` = 1)
+BEGIN
+    INSERT INTO `read_post` (`post_id`) VALUES (new.`post_id`) ON CONFLICT(`post_id`) DO NOTHING;
+END;
+
+INSERT INTO `read_post` (`post_id`)
+SELECT `post_id` FROM `posts`
+WHERE `identity` IN (SELECT `uuid` FROM `identity` WHERE `owned` = 1);
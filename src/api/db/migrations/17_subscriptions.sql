@@ -0,0 +1,28 @@
+-- groups the user subscribed to, each also covers its subgroups
+CREATE TABLE IF NOT EXISTS `subscription` (
+    `group_id` TEXT NOT NULL REFERENCES `newsgroup` (`uuid`) ON DELETE CASCADE,
+    PRIMARY KEY(`group_id`)
+);
+
+-- messages from unsubscribed groups passed on at the next sync without being
+-- stored, file messages keep the name and type they are sent with
+CREATE TABLE IF NOT EXISTS `forward_message` (
+    `hash` BLOB NOT NULL,
+    `body` BLOB NOT NULL,
+    `file_name` TEXT,
+    `mime` TEXT,
+    PRIMARY KEY(`hash`)
+);
+
+-- posts that were forwarded, so their edits and retractions can follow them
+CREATE TABLE IF NOT EXISTS `forward_post` (
+    `post_id` TEXT NOT NULL,
+    `group_id` TEXT NOT NULL,
+    PRIMARY KEY(`post_id`)
+);
+
+-- attachments of forwarded posts whose contents haven't arrived yet
+CREATE TABLE IF NOT EXISTS `forward_attachment` (
+    `hash` BLOB NOT NULL,
+    PRIMARY KEY(`hash`)
+);
@@ -0,0 +1,8 @@
+-- file messages no stored attachment refers to yet, moved to `attachment_data`
+-- once a post names them and trimmed to the newest otherwise
+CREATE TABLE IF NOT EXISTS `pending_attachment_data` (
+    `hash` BLOB NOT NULL,
+    `body` BLOB NOT NULL,
+    `receive_date` TEXT NOT NULL,
+    PRIMARY KEY(`hash`)
+);
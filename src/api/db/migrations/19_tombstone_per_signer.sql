@@ -0,0 +1,17 @@
+-- retractions are kept per signer, so one signed by someone other than the
+-- author can't take the place of the author's
+CREATE TABLE `tombstone_new` (
+    `post_id` TEXT NOT NULL,
+    `identity` TEXT NOT NULL,
+    `retract_date` TEXT NOT NULL,
+    `sig` BLOB,
+    `verification` INTEGER NOT NULL DEFAULT 0,
+    `sent` BOOLEAN NOT NULL DEFAULT 0,
+    PRIMARY KEY(`post_id`, `identity`)
+);
+
+INSERT INTO `tombstone_new` (`post_id`, `identity`, `retract_date`, `sig`, `verification`, `sent`)
+SELECT `post_id`, `identity`, `retract_date`, `sig`, `verification`, `sent` FROM `tombstone`;
+
+DROP TABLE `tombstone`;
+ALTER TABLE `tombstone_new` RENAME TO `tombstone`;
@@ -0,0 +1,11 @@
+-- private groups name the identity that signs their key grants, grants are
+-- only opened once that signature checks out. The group's uuid is derived
+-- from the owner and the salt, so no one else can claim to own it.
+ALTER TABLE `newsgroup` ADD COLUMN `owner` TEXT;
+ALTER TABLE `newsgroup` ADD COLUMN `owner_salt` BLOB;
+ALTER TABLE `pending_newsgroup` ADD COLUMN `owner` TEXT;
+ALTER TABLE `pending_newsgroup` ADD COLUMN `owner_salt` BLOB;
+ALTER TABLE `key_grant` ADD COLUMN `sig` BLOB;
+
+-- sealed posts are passed on to peers like any other post
+ALTER TABLE `sealed_post` ADD COLUMN `sent` BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,11 @@
+-- profiles are signed, those from peers whose key isn't known yet are held
+-- here until it is
+CREATE TABLE IF NOT EXISTS `pending_profile` (
+    `uuid` TEXT NOT NULL,
+    `version` INTEGER NOT NULL,
+    `user_name` TEXT NOT NULL,
+    `bio` TEXT NOT NULL,
+    `image_bytes` BLOB,
+    `sig` BLOB NOT NULL,
+    PRIMARY KEY(`uuid`, `version`)
+);
@@ -0,0 +1,6 @@
+-- posts removed by retention, so copies peers deliver again aren't stored anew
+-- with a fresh receive date
+CREATE TABLE IF NOT EXISTS `pruned_post` (
+    `post_id` TEXT NOT NULL,
+    PRIMARY KEY(`post_id`)
+);
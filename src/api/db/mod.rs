@@ -1,4 +1,8 @@
 pub mod connection;
 pub mod entities;
 pub mod migrations;
+pub mod retention;
+pub mod subscription;
 pub mod sync;
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,392 @@
+use std::sync::PoisonError;
+
+use flutter_rust_bridge::frb;
+use rusqlite::types::Value;
+use uuid::Uuid;
+
+use super::{
+    connection::{SubrosaDb, SubrosaTransaction},
+    entities::SubrosaDao,
+    sync::MAX_HELD_RECORDS,
+};
+
+/// Number of posts removed per statement while pruning
+const PRUNE_BATCH: u32 = 256;
+/// Posts removed between checks of the database size, small so a size budget
+/// isn't overshot by much
+const SIZE_PRUNE_BATCH: u32 = 16;
+
+/// Limits on the posts kept by [`SubrosaDb::prune`]. Posts not sent yet, posts
+/// written by our own identities, starred posts and posts with replies are
+/// never removed. No limits are set by default.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    /// Posts received longer ago than this are removed
+    pub max_age: Option<chrono::Duration>,
+    /// The oldest posts of a group beyond this many are removed
+    pub max_posts_per_group: Option<u32>,
+    /// The oldest posts are removed until the database uses about this many bytes
+    pub max_bytes: Option<u64>,
+}
+
+/// What [`SubrosaDb::prune`] removed
+#[derive(Debug, Clone, Default)]
+pub struct PruneReport {
+    pub posts: Vec<Uuid>,
+    pub attachments: u64,
+    /// Bytes returned to the filesystem
+    pub reclaimed_bytes: u64,
+}
+
+impl SubrosaDb {
+    /// Sets the limits applied by [`SubrosaDb::prune`], which also runs after
+    /// every sync
+    #[frb(sync)]
+    pub fn set_retention_policy(&self, policy: RetentionPolicy) {
+        *self
+            .0
+            .retention
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = policy;
+    }
+
+    #[frb(sync)]
+    pub fn retention_policy(&self) -> RetentionPolicy {
+        self.0
+            .retention
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Removes the oldest posts exceeding the retention policy along with their
+    /// attachments and edits, and the oldest records held beyond
+    /// [`MAX_HELD_RECORDS`], then returns the freed space to the filesystem
+    pub fn prune(&self) -> anyhow::Result<PruneReport> {
+        let policy = self.retention_policy();
+        let mut report = self.transaction(|tx| {
+            // held records may wait for something that never arrives, so they
+            // are bounded whatever the policy
+            tx.trim_held(MAX_HELD_RECORDS)?;
+            let mut report = PruneReport::default();
+            if let Some(max_age) = policy.max_age {
+                let before = chrono::Utc::now().naive_utc() - max_age;
+                while tx.remove_posts(tx.get_expired_posts(before, PRUNE_BATCH)?, &mut report)? {}
+            }
+            if let Some(max) = policy.max_posts_per_group {
+                while tx.remove_posts(tx.get_excess_posts(max, PRUNE_BATCH)?, &mut report)? {}
+            }
+            if let Some(max_bytes) = policy.max_bytes {
+                // deleted rows only count once their pages are empty, so this
+                // can remove somewhat more than needed
+                while tx.get_used_bytes()? as u64 > max_bytes
+                    && tx.remove_posts(
+                        tx.get_oldest_prunable_posts(SIZE_PRUNE_BATCH)?,
+                        &mut report,
+                    )?
+                {}
+            }
+            Ok(report)
+        })?;
+
+        let conn = self.writer();
+        let page_count = || conn.pragma_query_value(None, "page_count", |row| row.get::<_, u64>(0));
+        let before = page_count()?;
+        {
+            // the pragma frees pages as its rows are stepped through
+            let mut st = conn.prepare("PRAGMA incremental_vacuum")?;
+            let mut rows = st.query([])?;
+            while rows.next()?.is_some() {}
+        }
+        let page_size = conn.pragma_query_value(None, "page_size", |row| row.get::<_, u64>(0))?;
+        report.reclaimed_bytes = before.saturating_sub(page_count()?) * page_size;
+
+        log::debug!(
+            "pruned {} posts, {} attachments, reclaimed {} bytes",
+            report.posts.len(),
+            report.attachments,
+            report.reclaimed_bytes
+        );
+        Ok(report)
+    }
+}
+
+impl SubrosaTransaction<'_> {
+    /// Deletes the oldest records of each kind held beyond `max`
+    fn trim_held(&self, max: u32) -> anyhow::Result<()> {
+        let posts = self.get_excess_pending_posts(max)?;
+        let ids = posts.into_iter().map(Value::from).collect::<Vec<_>>();
+        // attachments are stored while their post is held
+        self.delete_attachment_data_of(ids.clone())?;
+        self.delete_attachments_of(ids.clone())?;
+        self.delete_pending_posts(ids)?;
+        self.trim_pending_groups(max)?;
+        self.trim_sealed_posts(max)?;
+        self.trim_pending_profiles(max)?;
+        Ok(())
+    }
+
+    /// Deletes `posts` with their attachments and edits, false if there were none
+    fn remove_posts(&self, posts: Vec<Uuid>, report: &mut PruneReport) -> anyhow::Result<bool> {
+        if posts.is_empty() {
+            return Ok(false);
+        }
+
+        let ids = posts.iter().map(|v| (*v).into()).collect::<Vec<Value>>();
+        report.attachments += self.count_attachments_of(ids.clone())? as u64;
+        // attachment contents can be shared, so only unreferenced ones go
+        self.delete_attachment_data_of(ids.clone())?;
+        self.delete_attachments_of(ids.clone())?;
+        self.delete_edits_of(ids.clone())?;
+        self.delete_posts(ids.clone())?;
+        // peers still carry these posts and would deliver them again
+        self.remember_pruned_posts(ids)?;
+        report.posts.extend(posts);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use crate::api::{
+        db::{
+            connection::Crud,
+            entities::{
+                Attachment, AttachmentData, NewsGroup, PendingNewsGroup, PendingPost,
+                PendingProfile, Posts, SealedPost, SubrosaDao,
+            },
+            testing::{deliver, test_db, test_group},
+        },
+        proto::ser::SubrosaMessage,
+    };
+
+    use super::RetentionPolicy;
+
+    #[test]
+    fn prune_posts() {
+        let db = test_db();
+
+        let group = test_group(&db);
+        let me = Uuid::new_v4();
+        db.set_public_key(&me, true, &vec![1]).unwrap();
+
+        let now = chrono::Utc::now().naive_utc();
+        let old = now - chrono::Duration::days(40);
+        let post = |age: chrono::NaiveDateTime, reply_to: Option<&Posts>| {
+            let mut post = match reply_to {
+                Some(parent) => Posts::new_reply("".to_owned(), "".to_owned(), None, parent),
+                None => Posts::new("".to_owned(), "".to_owned(), &group),
+            };
+            post.receive_date = age;
+            post.sent = true;
+            post.insert(&db).unwrap();
+            post
+        };
+
+        let expired = post(old, None);
+        let thread = post(old, None);
+        let old_reply = post(old, Some(&thread));
+        let replied = post(old, None);
+        let recent_reply = post(now, Some(&replied));
+        let starred = post(old, None);
+        db.star_post(&starred.post_id).unwrap();
+        let mut own = Posts::new("".to_owned(), "".to_owned(), &group);
+        own.receive_date = old;
+        own.sent = true;
+        own.identity = Some(me);
+        own.insert(&db).unwrap();
+        let mut unsent = Posts::new("".to_owned(), "".to_owned(), &group);
+        unsent.receive_date = old;
+        unsent.insert(&db).unwrap();
+        db.add_attachment(expired.post_id, "a".to_owned(), "".to_owned(), vec![1])
+            .unwrap();
+        let shared = db
+            .add_attachment(starred.post_id, "b".to_owned(), "".to_owned(), vec![2])
+            .unwrap();
+        db.add_attachment(thread.post_id, "c".to_owned(), "".to_owned(), vec![2])
+            .unwrap();
+
+        // nothing is pruned without a policy
+        assert!(db.prune().unwrap().posts.is_empty());
+
+        db.set_retention_policy(RetentionPolicy {
+            max_age: Some(chrono::Duration::days(30)),
+            ..Default::default()
+        });
+        let mut report = db.prune().unwrap();
+        report.posts.sort();
+        let mut removed = vec![expired.post_id, thread.post_id, old_reply.post_id];
+        removed.sort();
+        assert_eq!(report.posts, removed);
+        assert_eq!(report.attachments, 2);
+        for kept in [&replied, &recent_reply, &starred, &own, &unsent] {
+            assert!(db.get_post(&kept.post_id).unwrap().is_some());
+        }
+        assert!(db.get_attachment_data(&shared.hash).unwrap().is_some());
+        assert_eq!(db.get_starred_posts().unwrap().len(), 1);
+
+        // the oldest posts beyond the limit go, except the protected ones
+        let other = test_group(&db);
+        let posts = (0..5)
+            .map(|i| {
+                let mut post = Posts::new(format!("{}", i), "".to_owned(), &other);
+                post.receive_date = now - chrono::Duration::minutes(10 - i);
+                post.sent = true;
+                post.insert(&db).unwrap();
+                post
+            })
+            .collect::<Vec<_>>();
+        db.set_retention_policy(RetentionPolicy {
+            max_posts_per_group: Some(3),
+            ..Default::default()
+        });
+        let report = db.prune().unwrap();
+        assert_eq!(report.posts, vec![posts[0].post_id, posts[1].post_id]);
+        assert_eq!(db.get_total_posts(&other).unwrap(), 3);
+
+        // a size budget removes the oldest posts until it is met
+        let base = db.get_used_bytes().unwrap() as u64;
+        let big = (0..200)
+            .map(|i| {
+                let mut post = Posts::new(format!("{}", i), "x".repeat(4096), &other);
+                post.receive_date = now + chrono::Duration::seconds(i);
+                post.sent = true;
+                post.insert(&db).unwrap();
+                post
+            })
+            .collect::<Vec<_>>();
+        let budget = base + (db.get_used_bytes().unwrap() as u64 - base) / 2;
+        db.set_retention_policy(RetentionPolicy {
+            max_bytes: Some(budget),
+            ..Default::default()
+        });
+        let report = db.prune().unwrap();
+        assert!(db.get_used_bytes().unwrap() as u64 <= budget);
+        assert!(report.reclaimed_bytes > 0);
+        assert!(db.get_post(&big[199].post_id).unwrap().is_some());
+        assert!(db.get_post(&big[0].post_id).unwrap().is_none());
+        assert!(db.get_post(&starred.post_id).unwrap().is_some());
+        assert!(db.get_post(&own.post_id).unwrap().is_some());
+    }
+
+    #[test]
+    fn trim_held() {
+        let db = test_db();
+
+        // posts held for a group that never arrives
+        let group = Uuid::new_v4();
+        let now = chrono::Utc::now().naive_utc();
+        let held = (0..3)
+            .map(|i| {
+                let mut post = Posts::new(format!("{}", i), "".to_owned(), &group);
+                post.receive_date = now - chrono::Duration::minutes(10 - i);
+                PendingPost::from(post)
+            })
+            .collect::<Vec<_>>();
+        PendingPost::insert_many(&held, &db).unwrap();
+        let data = AttachmentData::new(vec![1]);
+        data.insert(&db).unwrap();
+        Attachment::new(&held[0].post_id, "a".to_owned(), "".to_owned(), &data)
+            .insert(&db)
+            .unwrap();
+
+        // groups held for a parent that never arrives
+        let parent = NewsGroup::new(Uuid::new_v4(), "".to_owned(), None, "a".to_owned(), false);
+        let groups = (0..3)
+            .map(|i| {
+                PendingNewsGroup::from(NewsGroup::new(
+                    Uuid::new_v4(),
+                    "".to_owned(),
+                    Some(parent.as_parent()),
+                    format!("{}", i),
+                    false,
+                ))
+            })
+            .collect::<Vec<_>>();
+        for group in &groups {
+            group.insert(&db).unwrap();
+        }
+
+        // posts sealed with a key we are never given
+        let sealed = (0..3)
+            .map(|i| SealedPost {
+                post_id: Uuid::new_v4(),
+                parent_group: group,
+                body: vec![i],
+                sent: false,
+            })
+            .collect::<Vec<_>>();
+        for post in &sealed {
+            post.insert(&db).unwrap();
+        }
+
+        // profiles of an identity whose key we never learn
+        let identity = Uuid::new_v4();
+        for version in 0..3 {
+            PendingProfile {
+                uuid: identity,
+                version,
+                user_name: "".to_owned(),
+                bio: "".to_owned(),
+                image_bytes: None,
+                sig: vec![],
+            }
+            .insert(&db)
+            .unwrap();
+        }
+
+        db.transaction(|tx| tx.trim_held(2)).unwrap();
+        let mut kept = db
+            .get_pending_posts(&group)
+            .unwrap()
+            .into_iter()
+            .map(|v| v.post_id)
+            .collect::<Vec<_>>();
+        kept.sort();
+        let mut newest = vec![held[1].post_id, held[2].post_id];
+        newest.sort();
+        assert_eq!(kept, newest);
+        assert!(db.get_attachments(&held[0].post_id).unwrap().is_empty());
+        assert!(db.get_attachment_data(&data.hash).unwrap().is_none());
+
+        let kept = db.get_pending_children(&parent.uuid).unwrap();
+        assert_eq!(kept.len(), 2);
+        assert!(kept.iter().all(|v| v.uuid != groups[0].uuid));
+
+        let kept = db.get_sealed_posts(&group).unwrap();
+        assert_eq!(kept.len(), 2);
+        assert!(kept.iter().all(|v| v.post_id != sealed[0].post_id));
+
+        let kept = db.get_pending_profiles(&identity).unwrap();
+        assert_eq!(
+            kept.iter().map(|v| v.version).collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+    }
+
+    #[test]
+    fn pruned_posts_stay_gone() {
+        let db = test_db();
+
+        let group = test_group(&db);
+        let mut post = Posts::new("".to_owned(), "".to_owned(), &group);
+        post.receive_date = chrono::Utc::now().naive_utc() - chrono::Duration::days(40);
+        post.sent = true;
+        post.insert(&db).unwrap();
+        let post_id = post.post_id;
+        let proto = post.to_proto(&db).unwrap();
+
+        db.set_retention_policy(RetentionPolicy {
+            max_age: Some(chrono::Duration::days(30)),
+            ..Default::default()
+        });
+        assert_eq!(db.prune().unwrap().posts, vec![post_id]);
+
+        // a peer delivering it again doesn't bring it back with a fresh age
+        deliver(&db, &SubrosaMessage::Post(proto));
+        assert!(db.get_post(&post_id).unwrap().is_none());
+        assert!(db.prune().unwrap().posts.is_empty());
+    }
+}
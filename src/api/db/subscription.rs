@@ -0,0 +1,261 @@
+use std::sync::PoisonError;
+
+use flutter_rust_bridge::frb;
+use uuid::Uuid;
+
+use super::{
+    connection::{SubrosaDb, SubrosaTransaction},
+    entities::{NewsGroup, SubrosaDao},
+};
+
+/// What happens to posts received for groups outside every subscription.
+/// Newsgroups themselves are always stored so they can be subscribed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnsubscribedPolicy {
+    /// Posts are stored like those of subscribed groups
+    #[default]
+    Store,
+    /// Posts are stored without their body or attachments
+    HeadersOnly,
+    /// Posts are passed on at the next sync without being stored
+    Forward,
+}
+
+impl SubrosaDb {
+    /// Sets how posts of unsubscribed groups are handled from now on, posts
+    /// already stored are kept
+    #[frb(sync)]
+    pub fn set_unsubscribed_policy(&self, policy: UnsubscribedPolicy) {
+        *self
+            .0
+            .unsubscribed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = policy;
+    }
+
+    #[frb(sync)]
+    pub fn unsubscribed_policy(&self) -> UnsubscribedPolicy {
+        *self
+            .0
+            .unsubscribed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl NewsGroup {
+    /// Subscribes to this group and all of its subgroups
+    pub fn subscribe(&self, db: &SubrosaDb) -> anyhow::Result<()> {
+        db.subscribe_group(&self.uuid)?;
+        Ok(())
+    }
+
+    /// Removes the subscription to this group. Its subgroups stay subscribed
+    /// through any other subscription, and so does the group itself if one of
+    /// its parents is subscribed.
+    pub fn unsubscribe(&self, db: &SubrosaDb) -> anyhow::Result<()> {
+        db.unsubscribe_group(&self.uuid)?;
+        Ok(())
+    }
+
+    /// Whether this group or one of its parents is subscribed
+    pub fn is_subscribed(&self, db: &SubrosaDb) -> anyhow::Result<bool> {
+        Ok(db.is_subscribed(&self.uuid)? != 0)
+    }
+}
+
+impl SubrosaTransaction<'_> {
+    /// How posts received for `group` are handled
+    pub(crate) fn group_policy(&self, group: &Uuid) -> anyhow::Result<UnsubscribedPolicy> {
+        if self.is_subscribed(group)? != 0 {
+            return Ok(UnsubscribedPolicy::Store);
+        }
+        Ok(self.unsubscribed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use crate::api::{
+        db::{
+            connection::Crud,
+            entities::{AttachmentData, NewsGroup, Posts, SubrosaDao},
+            testing::{deliver, deliver_raw, test_db},
+        },
+        proto::{
+            ser::{Message, SubrosaMessage},
+            APP_NAME,
+        },
+    };
+
+    use super::UnsubscribedPolicy;
+
+    #[test]
+    fn subscriptions() {
+        let sender = test_db();
+        let receiver = test_db();
+
+        let parent = NewsGroup::new(Uuid::new_v4(), "".to_owned(), None, "a".to_owned(), false);
+        let child = NewsGroup::new(
+            Uuid::new_v4(),
+            "".to_owned(),
+            Some(parent.as_parent()),
+            "b".to_owned(),
+            false,
+        );
+        let other = NewsGroup::new(Uuid::new_v4(), "".to_owned(), None, "c".to_owned(), false);
+        for group in [&parent, &child, &other] {
+            sender.insert_group(group).unwrap();
+            receiver.insert_group(group).unwrap();
+        }
+        parent.subscribe(&receiver).unwrap();
+        assert!(child.is_subscribed(&receiver).unwrap());
+        assert!(!other.is_subscribed(&receiver).unwrap());
+        let subscribed = receiver
+            .get_subscribed_groups()
+            .unwrap()
+            .into_iter()
+            .map(|v| v.uuid)
+            .collect::<Vec<_>>();
+        assert_eq!(subscribed, vec![parent.uuid, child.uuid]);
+
+        let send = |header: &str, group: &Uuid| {
+            let post = Posts::new(header.to_owned(), "body".to_owned(), group);
+            post.insert(&sender).unwrap();
+            let post_id = post.post_id;
+            let message = SubrosaMessage::Post(post.to_proto(&sender).unwrap())
+                .encode_to_vec()
+                .unwrap();
+            deliver_raw(&receiver, message.clone());
+            (post_id, message)
+        };
+
+        // posts of unsubscribed groups are stored by default
+        let (stored, _) = send("stored", &other.uuid);
+        let stored = receiver.get_post(&stored).unwrap().unwrap();
+        assert_eq!(stored.body.as_deref(), Some("body"));
+
+        receiver.set_unsubscribed_policy(UnsubscribedPolicy::HeadersOnly);
+        let (subscribed, _) = send("subscribed", &child.uuid);
+        let subscribed = receiver.get_post(&subscribed).unwrap().unwrap();
+        assert_eq!(subscribed.body.as_deref(), Some("body"));
+        let (headers, _) = send("headers", &other.uuid);
+        let headers = receiver.get_post(&headers).unwrap().unwrap();
+        assert_eq!(headers.header.as_deref(), Some("headers"));
+        assert_eq!(headers.body, None);
+
+        receiver.set_unsubscribed_policy(UnsubscribedPolicy::Forward);
+        let (forwarded, message) = send("forwarded", &other.uuid);
+        assert!(receiver.get_post(&forwarded).unwrap().is_none());
+        let forward = receiver.get_forward_messages().unwrap();
+        assert_eq!(forward.len(), 1);
+        assert_eq!(forward[0].body, message);
+
+        // unsubscribing the parent also drops its subgroups
+        parent.unsubscribe(&receiver).unwrap();
+        assert!(!child.is_subscribed(&receiver).unwrap());
+        assert!(receiver.get_subscribed_groups().unwrap().is_empty());
+        let (dropped, _) = send("dropped", &child.uuid);
+        assert!(receiver.get_post(&dropped).unwrap().is_none());
+        assert_eq!(receiver.get_forward_messages().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn policy_follows_edits_and_files() {
+        let sender = test_db();
+        let receiver = test_db();
+
+        let key = ed25519_dalek::SigningKey::from_bytes(&[5; 32]);
+        let author = Uuid::new_v4();
+        let group = NewsGroup::new(Uuid::new_v4(), "".to_owned(), None, "a".to_owned(), false);
+        sender.insert_group(&group).unwrap();
+        receiver.insert_group(&group).unwrap();
+        sender
+            .set_signing_key(author, key.to_bytes().to_vec())
+            .unwrap();
+        receiver
+            .set_public_key(&author, false, &key.verifying_key().to_bytes().to_vec())
+            .unwrap();
+
+        let post = |header: &str, attachment: Option<Vec<u8>>| {
+            let mut post = Posts::new(header.to_owned(), "body".to_owned(), &group.uuid);
+            post.identity = Some(author);
+            post.insert(&sender).unwrap();
+            if let Some(data) = attachment {
+                sender
+                    .add_attachment(
+                        post.post_id,
+                        "cat.png".to_owned(),
+                        "image/png".to_owned(),
+                        data,
+                    )
+                    .unwrap();
+            }
+            let post = sender.sign_post(post).unwrap();
+            (
+                post.post_id,
+                SubrosaMessage::Post(post.to_proto(&sender).unwrap()),
+            )
+        };
+        let edit = |post_id: Uuid| {
+            let edit = sender
+                .edit_post(post_id, "edited".to_owned(), "edited".to_owned())
+                .unwrap();
+            SubrosaMessage::Edit(edit.to_proto())
+        };
+
+        // edits keep only their headers like the posts they change
+        receiver.set_unsubscribed_policy(UnsubscribedPolicy::HeadersOnly);
+        let (headers, headers_post) = post("headers", None);
+        deliver(&receiver, &headers_post);
+        deliver(&receiver, &edit(headers));
+        let latest = receiver.get_latest_edit(&headers).unwrap().unwrap();
+        assert_eq!(latest.header, "edited");
+        assert_eq!(latest.body, "");
+
+        // under forward a bundled post is passed on as the bytes it arrived as,
+        // followed by its edits, contents and retraction
+        receiver.set_unsubscribed_policy(UnsubscribedPolicy::Forward);
+        let (forwarded, forwarded_post) = post("forwarded", Some(vec![1, 2, 3]));
+        let raw = forwarded_post.encode_to_vec().unwrap();
+        let forwarded_edit = edit(forwarded);
+        let edit_raw = forwarded_edit.encode_to_vec().unwrap();
+        deliver_raw(
+            &receiver,
+            SubrosaMessage::Bundle(vec![forwarded_post, forwarded_edit])
+                .encode_to_vec()
+                .unwrap(),
+        );
+        let mut file = Message::from_vec(vec![1, 2, 3], APP_NAME.to_owned());
+        file.is_file = true;
+        file.file_name = "cat.png".to_owned();
+        file.mime = "image/png".to_owned();
+        receiver.insert_message(&file).unwrap();
+        sender.retract_post(forwarded).unwrap();
+        let tombstone = sender.get_unsent_tombstones().unwrap().pop().unwrap();
+        let tombstone_raw = SubrosaMessage::Retract(tombstone.to_proto())
+            .encode_to_vec()
+            .unwrap();
+        deliver_raw(&receiver, tombstone_raw.clone());
+
+        assert!(receiver.get_post(&forwarded).unwrap().is_none());
+        assert!(receiver.get_post_history(&forwarded).unwrap().is_empty());
+        let hash = AttachmentData::new(vec![1, 2, 3]).hash;
+        assert!(receiver.get_attachment_data(&hash).unwrap().is_none());
+        assert!(receiver
+            .get_pending_attachment_data(&hash)
+            .unwrap()
+            .is_none());
+        let forward = receiver.get_forward_messages().unwrap();
+        let body = |body: &[u8]| forward.iter().find(|v| v.body == body).unwrap();
+        assert_eq!(forward.len(), 4);
+        assert_eq!(body(&raw).file_name, None);
+        assert_eq!(body(&edit_raw).file_name, None);
+        assert_eq!(body(&tombstone_raw).file_name, None);
+        let file = body(&[1, 2, 3]);
+        assert_eq!(file.file_name.as_deref(), Some("cat.png"));
+        assert_eq!(file.mime.as_deref(), Some("image/png"));
+    }
+}
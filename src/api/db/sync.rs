@@ -1,16 +1,48 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use prost::Message as _;
 use rusqlite::types::Value;
-use scatterbrain::types::{Message, SbSession};
+use scatterbrain::types::{Identity, Message, SbSession};
+use uuid::Uuid;
 
 use crate::{
     api::proto::{ser::SubrosaMessage, ToUuid, APP_NAME},
-    proto::post::AuthorOr,
+    error::SubrosaErr,
+    proto::{self, post::AuthorOr},
 };
 
 use super::{
-    connection::{Crud, OnConflict, SubrosaDb},
-    entities::{CachedIdentity, NewsGroup, Posts, SubrosaDao},
+    connection::{Crud, OnConflict, SubrosaDb, SubrosaTransaction},
+    entities::{
+        Attachment, AttachmentData, CachedIdentity, ForwardAttachment, ForwardMessage, ForwardPost,
+        KeyGrant, NewsGroup, PendingAttachmentData, PendingNewsGroup, PendingPost, PendingProfile,
+        PostEdit, Posts, SealedPost, SigStatus, SubrosaDao, Tombstone, UnknownMessage, User,
+    },
+    subscription::UnsubscribedPolicy,
 };
 
+/// Largest attachment accepted from a file message
+pub const MAX_ATTACHMENT_SIZE: usize = 16 * 1024 * 1024;
+
+/// Total size of file messages kept while no post refers to them
+pub const MAX_PENDING_ATTACHMENT_BYTES: usize = 64 * 1024 * 1024;
+
+/// Records of each kind held until something they depend on arrives, such as
+/// posts waiting for their group. Anyone can send records that never resolve,
+/// so [`SubrosaDb::prune`] drops the oldest beyond this many.
+pub const MAX_HELD_RECORDS: u32 = 10_000;
+
+/// Outcome of checking a newsgroup's parent hash chain against local groups
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ChainStatus {
+    Valid,
+    Pending,
+    Invalid,
+}
+
 pub fn conn_test(session: SbSession) {
     drop(session);
 }
@@ -19,66 +51,1592 @@ impl SubrosaDb {
     pub async fn sync(&self, sb_connection: &SbSession) -> anyhow::Result<()> {
         let sync_time = self.get_last_sync_date()?;
 
+        let identities = sb_connection.get_identity(None).await?;
+        self.cache_identities(&identities)?;
+
         let messages = sb_connection
             .get_messages_recieve_date(APP_NAME.to_owned(), None, sync_time, None)
             .await?;
 
         let unsent_posts = self.get_unsent_posts()?;
         let unsent_groups = self.get_unsent_groups()?;
+        let unsent_attachments = self.get_unsent_attachments()?;
+        let unknown_messages = self.get_unsent_unknown_messages()?;
+        let unsent_edits = self.get_unsent_edits()?;
+        let unsent_tombstones = self.get_unsent_tombstones()?;
+        let unsent_grants = self.get_unsent_key_grants()?;
+        let unsent_users = self.get_unsent_users()?;
+        let forward_messages = self.get_forward_messages()?;
+        let unsent_sealed = self.get_unsent_sealed_posts()?;
 
         let sent_groups: Vec<Value> = unsent_groups.iter().map(|v| v.uuid.into()).collect();
         let sent_posts: Vec<Value> = unsent_posts.iter().map(|v| v.post_id.into()).collect();
+        let sent_attachments: Vec<Value> = unsent_attachments
+            .iter()
+            .map(|v| v.attachment_id.into())
+            .collect();
+        let sent_unknown: Vec<Value> = unknown_messages
+            .iter()
+            .map(|v| v.hash.clone().into())
+            .collect();
+        let sent_edits: Vec<Value> = unsent_edits.iter().map(|v| v.edit_id.into()).collect();
+        let sent_tombstones: Vec<(Uuid, Uuid)> = unsent_tombstones
+            .iter()
+            .map(|v| (v.post_id, v.identity))
+            .collect();
+        let sent_grants: Vec<Value> = unsent_grants.iter().map(|v| v.grant_id.into()).collect();
+        let sent_users: Vec<Value> = unsent_users.iter().map(|v| v.identity.into()).collect();
+        let sent_sealed: Vec<Value> = unsent_sealed.iter().map(|v| v.post_id.into()).collect();
+        let forwarded: Vec<Value> = forward_messages
+            .iter()
+            .map(|v| v.hash.clone().into())
+            .collect();
 
+        // posts are signed by their author's scatterbrain identity, so only posts
+        // from the same author can share a bundle
+        let mut outgoing: BTreeMap<Option<Uuid>, Vec<SubrosaMessage>> = BTreeMap::new();
         for post in unsent_groups {
             log::debug!("sending group {:?}", post.parent);
-            let message = SubrosaMessage::Newsgroup(post.to_proto()).encode_to_vec()?;
-            let message = Message::from_vec(message, APP_NAME.to_owned());
-            sb_connection.send_messages(vec![message], None).await?;
+            outgoing
+                .entry(None)
+                .or_default()
+                .push(SubrosaMessage::Newsgroup(post.to_proto()));
         }
 
         for post in unsent_posts {
             log::debug!("sending posts {}", post.post_id);
 
-            let post = post.to_proto(self)?;
+            let post = self.sign_post(post)?.to_proto(self)?;
             let author = post.author_or.map(|v| match v {
                 AuthorOr::Author(v) => v.as_uuid(),
             });
-            let message = SubrosaMessage::Post(post).encode_to_vec()?;
-            let message = Message::from_vec(message, APP_NAME.to_owned());
-            sb_connection.send_messages(vec![message], author).await?;
+            outgoing
+                .entry(author)
+                .or_default()
+                .push(SubrosaMessage::Post(post));
+        }
+
+        // sealed posts we can't open are passed on as they were received
+        for sealed in unsent_sealed {
+            log::debug!("sending sealed post {}", sealed.post_id);
+            let post = proto::Post::decode(sealed.body.as_slice())?;
+            outgoing
+                .entry(None)
+                .or_default()
+                .push(SubrosaMessage::Post(post));
+        }
+
+        for edit in unsent_edits {
+            log::debug!("sending edit {} for {}", edit.edit_id, edit.post_id);
+            let key = self
+                .get_post(&edit.post_id)?
+                .map(|v| self.get_group_key(&v.parent_group))
+                .transpose()?
+                .flatten();
+            let proto = match key {
+                Some(key) => edit.to_sealed_proto(&key)?,
+                None => edit.to_proto(),
+            };
+            outgoing
+                .entry(Some(edit.identity))
+                .or_default()
+                .push(SubrosaMessage::Edit(proto));
+        }
+
+        for user in unsent_users {
+            log::debug!("sending profile {} v{}", user.identity, user.version);
+            outgoing
+                .entry(Some(user.identity))
+                .or_default()
+                .push(SubrosaMessage::User(self.sign_user(&user)?));
+        }
+
+        for grant in unsent_grants {
+            log::debug!("sending key for {} to {}", grant.group_id, grant.recipient);
+            outgoing
+                .entry(None)
+                .or_default()
+                .push(SubrosaMessage::GroupKey(grant.to_proto()));
+        }
+
+        for tombstone in unsent_tombstones {
+            log::debug!("sending retraction for {}", tombstone.post_id);
+            outgoing
+                .entry(Some(tombstone.identity))
+                .or_default()
+                .push(SubrosaMessage::Retract(tombstone.to_proto()));
         }
 
-        self.mark_sent_groups(sent_groups)?;
-        self.mark_sent_posts(sent_posts)?;
+        for (author, messages) in outgoing {
+            let messages = SubrosaMessage::bundle(messages, self.bundle_size())
+                .into_iter()
+                .map(|v| Ok(Message::from_vec(v.encode_to_vec()?, APP_NAME.to_owned())))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            sb_connection.send_messages(messages, author).await?;
+        }
+
+        for attachment in unsent_attachments {
+            let Some(data) = self.get_attachment_data(&attachment.hash)? else {
+                log::warn!("missing data for attachment {}", attachment.attachment_id);
+                continue;
+            };
+            log::debug!("sending attachment {}", attachment.attachment_id);
+
+            let mut message = Message::from_vec(data.body, APP_NAME.to_owned());
+            message.is_file = true;
+            message.extension = attachment.extension();
+            message.mime = attachment.mime;
+            message.file_name = attachment.file_name;
+            sb_connection.send_messages(vec![message], None).await?;
+        }
+
+        // forward messages from newer clients exactly as we received them
+        for unknown in unknown_messages {
+            let message = Message::from_vec(unknown.body, APP_NAME.to_owned());
+            sb_connection.send_messages(vec![message], None).await?;
+        }
+
+        // pass on messages from unsubscribed groups, which are dropped once sent
+        for forward in forward_messages {
+            let mut message = Message::from_vec(forward.body, APP_NAME.to_owned());
+            if let Some(file_name) = forward.file_name {
+                message.is_file = true;
+                message.extension = Path::new(&file_name)
+                    .extension()
+                    .map(|v| v.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                message.mime = forward.mime.unwrap_or_default();
+                message.file_name = file_name;
+            }
+            sb_connection.send_messages(vec![message], None).await?;
+        }
+
+        self.transaction(|tx| {
+            tx.mark_sent_groups(sent_groups)?;
+            tx.mark_sent_posts(sent_posts)?;
+            tx.mark_sent_attachments(sent_attachments)?;
+            tx.mark_sent_unknown_messages(sent_unknown)?;
+            tx.mark_sent_edits(sent_edits)?;
+            for (post_id, identity) in &sent_tombstones {
+                tx.mark_sent_tombstone(post_id, identity)?;
+            }
+            tx.mark_sent_key_grants(sent_grants)?;
+            tx.mark_sent_users(sent_users)?;
+            tx.mark_sent_sealed_posts(sent_sealed)?;
+            tx.delete_forward_messages(forwarded)?;
+            Ok(())
+        })?;
 
         self.process_scatter_messages(&messages)?;
+        // the sync itself succeeded, pruning is tried again next time
+        if let Err(err) = self.prune() {
+            log::warn!("pruning after sync failed: {:?}", err);
+        }
         Ok(())
     }
 
     pub fn insert_message(&self, message: &Message) -> anyhow::Result<()> {
-        if let Err(err) = match SubrosaMessage::parse(&message.body) {
-            Ok(SubrosaMessage::Post(post)) => {
-                Posts::from_proto(post)?.insert_on_conflict(self, OnConflict::Ignore)
+        self.transaction(|tx| tx.insert_message(message))
+    }
+
+    /// Stores received messages in a single transaction
+    pub fn process_scatter_messages(&self, messages: &[Message]) -> anyhow::Result<()> {
+        self.transaction(|tx| {
+            for message in messages {
+                tx.insert_message(message)?;
             }
-            Ok(SubrosaMessage::Newsgroup(news)) => {
-                NewsGroup::from_proto(news)?.insert_on_conflict(self, OnConflict::Ignore)
+            Ok(())
+        })
+    }
+
+    /// Stores public keys from scatterbrain identities and checks any posts,
+    /// edits and retractions that arrived before their author's key was known
+    pub fn cache_identities(&self, identities: &[Identity]) -> anyhow::Result<()> {
+        self.transaction(|tx| tx.cache_identities(identities))
+    }
+
+    /// Signs a post from an owned identity if we hold its key, storing the signature locally.
+    /// The post is signed again each time so attachments added since are covered.
+    pub(crate) fn sign_post(&self, mut post: Posts) -> anyhow::Result<Posts> {
+        if let Some(key) = post
+            .identity
+            .map(|v| self.get_signing_key(&v))
+            .transpose()?
+            .flatten()
+        {
+            post.sign(&key.secret_key, &self.get_attachments(&post.post_id)?)?;
+            post.update(self)?;
+        }
+
+        Ok(post)
+    }
+
+    /// Encodes a profile, signed if we hold the identity's key
+    pub(crate) fn sign_user(&self, user: &User) -> anyhow::Result<proto::User> {
+        match self.get_signing_key(&user.identity)? {
+            Some(key) => Ok(user.to_signed_proto(&key.secret_key)?),
+            None => Ok(user.to_proto()),
+        }
+    }
+}
+
+impl SubrosaTransaction<'_> {
+    pub(crate) fn insert_message(&self, message: &Message) -> anyhow::Result<()> {
+        if message.is_file {
+            return self.insert_attachment_data(message);
+        }
+
+        let records = match SubrosaMessage::parse_records(&message.body) {
+            Ok(records) => records,
+            Err(err) => {
+                log::warn!("message parse failed {:?}", err);
+                return Ok(());
             }
-            Ok(SubrosaMessage::User(id)) => {
-                CachedIdentity::from_proto(id)?.insert_on_conflict(self, OnConflict::Ignore)
+        };
+        // a record that fails is skipped without undoing the rest of the batch
+        for (record, raw) in records {
+            if let Err(err) = self.savepoint(|tx| tx.insert_subrosa_message(record, &raw)) {
+                log::warn!("message insert failed {:?}", err);
             }
-            Ok(SubrosaMessage::MessageType(_)) => Ok(()),
-            Err(err) => Err(err.into()),
-        } {
-            log::warn!("message parse failed {:?}", err);
         }
         Ok(())
     }
 
-    pub fn process_scatter_messages(&self, messages: &[Message]) -> anyhow::Result<()> {
-        for message in messages {
-            self.insert_message(message)?;
+    /// Stores one record, `raw` being the bytes it was received as
+    fn insert_subrosa_message(&self, message: SubrosaMessage, raw: &[u8]) -> anyhow::Result<()> {
+        match message {
+            SubrosaMessage::Post(post) => self.insert_remote_post(post, raw),
+            SubrosaMessage::Newsgroup(news) => {
+                self.insert_remote_group(NewsGroup::from_proto(news)?)
+            }
+            SubrosaMessage::User(user) => {
+                self.insert_remote_user(PendingProfile::from_proto(user)?)
+            }
+            SubrosaMessage::Edit(edit) => self.insert_remote_edit(edit, raw),
+            SubrosaMessage::Retract(retraction) => {
+                self.insert_remote_tombstone(Tombstone::from_proto(retraction)?, raw)
+            }
+            SubrosaMessage::GroupKey(grant) => self.insert_remote_key_grant(grant),
+            SubrosaMessage::MessageType(_) => Ok(()),
+            // split into their records by `parse_records`
+            SubrosaMessage::Bundle(_) => Err(SubrosaErr::ParseError.into()),
+            SubrosaMessage::Unknown {
+                post_type,
+                version,
+                raw,
+            } => {
+                log::debug!("storing unknown message type {} v{}", post_type, version);
+                UnknownMessage::new(post_type, version, raw)
+                    .insert_on_conflict(self, OnConflict::Ignore)
+            }
+        }
+    }
+
+    pub(crate) fn insert_remote_post(
+        &self,
+        mut post: proto::Post,
+        raw: &[u8],
+    ) -> anyhow::Result<()> {
+        let group = post
+            .parent
+            .as_ref()
+            .and_then(|v| v.uuid)
+            .ok_or(SubrosaErr::ParseError)?
+            .as_uuid();
+
+        // posts carry their group, which may not have reached us on its own
+        if let Some(parent) = post.parent.clone() {
+            let parent = NewsGroup::from_proto(parent)?;
+            if self.get_group(parent.uuid)?.is_none() {
+                self.insert_remote_group(parent)?;
+            }
+        }
+
+        let policy = self.group_policy(&group)?;
+        if policy == UnsubscribedPolicy::Forward {
+            log::debug!("forwarding post for unsubscribed group {}", group);
+            ForwardPost {
+                post_id: post.uuid.ok_or(SubrosaErr::ParseError)?.as_uuid(),
+                group_id: group,
+            }
+            .insert_on_conflict(self, OnConflict::Ignore)?;
+            for attachment in post.attachments {
+                self.forward_attachment(attachment)?;
+            }
+            return self.forward(raw);
+        }
+
+        let post_id = post.uuid.ok_or(SubrosaErr::ParseError)?.as_uuid();
+        if self.is_post_pruned(&post_id)? != 0 {
+            log::debug!("dropping pruned post {}", post_id);
+            return Ok(());
+        }
+
+        if post.sealed.is_some() {
+            // without the key headers and body can't be told apart, so a
+            // sealed post is kept whole whatever the policy
+            match self.get_group_key(&group)? {
+                Some(key) => Posts::open_proto(&mut post, &key)?,
+                None => {
+                    // keep it as received so it can be opened if we are given the key
+                    log::debug!("storing sealed post {} for group {}", post_id, group);
+                    return SealedPost {
+                        post_id,
+                        parent_group: group,
+                        body: post.encode_to_vec(),
+                        sent: false,
+                    }
+                    .insert_on_conflict(self, OnConflict::Ignore);
+                }
+            }
+        }
+
+        let attachments = std::mem::take(&mut post.attachments);
+        let mut post = Posts::from_proto(post)?;
+        let mut attachments = attachments
+            .into_iter()
+            .map(|v| Attachment::from_proto(v, post_id))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if self.is_retracted(&post)? {
+            log::debug!("dropping retracted post {}", post.post_id);
+            return Ok(());
+        }
+
+        post.verification = self.check_signature(&post, &attachments)?;
+        if policy == UnsubscribedPolicy::HeadersOnly {
+            post.body = None;
+            attachments.clear();
+        }
+
+        // a copy that didn't verify may have arrived first with other
+        // attachments, the signed one replaces it whether stored or held
+        let stored = match self.get_post(&post_id)? {
+            Some(v) => Some(v.verification),
+            None => self.get_pending_post(&post_id)?.map(|v| v.verification),
+        };
+        let replaces =
+            post.verification == SigStatus::Valid && stored.is_some_and(|v| v != SigStatus::Valid);
+        let on_conflict = if replaces {
+            log::debug!("replacing unverified copy of post {}", post_id);
+            self.delete_attachments(&post_id)?;
+            OnConflict::Update
+        } else {
+            OnConflict::Ignore
+        };
+        self.insert_post_or_hold(post, on_conflict)?;
+
+        // attachments don't reference the post so they are stored even while it is held
+        Attachment::insert_many_on_conflict(&attachments, self, OnConflict::Ignore)?;
+        self.store_pending_attachment_data(&post_id)?;
+        self.delete_pending_attachment_data(&post_id)?;
+        Ok(())
+    }
+
+    /// Queues a message from an unsubscribed group to be passed on unchanged
+    fn forward(&self, raw: &[u8]) -> anyhow::Result<()> {
+        ForwardMessage::new(raw.to_vec()).insert_on_conflict(self, OnConflict::Ignore)
+    }
+
+    /// Forwards the contents of an attachment of a forwarded post, now if they
+    /// are pending or otherwise once they arrive
+    fn forward_attachment(&self, attachment: proto::Attachment) -> anyhow::Result<()> {
+        let Some(pending) = self.get_pending_attachment_data(&attachment.hash)? else {
+            return ForwardAttachment {
+                hash: attachment.hash,
+            }
+            .insert_on_conflict(self, OnConflict::Ignore);
+        };
+
+        let data = AttachmentData {
+            hash: pending.hash.clone(),
+            body: pending.body.clone(),
+        };
+        pending.delete(self)?;
+        ForwardMessage::file(data, attachment.file_name, attachment.mime)
+            .insert_on_conflict(self, OnConflict::Ignore)
+    }
+
+    /// Stores the contents of a file message. They are keyed by hash so they can
+    /// arrive before or after the post, but are only held in a bounded pending
+    /// store until a post refers to them. Contents of forwarded posts are
+    /// forwarded instead.
+    fn insert_attachment_data(&self, message: &Message) -> anyhow::Result<()> {
+        let body = &message.body;
+        if body.len() > MAX_ATTACHMENT_SIZE {
+            log::warn!("dropping file message of {} bytes", body.len());
+            return Ok(());
+        }
+
+        let data = AttachmentData::new(body.to_vec());
+        if self.is_attachment_referenced(&data.hash)? != 0 {
+            return data.insert_on_conflict(self, OnConflict::Ignore);
+        }
+
+        if let Some(forward) = self.get_forward_attachment(&data.hash)? {
+            forward.delete(self)?;
+            return ForwardMessage::file(data, message.file_name.clone(), message.mime.clone())
+                .insert_on_conflict(self, OnConflict::Ignore);
+        }
+
+        PendingAttachmentData::from(data).insert_on_conflict(self, OnConflict::Ignore)?;
+        self.trim_pending_attachment_data(MAX_PENDING_ATTACHMENT_BYTES as i64)?;
+        Ok(())
+    }
+
+    /// Policy of the group of a post, edits and retractions of posts we haven't
+    /// seen are stored
+    fn post_policy(&self, post_id: &Uuid) -> anyhow::Result<UnsubscribedPolicy> {
+        match self.get_post_group(post_id)? {
+            Some(group) => self.group_policy(&group),
+            None => Ok(UnsubscribedPolicy::Store),
+        }
+    }
+
+    /// Stores a post, or holds it as pending if its group hasn't arrived yet
+    fn insert_post_or_hold(&self, post: Posts, on_conflict: OnConflict) -> anyhow::Result<()> {
+        if self.get_group(post.parent_group)?.is_some() {
+            return post.insert_on_conflict(self, on_conflict);
+        }
+
+        log::debug!(
+            "holding post {} until group {} arrives",
+            post.post_id,
+            post.parent_group
+        );
+        PendingPost::from(post).insert_on_conflict(self, on_conflict)
+    }
+
+    fn is_retracted(&self, post: &Posts) -> anyhow::Result<bool> {
+        let Some(identity) = post.identity else {
+            return Ok(false);
+        };
+        Ok(self
+            .get_tombstone(&post.post_id, &identity)?
+            .is_some_and(|tombstone| tombstone.verification == SigStatus::Valid))
+    }
+
+    pub(crate) fn insert_remote_edit(
+        &self,
+        mut edit: proto::PostEdit,
+        raw: &[u8],
+    ) -> anyhow::Result<()> {
+        let post_id = edit.post.ok_or(SubrosaErr::ParseError)?.as_uuid();
+        let policy = self.post_policy(&post_id)?;
+        if policy == UnsubscribedPolicy::Forward {
+            log::debug!("forwarding edit of {} for unsubscribed group", post_id);
+            return self.forward(raw);
+        }
+        if self.is_post_pruned(&post_id)? != 0 {
+            log::debug!("dropping edit of pruned post {}", post_id);
+            return Ok(());
+        }
+
+        if edit.sealed.is_some() {
+            let key = self
+                .get_post(&post_id)?
+                .map(|v| self.get_group_key(&v.parent_group))
+                .transpose()?
+                .flatten();
+            let Some(key) = key else {
+                log::debug!("dropping sealed edit for unknown post {}", post_id);
+                return Ok(());
+            };
+            PostEdit::open_proto(&mut edit, &key)?;
+        }
+
+        let mut edit = PostEdit::from_proto(edit)?;
+        edit.verification = self.check_key(&edit.identity, |key| edit.verify(key))?;
+        if edit.verification == SigStatus::Invalid {
+            log::warn!("rejecting edit {} with invalid signature", edit.edit_id);
+            return Ok(());
+        }
+        if policy == UnsubscribedPolicy::HeadersOnly {
+            edit.body = String::new();
+        }
+
+        edit.insert_on_conflict(self, OnConflict::Ignore)
+    }
+
+    pub(crate) fn insert_remote_tombstone(
+        &self,
+        mut tombstone: Tombstone,
+        raw: &[u8],
+    ) -> anyhow::Result<()> {
+        if self.post_policy(&tombstone.post_id)? == UnsubscribedPolicy::Forward {
+            log::debug!("forwarding retraction of {}", tombstone.post_id);
+            return self.forward(raw);
+        }
+
+        tombstone.verification =
+            self.check_key(&tombstone.identity, |key| tombstone.verify(key))?;
+        if tombstone.verification == SigStatus::Invalid {
+            log::warn!(
+                "rejecting retraction of {} with invalid signature",
+                tombstone.post_id
+            );
+            return Ok(());
+        }
+
+        // only the author can retract a post, once it is known
+        if let Some(post) = self.get_post(&tombstone.post_id)? {
+            if post.identity != Some(tombstone.identity) {
+                log::warn!(
+                    "ignoring retraction of {} from non author",
+                    tombstone.post_id
+                );
+                return Ok(());
+            }
+        }
+
+        // an unverified tombstone must not block a verified one from the same signer
+        match self.get_tombstone(&tombstone.post_id, &tombstone.identity)? {
+            Some(existing) if existing.verification == SigStatus::Valid => return Ok(()),
+            _ => tombstone.insert_on_conflict(self, OnConflict::Update)?,
+        }
+
+        if tombstone.verification == SigStatus::Valid {
+            self.apply_tombstone(&tombstone)?;
+        }
+        Ok(())
+    }
+
+    /// Caches a peer's profile, replacing the cached one only if it is newer.
+    /// Profiles are held until the peer's key is known and dropped if the
+    /// signature doesn't match.
+    pub(crate) fn insert_remote_user(&self, profile: PendingProfile) -> anyhow::Result<()> {
+        match self.check_key(&profile.uuid, |key| profile.verify(key))? {
+            SigStatus::Valid => self.apply_profile(profile),
+            SigStatus::UnknownKey => {
+                profile.insert_on_conflict(self, OnConflict::Ignore)?;
+                Ok(())
+            }
+            SigStatus::Invalid => {
+                log::warn!("dropping profile of {} with a bad signature", profile.uuid);
+                Ok(())
+            }
+        }
+    }
+
+    fn apply_profile(&self, profile: PendingProfile) -> anyhow::Result<()> {
+        let user = CachedIdentity::from(profile);
+        user.insert_on_conflict(self, OnConflict::Ignore)?;
+        self.update_cached_profile(
+            &user.uuid,
+            &user.user_name,
+            &user.bio,
+            &user.image_bytes,
+            user.version,
+        )?;
+        Ok(())
+    }
+
+    /// Holds a group key sealed to one of our identities until it can be
+    /// checked against the group owner's key, then opens it
+    pub(crate) fn insert_remote_key_grant(&self, grant: proto::GroupKey) -> anyhow::Result<()> {
+        let grant = KeyGrant::from_proto(grant)?;
+        if self.get_signing_key(&grant.recipient)?.is_none()
+            || self.get_group_key(&grant.group_id)?.is_some()
+        {
+            return Ok(());
+        }
+
+        grant.insert_on_conflict(self, OnConflict::Ignore)?;
+        self.open_key_grants(grant.group_id)
+    }
+
+    /// Opens the first grant for `group` signed by the group's owner and any
+    /// posts that were waiting on the key. Grants stay held while the group or
+    /// the owner's key is missing, those with a bad signature are dropped.
+    fn open_key_grants(&self, group: Uuid) -> anyhow::Result<()> {
+        if self.get_group_key(&group)?.is_some() {
+            return Ok(());
+        }
+        let Some(owner) = self.get_group(group)?.and_then(|v| v.owner) else {
+            return Ok(());
+        };
+
+        for grant in self.get_received_key_grants(&group)? {
+            match self.check_key(&owner, |key| grant.verify(key))? {
+                SigStatus::UnknownKey => return Ok(()),
+                SigStatus::Valid => {}
+                SigStatus::Invalid => {
+                    log::warn!("dropping key grant {} not signed by owner", grant.grant_id);
+                    grant.delete(self)?;
+                    continue;
+                }
+            }
+
+            let Some(secret) = self.get_signing_key(&grant.recipient)? else {
+                continue;
+            };
+            let key = match grant.open(&secret.secret_key) {
+                Ok(key) => key,
+                Err(err) => {
+                    log::warn!("dropping key grant {}: {:?}", grant.grant_id, err);
+                    grant.delete(self)?;
+                    continue;
+                }
+            };
+            log::debug!("received key for group {}", key.group_id);
+            key.insert_on_conflict(self, OnConflict::Ignore)?;
+            self.delete_received_key_grants(&group)?;
+            break;
+        }
+
+        if self.get_group_key(&group)?.is_none() {
+            return Ok(());
+        }
+        // a sealed post that fails to open is kept rather than lost
+        for sealed in self.get_sealed_posts(&group)? {
+            let opened = self.savepoint(|tx| {
+                let post = proto::Post::decode(sealed.body.as_slice())?;
+                let raw = SubrosaMessage::Post(post.clone()).encode_to_vec()?;
+                tx.insert_remote_post(post, &raw)?;
+                tx.delete_sealed_post(&sealed.post_id)?;
+                Ok(())
+            });
+            if let Err(err) = opened {
+                log::warn!("failed to open sealed post {}: {:?}", sealed.post_id, err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes a retracted post along with its edits and attachments, as long
+    /// as the retraction comes from the post's author
+    pub(crate) fn apply_tombstone(&self, tombstone: &Tombstone) -> anyhow::Result<()> {
+        if let Some(post) = self.get_post(&tombstone.post_id)? {
+            if post.identity != Some(tombstone.identity) {
+                log::warn!(
+                    "ignoring retraction of {} from non author",
+                    tombstone.post_id
+                );
+                return Ok(());
+            }
+        }
+
+        self.delete_retracted_post(&tombstone.post_id, &tombstone.identity)?;
+        self.delete_post_edits(&tombstone.post_id)?;
+        self.delete_post_attachments(&tombstone.post_id)?;
+        Ok(())
+    }
+
+    /// Walks the ancestry of `group` checking that every parent hash matches the
+    /// stored parent. Groups with a missing ancestor are pending.
+    pub(crate) fn check_group_chain(&self, group: &NewsGroup) -> anyhow::Result<ChainStatus> {
+        let (mut parent, mut expected) = match (group.parent, &group.parent_hash) {
+            (None, None) => return Ok(ChainStatus::Valid),
+            (Some(parent), Some(hash)) => (parent, hash.clone()),
+            _ => return Ok(ChainStatus::Invalid),
+        };
+
+        let ancestors = self
+            .get_parents(&parent)?
+            .into_iter()
+            .map(|v| (v.uuid, v))
+            .collect::<HashMap<_, _>>();
+
+        for _ in 0..=ancestors.len() {
+            let Some(group) = ancestors.get(&parent) else {
+                return Ok(ChainStatus::Pending);
+            };
+
+            if group.hash() != expected {
+                return Ok(ChainStatus::Invalid);
+            }
+
+            match (group.parent, &group.parent_hash) {
+                (None, None) => return Ok(ChainStatus::Valid),
+                (Some(next), Some(hash)) => {
+                    parent = next;
+                    expected = hash.clone();
+                }
+                _ => return Ok(ChainStatus::Invalid),
+            }
+        }
+
+        Ok(ChainStatus::Invalid)
+    }
+
+    /// Stores a newsgroup received from a peer once its hash chain checks out,
+    /// releasing any pending subgroups that were waiting on it
+    pub(crate) fn insert_remote_group(&self, group: NewsGroup) -> anyhow::Result<()> {
+        if !group.owner_matches() {
+            log::warn!(
+                "rejecting newsgroup {} claimed by another owner",
+                group.uuid
+            );
+            return Ok(());
+        }
+        match self.check_group_chain(&group)? {
+            ChainStatus::Valid => {
+                group.insert_on_conflict(self, OnConflict::Ignore)?;
+                self.resolve_pending_posts(group.uuid)?;
+                self.open_key_grants(group.uuid)?;
+                self.resolve_pending_groups(group.uuid)?;
+            }
+            ChainStatus::Pending => {
+                log::debug!("holding newsgroup {} until parent arrives", group.uuid);
+                PendingNewsGroup::from(group).insert_on_conflict(self, OnConflict::Ignore)?;
+            }
+            ChainStatus::Invalid => {
+                log::warn!(
+                    "rejecting newsgroup {} with invalid parent hash",
+                    group.uuid
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve_pending_groups(&self, parent: Uuid) -> anyhow::Result<()> {
+        let mut parents = vec![parent];
+        while let Some(parent) = parents.pop() {
+            for pending in self.get_pending_children(&parent)? {
+                self.delete_pending_group(&pending.uuid)?;
+                let group = NewsGroup::from(pending);
+                match self.check_group_chain(&group)? {
+                    ChainStatus::Valid => {
+                        group.insert_on_conflict(self, OnConflict::Ignore)?;
+                        self.resolve_pending_posts(group.uuid)?;
+                        self.open_key_grants(group.uuid)?;
+                        parents.push(group.uuid);
+                    }
+                    status => {
+                        log::warn!("rejecting pending newsgroup {} ({:?})", group.uuid, status)
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores the posts that were held until `group` arrived
+    fn resolve_pending_posts(&self, group: Uuid) -> anyhow::Result<()> {
+        for pending in self.get_pending_posts(&group)? {
+            self.delete_pending_post(&pending.post_id)?;
+            let post = Posts::from(pending);
+            // a retraction may have arrived while the post was held
+            if !self.is_retracted(&post)? {
+                post.insert_on_conflict(self, OnConflict::Ignore)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_signature(
+        &self,
+        post: &Posts,
+        attachments: &[Attachment],
+    ) -> anyhow::Result<SigStatus> {
+        match post.identity {
+            Some(identity) => self.check_key(&identity, |key| post.verify(key, attachments)),
+            None => Ok(SigStatus::UnknownKey),
+        }
+    }
+
+    fn check_key(
+        &self,
+        identity: &Uuid,
+        verify: impl FnOnce(&[u8]) -> SigStatus,
+    ) -> anyhow::Result<SigStatus> {
+        let status = match self
+            .get_cached_identity(identity)?
+            .and_then(|v| v.public_key)
+        {
+            Some(key) => verify(&key),
+            None => SigStatus::UnknownKey,
+        };
+
+        Ok(status)
+    }
+
+    pub(crate) fn cache_identities(&self, identities: &[Identity]) -> anyhow::Result<()> {
+        for identity in identities {
+            let Some(fingerprint) = identity.fingerprint else {
+                continue;
+            };
+
+            if identity.public_key.is_empty() {
+                continue;
+            }
+
+            self.set_public_key(&fingerprint, identity.is_owned, &identity.public_key)?;
+
+            for post in self.get_unverified_posts(&fingerprint)? {
+                let attachments = self.get_attachments(&post.post_id)?;
+                let status = post.verify(&identity.public_key, &attachments);
+                self.set_verification(&post.post_id, status)?;
+            }
+
+            for edit in self.get_unverified_edits(&fingerprint)? {
+                self.set_edit_verification(&edit.edit_id, edit.verify(&identity.public_key))?;
+            }
+
+            for tombstone in self.get_unverified_tombstones(&fingerprint)? {
+                let status = tombstone.verify(&identity.public_key);
+                self.set_tombstone_verification(&tombstone.post_id, &tombstone.identity, status)?;
+                if status == SigStatus::Valid {
+                    self.apply_tombstone(&tombstone)?;
+                }
+            }
+
+            // the newest profile with a good signature wins
+            for profile in self.get_pending_profiles(&fingerprint)? {
+                if profile.verify(&identity.public_key) == SigStatus::Valid {
+                    self.apply_profile(profile)?;
+                    break;
+                }
+            }
+            self.delete_pending_profiles(&fingerprint)?;
+
+            for group in self.get_owned_groups(&fingerprint)? {
+                self.open_key_grants(group.uuid)?;
+            }
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use crate::api::{
+        db::{
+            connection::{Crud, SubrosaDb},
+            entities::{
+                AttachmentData, GroupKey, KeyGrant, NewsGroup, Posts, SigStatus, SubrosaDao,
+                Tombstone, User,
+            },
+            testing::{deliver, deliver_raw, peer, test_db},
+        },
+        proto::{
+            ser::{Message, SubrosaMessage},
+            ToUuid, APP_NAME,
+        },
+    };
+
+    use super::MAX_ATTACHMENT_SIZE;
+
+    #[test]
+    fn ingest_verification() {
+        let sender = test_db();
+        let receiver = test_db();
+
+        let key = ed25519_dalek::SigningKey::from_bytes(&[3; 32]);
+        let author = Uuid::new_v4();
+        let group = NewsGroup {
+            uuid: Uuid::new_v4(),
+            description: "test".to_owned(),
+            parent_hash: None,
+            parent: None,
+            group_name: "test".to_owned(),
+            sent: false,
+            owner: None,
+            owner_salt: None,
+        };
+        sender.insert_group(&group).unwrap();
+        receiver.insert_group(&group).unwrap();
+        sender
+            .set_signing_key(author, key.to_bytes().to_vec())
+            .unwrap();
+
+        let mut post = Posts::new("test".to_owned(), "body".to_owned(), &group.uuid);
+        post.identity = Some(author);
+        post.insert(&sender).unwrap();
+
+        let post = sender.sign_post(post).unwrap();
+        assert_eq!(post.verification, SigStatus::Valid);
+        deliver(
+            &receiver,
+            &SubrosaMessage::Post(post.to_proto(&sender).unwrap()),
+        );
+        let posts = receiver.get_posts(&group.uuid).unwrap();
+        assert_eq!(posts[0].verification, SigStatus::UnknownKey);
+
+        receiver
+            .cache_identities(&[peer(author, "author", &key)])
+            .unwrap();
+        let posts = receiver.get_posts(&group.uuid).unwrap();
+        assert_eq!(posts[0].verification, SigStatus::Valid);
+    }
+
+    #[test]
+    fn group_hash_chain() {
+        let db = test_db();
+
+        let root = NewsGroup::new(
+            Uuid::new_v4(),
+            "root".to_owned(),
+            None,
+            "root".to_owned(),
+            true,
+        );
+        let child = NewsGroup::new(
+            Uuid::new_v4(),
+            "child".to_owned(),
+            Some(root.as_parent()),
+            "child".to_owned(),
+            true,
+        );
+        let grandchild = NewsGroup::new(
+            Uuid::new_v4(),
+            "grandchild".to_owned(),
+            Some(child.as_parent()),
+            "grandchild".to_owned(),
+            true,
+        );
+        let mut forged = NewsGroup::new(
+            Uuid::new_v4(),
+            "forged".to_owned(),
+            Some(root.as_parent()),
+            "forged".to_owned(),
+            true,
+        );
+        forged.parent_hash = Some(vec![0; 32]);
+
+        // grandchild arrives first and waits for its parents
+        db.transaction(|tx| tx.insert_remote_group(grandchild.clone()))
+            .unwrap();
+        db.transaction(|tx| tx.insert_remote_group(child.clone()))
+            .unwrap();
+        assert!(db.get_group(grandchild.uuid).unwrap().is_none());
+
+        db.transaction(|tx| tx.insert_remote_group(root.clone()))
+            .unwrap();
+        db.transaction(|tx| tx.insert_remote_group(forged.clone()))
+            .unwrap();
+
+        assert!(db.get_group(child.uuid).unwrap().is_some());
+        assert!(db.get_group(grandchild.uuid).unwrap().is_some());
+        assert!(db.get_group(forged.uuid).unwrap().is_none());
+        assert!(db.get_pending_children(&child.uuid).unwrap().is_empty());
+    }
+
+    #[test]
+    fn attachments() {
+        let sender = test_db();
+        let receiver = test_db();
+
+        let group = NewsGroup::new(
+            Uuid::new_v4(),
+            "test".to_owned(),
+            None,
+            "test".to_owned(),
+            false,
+        );
+        sender.insert_group(&group).unwrap();
+        let post = Posts::new("test".to_owned(), "".to_owned(), &group.uuid);
+        post.insert(&sender).unwrap();
+        let attachment = sender
+            .add_attachment(
+                post.post_id,
+                "cat.png".to_owned(),
+                "image/png".to_owned(),
+                vec![1, 2, 3],
+            )
+            .unwrap();
+        assert_eq!(attachment.extension(), "png");
+
+        let mut file = Message::from_vec(vec![1, 2, 3], APP_NAME.to_owned());
+        file.is_file = true;
+        receiver.insert_message(&file).unwrap();
+
+        deliver(
+            &receiver,
+            &SubrosaMessage::Post(post.to_proto(&sender).unwrap()),
+        );
+
+        let received = receiver.get_attachments(&attachment.post_id).unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].attachment_id, attachment.attachment_id);
+        let data = receiver
+            .get_attachment_data(&received[0].hash)
+            .unwrap()
+            .unwrap();
+        assert_eq!(data.body, vec![1, 2, 3]);
+
+        // files no post refers to are held apart, trimmed to the newest
+        let pending = |db: &SubrosaDb| -> i64 {
+            db.connection()
+                .query_row("SELECT COUNT(*) FROM pending_attachment_data", [], |row| {
+                    row.get(0)
+                })
+                .unwrap()
+        };
+        assert_eq!(pending(&receiver), 0);
+        for body in [vec![4; 8], vec![0; MAX_ATTACHMENT_SIZE + 1]] {
+            let mut file = Message::from_vec(body, APP_NAME.to_owned());
+            file.is_file = true;
+            receiver.insert_message(&file).unwrap();
+        }
+        let stray = AttachmentData::new(vec![4; 8]);
+        assert!(receiver.get_attachment_data(&stray.hash).unwrap().is_none());
+        assert_eq!(pending(&receiver), 1);
+        receiver.trim_pending_attachment_data(8).unwrap();
+        assert_eq!(pending(&receiver), 1);
+        receiver.trim_pending_attachment_data(0).unwrap();
+        assert_eq!(pending(&receiver), 0);
+    }
+
+    #[test]
+    fn attachment_signatures() {
+        let sender = test_db();
+        let receiver = test_db();
+
+        let key = ed25519_dalek::SigningKey::from_bytes(&[5; 32]);
+        let author = Uuid::new_v4();
+        let group = NewsGroup::new(Uuid::new_v4(), "".to_owned(), None, "a".to_owned(), false);
+        sender.insert_group(&group).unwrap();
+        sender
+            .set_signing_key(author, key.to_bytes().to_vec())
+            .unwrap();
+        receiver
+            .set_public_key(&author, false, &key.verifying_key().to_bytes().to_vec())
+            .unwrap();
+
+        let mut post = Posts::new("test".to_owned(), "".to_owned(), &group.uuid);
+        post.identity = Some(author);
+        post.insert(&sender).unwrap();
+        let attachment = sender
+            .add_attachment(post.post_id, "cat.png".to_owned(), "".to_owned(), vec![1])
+            .unwrap();
+        let post = sender.sign_post(post).unwrap();
+        let post_id = post.post_id;
+        let proto = post.to_proto(&sender).unwrap();
+
+        let deliver = |proto: crate::proto::Post| deliver(&receiver, &SubrosaMessage::Post(proto));
+
+        // a copy naming another file arrives first and doesn't verify
+        let mut forged = proto.clone();
+        forged.attachments[0].hash = vec![9; 32];
+        deliver(forged);
+        let stored = receiver.get_post(&post_id).unwrap().unwrap();
+        assert_eq!(stored.verification, SigStatus::Invalid);
+
+        // the signed copy replaces it along with its attachments
+        deliver(proto);
+        let stored = receiver.get_post(&post_id).unwrap().unwrap();
+        assert_eq!(stored.verification, SigStatus::Valid);
+        let attachments = receiver.get_attachments(&post_id).unwrap();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].hash, attachment.hash);
+    }
+
+    #[test]
+    fn held_copy_replaced() {
+        let sender = test_db();
+        let receiver = test_db();
+
+        let key = ed25519_dalek::SigningKey::from_bytes(&[5; 32]);
+        let author = Uuid::new_v4();
+        let parent = NewsGroup::new(Uuid::new_v4(), "".to_owned(), None, "a".to_owned(), false);
+        let group = NewsGroup::new(
+            Uuid::new_v4(),
+            "".to_owned(),
+            Some(parent.as_parent()),
+            "b".to_owned(),
+            false,
+        );
+        sender.insert_group(&parent).unwrap();
+        sender.insert_group(&group).unwrap();
+        sender
+            .set_signing_key(author, key.to_bytes().to_vec())
+            .unwrap();
+        receiver
+            .set_public_key(&author, false, &key.verifying_key().to_bytes().to_vec())
+            .unwrap();
+
+        let mut post = Posts::new("test".to_owned(), "".to_owned(), &group.uuid);
+        post.identity = Some(author);
+        post.insert(&sender).unwrap();
+        let attachment = sender
+            .add_attachment(post.post_id, "cat.png".to_owned(), "".to_owned(), vec![1])
+            .unwrap();
+        let post = sender.sign_post(post).unwrap();
+        let post_id = post.post_id;
+        let proto = post.to_proto(&sender).unwrap();
+
+        // both copies arrive before the parent of their group, the forged one first
+        let mut forged = proto.clone();
+        forged.attachments[0].hash = vec![9; 32];
+        deliver(&receiver, &SubrosaMessage::Post(forged));
+        let held = receiver.get_pending_post(&post_id).unwrap().unwrap();
+        assert_eq!(held.verification, SigStatus::Invalid);
+
+        deliver(&receiver, &SubrosaMessage::Post(proto));
+        let held = receiver.get_pending_post(&post_id).unwrap().unwrap();
+        assert_eq!(held.verification, SigStatus::Valid);
+
+        deliver(&receiver, &SubrosaMessage::Newsgroup(parent.to_proto()));
+        let stored = receiver.get_post(&post_id).unwrap().unwrap();
+        assert_eq!(stored.verification, SigStatus::Valid);
+        let attachments = receiver.get_attachments(&post_id).unwrap();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].hash, attachment.hash);
+    }
+
+    #[test]
+    fn unknown_message_kept() {
+        let db = test_db();
+
+        let prefix = crate::proto::TypePrefix {
+            post_type: 42,
+            version: 7,
+            capabilities: 0,
+            compression: 0,
+        };
+        let mut raw = (prost::Message::encoded_len(&prefix) as i32)
+            .to_be_bytes()
+            .to_vec();
+        prost::Message::encode(&prefix, &mut raw).unwrap();
+        raw.extend_from_slice(&0i32.to_be_bytes());
+
+        deliver_raw(&db, raw.clone());
+        deliver_raw(&db, raw.clone());
+
+        let unknown = db.get_unsent_unknown_messages().unwrap();
+        assert_eq!(unknown.len(), 1);
+        assert_eq!(unknown[0].post_type, 42);
+        assert_eq!(unknown[0].body, raw);
+    }
+
+    #[test]
+    fn bundle_ingest() {
+        let sender = test_db();
+        let db = test_db();
+
+        let group = NewsGroup::new(
+            Uuid::new_v4(),
+            "".to_owned(),
+            None,
+            "test".to_owned(),
+            false,
+        );
+        sender.insert_group(&group).unwrap();
+        let posts = (0..8)
+            .map(|v| Posts::new(format!("header {}", v), "body".to_owned(), &group.uuid))
+            .collect::<Vec<_>>();
+
+        let records = std::iter::once(SubrosaMessage::Newsgroup(group.clone().to_proto()))
+            .chain(
+                posts
+                    .into_iter()
+                    .map(|v| SubrosaMessage::Post(v.to_proto(&sender).unwrap())),
+            )
+            .collect::<Vec<_>>();
+        let bundles = SubrosaMessage::bundle(records, 64 * 1024);
+        assert_eq!(bundles.len(), 1);
+
+        deliver(&db, &bundles[0]);
+
+        assert!(db.get_group(group.uuid).unwrap().is_some());
+        assert_eq!(db.get_posts(&group.uuid).unwrap().len(), 8);
+    }
+
+    #[test]
+    fn edit_and_retract() {
+        let sender = test_db();
+        let receiver = test_db();
+
+        let key = ed25519_dalek::SigningKey::from_bytes(&[5; 32]);
+        let author = Uuid::new_v4();
+        let group = NewsGroup::new(
+            Uuid::new_v4(),
+            "".to_owned(),
+            None,
+            "test".to_owned(),
+            false,
+        );
+        sender.insert_group(&group).unwrap();
+        receiver.insert_group(&group).unwrap();
+        sender
+            .set_signing_key(author, key.to_bytes().to_vec())
+            .unwrap();
+        receiver
+            .cache_identities(&[peer(author, "author", &key)])
+            .unwrap();
+
+        let mut post = Posts::new("test".to_owned(), "original".to_owned(), &group.uuid);
+        post.identity = Some(author);
+        post.insert(&sender).unwrap();
+        let post_id = post.post_id;
+        let post = SubrosaMessage::Post(sender.sign_post(post).unwrap().to_proto(&sender).unwrap());
+        deliver(&receiver, &post);
+
+        let edit = sender
+            .edit_post(post_id, "test".to_owned(), "edited".to_owned())
+            .unwrap();
+        deliver(&receiver, &SubrosaMessage::Edit(edit.to_proto()));
+
+        let mut forged = edit.to_proto();
+        forged.uuid = Some(Uuid::new_v4().as_proto());
+        forged.body = "forged".to_owned();
+        deliver(&receiver, &SubrosaMessage::Edit(forged));
+
+        let history = receiver.get_post_history(&post_id).unwrap();
+        assert_eq!(history.len(), 1);
+        let latest = receiver.get_latest_edit(&post_id).unwrap().unwrap();
+        assert_eq!(latest.body, "edited");
+        assert_eq!(
+            receiver
+                .get_post(&post_id)
+                .unwrap()
+                .unwrap()
+                .body
+                .as_deref(),
+            Some("original")
+        );
+
+        sender.retract_post(post_id).unwrap();
+        assert!(sender.get_post(&post_id).unwrap().is_none());
+        let tombstone = sender.get_unsent_tombstones().unwrap().pop().unwrap();
+        deliver(&receiver, &SubrosaMessage::Retract(tombstone.to_proto()));
+
+        assert!(receiver.get_post(&post_id).unwrap().is_none());
+        assert!(receiver.get_post_history(&post_id).unwrap().is_empty());
+
+        // a re-delivered original stays deleted
+        deliver(&receiver, &post);
+        assert!(receiver.get_post(&post_id).unwrap().is_none());
+    }
+
+    #[test]
+    fn retraction_from_non_author() {
+        let sender = test_db();
+        let receiver = test_db();
+
+        let key = ed25519_dalek::SigningKey::from_bytes(&[5; 32]);
+        let other_key = ed25519_dalek::SigningKey::from_bytes(&[6; 32]);
+        let author = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        let group = NewsGroup::new(Uuid::new_v4(), "".to_owned(), None, "a".to_owned(), false);
+        sender.insert_group(&group).unwrap();
+        sender
+            .set_signing_key(author, key.to_bytes().to_vec())
+            .unwrap();
+        for (identity, key) in [(author, &key), (other, &other_key)] {
+            receiver
+                .set_public_key(&identity, false, &key.verifying_key().to_bytes().to_vec())
+                .unwrap();
+        }
+
+        let post = |header: &str| {
+            let mut post = Posts::new(header.to_owned(), "".to_owned(), &group.uuid);
+            post.identity = Some(author);
+            post.insert(&sender).unwrap();
+            let post = sender.sign_post(post).unwrap();
+            (
+                post.post_id,
+                SubrosaMessage::Post(post.to_proto(&sender).unwrap()),
+            )
+        };
+        let forge = |post_id: Uuid| {
+            let post = sender.get_post(&post_id).unwrap().unwrap();
+            let mut tombstone = Tombstone::new(&post).unwrap();
+            tombstone.identity = other;
+            tombstone.sign(&other_key.to_bytes()).unwrap();
+            SubrosaMessage::Retract(tombstone.to_proto())
+        };
+        let retract = |post_id: Uuid| {
+            sender.retract_post(post_id).unwrap();
+            let tombstone = sender
+                .get_unsent_tombstones()
+                .unwrap()
+                .into_iter()
+                .find(|v| v.post_id == post_id)
+                .unwrap();
+            SubrosaMessage::Retract(tombstone.to_proto())
+        };
+
+        // both retractions arrive before the post, the forged one first
+        let (early, early_post) = post("early");
+        deliver(&receiver, &forge(early));
+        deliver(&receiver, &retract(early));
+        deliver(&receiver, &early_post);
+        assert!(receiver.get_post(&early).unwrap().is_none());
+
+        // once the post is known a retraction from anyone else is dropped
+        let (known, known_post) = post("known");
+        deliver(&receiver, &known_post);
+        deliver(&receiver, &forge(known));
+        assert!(receiver.get_post(&known).unwrap().is_some());
+        deliver(&receiver, &retract(known));
+        assert!(receiver.get_post(&known).unwrap().is_none());
+    }
+
+    #[test]
+    fn private_group() {
+        let sender = test_db();
+        let receiver = test_db();
+
+        let member_key = ed25519_dalek::SigningKey::from_bytes(&[9; 32]);
+        let member = Uuid::new_v4();
+        receiver
+            .set_signing_key(member, member_key.to_bytes().to_vec())
+            .unwrap();
+        sender
+            .cache_identities(&[peer(member, "member", &member_key)])
+            .unwrap();
+
+        let group = NewsGroup::new(
+            Uuid::new_v4(),
+            "".to_owned(),
+            None,
+            "secret".to_owned(),
+            false,
+        );
+        let owner_key = ed25519_dalek::SigningKey::from_bytes(&[3; 32]);
+        let owner = Uuid::new_v4();
+        sender
+            .set_signing_key(owner, owner_key.to_bytes().to_vec())
+            .unwrap();
+        let group = sender.insert_private_group(&group, owner).unwrap();
+        assert_eq!(group.owner, Some(owner));
+
+        deliver(
+            &receiver,
+            &SubrosaMessage::Newsgroup(group.clone().to_proto()),
+        );
+
+        let post = Posts::new("hidden".to_owned(), "body".to_owned(), &group.uuid);
+        let post = post.to_proto(&sender).unwrap();
+        assert!(post.header.is_empty() && post.body.is_empty());
+        assert!(post.sealed.is_some());
+
+        deliver(&receiver, &SubrosaMessage::Post(post));
+        assert!(receiver.get_posts(&group.uuid).unwrap().is_empty());
+        // sealed posts are passed on to other peers
+        assert_eq!(receiver.get_unsent_sealed_posts().unwrap().len(), 1);
+
+        // a grant for a key of someone else's choosing is never opened
+        let mut forged = KeyGrant::new(
+            &GroupKey::new(group.uuid),
+            member,
+            &member_key.verifying_key().to_bytes(),
+        )
+        .unwrap();
+        forged
+            .sign(&ed25519_dalek::SigningKey::from_bytes(&[4; 32]).to_bytes())
+            .unwrap();
+        deliver(&receiver, &SubrosaMessage::GroupKey(forged.to_proto()));
+        assert!(receiver.get_group_key(&group.uuid).unwrap().is_none());
+        receiver
+            .cache_identities(&[peer(owner, "owner", &owner_key)])
+            .unwrap();
+        assert!(receiver.get_group_key(&group.uuid).unwrap().is_none());
+        assert!(receiver
+            .get_received_key_grants(&group.uuid)
+            .unwrap()
+            .is_empty());
+
+        sender.add_group_member(group.uuid, member).unwrap();
+        let grant = sender.get_unsent_key_grants().unwrap().pop().unwrap();
+        deliver(&receiver, &SubrosaMessage::GroupKey(grant.to_proto()));
+
+        let posts = receiver.get_posts(&group.uuid).unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].header.as_deref(), Some("hidden"));
+        assert!(receiver.get_sealed_posts(&group.uuid).unwrap().is_empty());
+        assert!(receiver
+            .get_received_key_grants(&group.uuid)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn forged_group_owner() {
+        let sender = test_db();
+        let receiver = test_db();
+
+        let keys = [3, 4, 9].map(|v| ed25519_dalek::SigningKey::from_bytes(&[v; 32]));
+        let [owner_key, forger_key, member_key] = &keys;
+        let [owner, forger, member] = [(); 3].map(|_| Uuid::new_v4());
+        for (identity, key) in [(owner, owner_key), (forger, forger_key)] {
+            sender
+                .set_signing_key(identity, key.to_bytes().to_vec())
+                .unwrap();
+        }
+        sender
+            .cache_identities(&[peer(member, "member", member_key)])
+            .unwrap();
+        receiver
+            .set_signing_key(member, member_key.to_bytes().to_vec())
+            .unwrap();
+        receiver
+            .cache_identities(&[
+                peer(owner, "owner", owner_key),
+                peer(forger, "forger", forger_key),
+            ])
+            .unwrap();
+
+        let group = NewsGroup::new(Uuid::new_v4(), "".to_owned(), None, "a".to_owned(), false);
+        let group = sender.insert_private_group(&group, owner).unwrap();
+
+        // a copy claimed by someone else arrives first, alone and with a post,
+        // followed by a key of the forger's choosing
+        let mut forged = group.clone();
+        forged.owner = Some(forger);
+        forged.owner_salt = Some(vec![1; 16]);
+        assert_ne!(forged.hash(), group.hash());
+        deliver(
+            &receiver,
+            &SubrosaMessage::Newsgroup(forged.clone().to_proto()),
+        );
+        let mut post = Posts::new("".to_owned(), "".to_owned(), &group.uuid)
+            .to_proto(&sender)
+            .unwrap();
+        post.parent = Some(forged.to_proto());
+        deliver(&receiver, &SubrosaMessage::Post(post));
+        let mut grant = KeyGrant::new(
+            &GroupKey::new(group.uuid),
+            member,
+            &member_key.verifying_key().to_bytes(),
+        )
+        .unwrap();
+        grant.sign(&forger_key.to_bytes()).unwrap();
+        deliver(&receiver, &SubrosaMessage::GroupKey(grant.to_proto()));
+        assert!(receiver.get_group(group.uuid).unwrap().is_none());
+
+        // the owner's copy and key still get through
+        deliver(
+            &receiver,
+            &SubrosaMessage::Newsgroup(group.clone().to_proto()),
+        );
+        let stored = receiver.get_group(group.uuid).unwrap().unwrap();
+        assert_eq!(stored.owner, Some(owner));
+        assert!(receiver.get_group_key(&group.uuid).unwrap().is_none());
+        sender.add_group_member(group.uuid, member).unwrap();
+        let grant = sender.get_unsent_key_grants().unwrap().pop().unwrap();
+        deliver(&receiver, &SubrosaMessage::GroupKey(grant.to_proto()));
+        assert_eq!(
+            receiver.get_group_key(&group.uuid).unwrap().unwrap().key,
+            sender.get_group_key(&group.uuid).unwrap().unwrap().key
+        );
+    }
+
+    #[test]
+    fn profile_versions() {
+        let sender = test_db();
+        let receiver = test_db();
+
+        let identity = Uuid::new_v4();
+        let key = ed25519_dalek::SigningKey::from_bytes(&[6; 32]);
+        sender
+            .set_signing_key(identity, key.to_bytes().to_vec())
+            .unwrap();
+        let old = sender
+            .update_profile(identity, "old".to_owned(), "".to_owned(), vec![])
+            .unwrap();
+        let new = sender
+            .update_profile(identity, "new".to_owned(), "bio".to_owned(), vec![1, 2])
+            .unwrap();
+        assert!(new.version > old.version);
+        assert_eq!(sender.get_unsent_users().unwrap().len(), 1);
+
+        // an unsigned profile can't pin the cache with a huge version
+        let forged = crate::proto::User {
+            name: "forged".to_owned(),
+            version: u64::MAX,
+            ..new.to_proto()
+        };
+        deliver(&receiver, &SubrosaMessage::User(forged.clone()));
+        deliver(
+            &receiver,
+            &SubrosaMessage::User(sender.sign_user(&new).unwrap()),
+        );
+        assert!(receiver.get_cached_identity(&identity).unwrap().is_none());
+
+        receiver
+            .cache_identities(&[peer(identity, "peer", &key)])
+            .unwrap();
+        deliver(
+            &receiver,
+            &SubrosaMessage::User(sender.sign_user(&old).unwrap()),
+        );
+        deliver(&receiver, &SubrosaMessage::User(forged));
+
+        let cached = receiver.get_cached_identity(&identity).unwrap().unwrap();
+        assert_eq!(cached.user_name.as_deref(), Some("new"));
+        assert_eq!(cached.image_bytes, Some(vec![1, 2]));
+        assert_eq!(cached.version, new.version);
+        assert!(receiver.get_pending_profiles(&identity).unwrap().is_empty());
+    }
+
+    #[test]
+    fn identity_and_foreign_keys() {
+        let db = test_db();
+
+        let author = Uuid::new_v4();
+        let parent = NewsGroup::new(Uuid::new_v4(), "".to_owned(), None, "a".to_owned(), false);
+        let child = NewsGroup::new(
+            Uuid::new_v4(),
+            "".to_owned(),
+            Some(parent.as_parent()),
+            "b".to_owned(),
+            false,
+        );
+        let mut post = Posts::new("test".to_owned(), "body".to_owned(), &child.uuid);
+        post.identity = Some(author);
+        let post_id = post.post_id;
+
+        // a post is held until its group, which travels with it, can be stored
+        let mut proto = post.to_proto(&db).unwrap();
+        proto.parent = Some(child.clone().to_proto());
+        deliver(&db, &SubrosaMessage::Post(proto));
+        assert!(db.get_post(&post_id).unwrap().is_none());
+        deliver(&db, &SubrosaMessage::Newsgroup(parent.clone().to_proto()));
+        assert!(db.get_post(&post_id).unwrap().is_some());
+        assert!(db.get_pending_posts(&child.uuid).unwrap().is_empty());
+
+        // peer profiles and our own share one table
+        let author_key = ed25519_dalek::SigningKey::from_bytes(&[5; 32]);
+        db.set_public_key(
+            &author,
+            false,
+            &author_key.verifying_key().to_bytes().to_vec(),
+        )
+        .unwrap();
+        deliver(
+            &db,
+            &SubrosaMessage::User(
+                User {
+                    identity: author,
+                    user_name: "author".to_owned(),
+                    bio: "".to_owned(),
+                    owned: false,
+                    image_bytes: vec![],
+                    version: 1,
+                    sent: false,
+                }
+                .to_signed_proto(&author_key.to_bytes())
+                .unwrap(),
+            ),
+        );
+        let posts = db.get_posts_with_identity(&child.uuid).unwrap();
+        assert_eq!(posts[0].author.as_deref(), Some("author"));
+
+        let me = Uuid::new_v4();
+        db.set_public_key(&me, true, &vec![1]).unwrap();
+        db.update_profile(me, "me".to_owned(), "".to_owned(), vec![])
+            .unwrap();
+        let cached = db.get_cached_identity(&me).unwrap().unwrap();
+        assert_eq!(cached.owned, Some(true));
+        assert_eq!(cached.public_key, Some(vec![1]));
+        assert_eq!(db.get_user(me).unwrap().unwrap().user_name, "me");
+        let unsent = db.get_unsent_users().unwrap();
+        assert_eq!(unsent.len(), 1);
+        assert_eq!(unsent[0].identity, me);
+
+        // deleting a group removes its subgroups and their posts
+        assert!(Posts::new("x".to_owned(), "x".to_owned(), &Uuid::new_v4())
+            .insert(&db)
+            .is_err());
+        db.delete_group(parent.uuid).unwrap();
+        assert!(db.get_group(child.uuid).unwrap().is_none());
+        assert!(db.get_post(&post_id).unwrap().is_none());
+    }
+}
@@ -0,0 +1,56 @@
+//! Helpers shared by the database tests
+
+use std::collections::HashMap;
+
+use scatterbrain::types::Identity;
+use uuid::Uuid;
+
+use crate::api::proto::{
+    ser::{Message, SubrosaMessage},
+    APP_NAME,
+};
+
+use super::{connection::SubrosaDb, entities::NewsGroup, migrations::run_migrations};
+
+/// An in-memory database with every migration applied
+pub(crate) fn test_db() -> SubrosaDb {
+    let db = SubrosaDb::new_in_memory().unwrap();
+    run_migrations(&db).unwrap();
+    db
+}
+
+/// Stores a new root group and returns its id
+pub(crate) fn test_group(db: &SubrosaDb) -> Uuid {
+    let group = NewsGroup::new(
+        Uuid::new_v4(),
+        "".to_owned(),
+        None,
+        "test".to_owned(),
+        false,
+    );
+    db.insert_group(&group).unwrap();
+    group.uuid
+}
+
+/// Hands `message` to `db` as if it was received from a peer
+pub(crate) fn deliver(db: &SubrosaDb, message: &SubrosaMessage) {
+    deliver_raw(db, message.encode_to_vec().unwrap());
+}
+
+/// Hands an already encoded message to `db` as if it was received from a peer
+pub(crate) fn deliver_raw(db: &SubrosaDb, body: Vec<u8>) {
+    db.insert_message(&Message::from_vec(body, APP_NAME.to_owned()))
+        .unwrap();
+}
+
+/// The identity of a peer signing with `key`, as scatterbrain reports it
+pub(crate) fn peer(fingerprint: Uuid, name: &str, key: &ed25519_dalek::SigningKey) -> Identity {
+    Identity {
+        fingerprint: Some(fingerprint),
+        name: name.to_owned(),
+        public_key: key.verifying_key().to_bytes().to_vec(),
+        is_owned: false,
+        extra: HashMap::new(),
+        sig: Vec::new(),
+    }
+}
@@ -3,7 +3,7 @@ use scatterbrain::types::{Message, SbSession};
 use super::{
     db::{
         connection::SubrosaDb,
-        entities::{NewsGroup, Posts},
+        entities::{NewsGroup, Posts, SubrosaDao, User},
     },
     proto::{ser::SubrosaMessage, APP_NAME},
 };
@@ -14,13 +14,14 @@ pub use anyhow::Result;
 pub trait Sender: Send + Sync {
     async fn send_post(&self, post: Posts, db: &SubrosaDb) -> Result<()>;
     async fn send_newsgroup(&self, newsgroup: NewsGroup) -> Result<()>;
+    async fn send_user(&self, user: User, db: &SubrosaDb) -> Result<()>;
 }
 
 impl Sender for SbSession {
     async fn send_post(&self, post: Posts, db: &SubrosaDb) -> Result<()> {
         let id = post.identity;
 
-        let post = post.to_proto(db)?;
+        let post = db.sign_post(post)?.to_proto(db)?;
         let v = SubrosaMessage::Post(post).encode_to_vec()?;
         let message = Message::from_vec(v, APP_NAME.to_owned());
         self.send_messages(vec![message], id).await?;
@@ -34,6 +35,16 @@ impl Sender for SbSession {
         self.send_messages(vec![message], None).await?;
         Ok(())
     }
+
+    async fn send_user(&self, user: User, db: &SubrosaDb) -> Result<()> {
+        let v = SubrosaMessage::User(db.sign_user(&user)?).encode_to_vec()?;
+        let message = Message::from_vec(v, APP_NAME.to_owned());
+
+        self.send_messages(vec![message], Some(user.identity))
+            .await?;
+        db.mark_sent_users(vec![user.identity.into()])?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
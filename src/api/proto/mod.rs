@@ -2,6 +2,24 @@ use crate::proto::ProtoUuid;
 
 pub(crate) const APP_NAME: &str = "newsnet";
 
+/// Wire format version written by this client. Readers parse messages from newer
+/// versions as long as the post type and required capabilities are known, anything
+/// else is kept as `SubrosaMessage::Unknown` and forwarded untouched.
+pub(crate) const PROTOCOL_VERSION: u32 = 1;
+
+/// Capability bit set when the payload is compressed with `TypePrefix.compression`
+pub(crate) const CAP_COMPRESSION: u64 = 1;
+
+/// Capability bit set on bundles. A node that doesn't understand them keeps the
+/// whole bundle as `SubrosaMessage::Unknown`.
+pub(crate) const CAP_BUNDLE: u64 = 1 << 1;
+
+/// Capability bits this client understands
+pub(crate) const CAPABILITIES: u64 = CAP_COMPRESSION | CAP_BUNDLE;
+
+/// Upper bound on the size of a payload after decompression
+pub(crate) const MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+
 pub mod ser;
 pub mod types;
 
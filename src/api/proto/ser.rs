@@ -6,7 +6,13 @@ use flutter_rust_bridge::frb;
 use prost::{bytes::BufMut, Message as Ser};
 pub use scatterbrain::response::Message;
 
-use super::APP_NAME;
+use std::borrow::Cow;
+
+use miniz_oxide::inflate::TINFLStatus;
+
+use super::{
+    APP_NAME, CAPABILITIES, CAP_BUNDLE, CAP_COMPRESSION, MAX_DECOMPRESSED_SIZE, PROTOCOL_VERSION,
+};
 
 #[derive(Clone, Debug)]
 pub enum SubrosaMessage {
@@ -14,23 +20,53 @@ pub enum SubrosaMessage {
     Newsgroup(proto::NewsGroup),
     Post(proto::Post),
     User(proto::User),
+    Edit(proto::PostEdit),
+    Retract(proto::Retraction),
+    GroupKey(proto::GroupKey),
+    /// Several messages packed into one scatterbrain message. Bundles never nest.
+    Bundle(Vec<SubrosaMessage>),
+    /// A message from a newer client this version can't decode. `raw` is the
+    /// complete original message so it can be forwarded unchanged.
+    Unknown {
+        post_type: i32,
+        version: u32,
+        raw: Vec<u8>,
+    },
 }
 
-fn parse_length_delimited<T>(message: &[u8]) -> Result<(T, &'_ [u8])>
-where
-    T: prost::Message + Default,
-{
+fn split_length_delimited(message: &[u8]) -> Result<(&'_ [u8], &'_ [u8])> {
     if message.len() < 4 {
         return Err(crate::error::SubrosaErr::ParseError);
     }
-    let len: i32 = i32::from_be_bytes(message[0..4].try_into().unwrap());
+    let len = u32::from_be_bytes(message[0..4].try_into().unwrap()) as usize;
 
-    if message.len() < 4 + len as usize {
+    if message.len() - 4 < len || len > i32::MAX as usize {
         return Err(crate::error::SubrosaErr::ParseError);
     }
 
-    let m = &message[4..4 + len as usize];
-    Ok((Ser::decode(m)?, &message[4 + len as usize..]))
+    Ok((&message[4..4 + len], &message[4 + len..]))
+}
+
+fn parse_length_delimited<T>(message: &[u8]) -> Result<(T, &'_ [u8])>
+where
+    T: prost::Message + Default,
+{
+    let (m, rest) = split_length_delimited(message)?;
+    Ok((Ser::decode(m)?, rest))
+}
+
+fn decompress(compression: proto::Compression, payload: &[u8]) -> Result<Cow<'_, [u8]>> {
+    match compression {
+        proto::Compression::None => Ok(Cow::Borrowed(payload)),
+        proto::Compression::Deflate => {
+            miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(payload, MAX_DECOMPRESSED_SIZE)
+                .map(Cow::Owned)
+                .map_err(|err| match err.status {
+                    TINFLStatus::HasMoreOutput => crate::error::SubrosaErr::PayloadTooLarge,
+                    _ => crate::error::SubrosaErr::ParseError,
+                })
+        }
+    }
 }
 
 impl SubrosaMessage {
@@ -50,39 +86,173 @@ impl SubrosaMessage {
     // }
 
     pub async fn get_message(&self) -> anyhow::Result<Message> {
-        let size = match self {
-            SubrosaMessage::Post(m) => m.encoded_len(),
-            SubrosaMessage::MessageType(m) => m.encoded_len(),
-            SubrosaMessage::User(m) => m.encoded_len(),
-            SubrosaMessage::Newsgroup(m) => m.encoded_len(),
-        };
-        let mut v = Vec::with_capacity(size);
+        let mut v = Vec::with_capacity(self.encoded_len());
         self.encode(&mut v)?;
         let m = Message::from_vec(v, APP_NAME.to_owned());
         Ok(m)
     }
 
+    /// Packs messages into bundles of at most `max_size` encoded bytes. A message
+    /// that doesn't fit alongside others is left unbundled.
+    pub(crate) fn bundle(messages: Vec<SubrosaMessage>, max_size: usize) -> Vec<SubrosaMessage> {
+        let overhead = SubrosaMessage::Bundle(Vec::new()).encoded_len();
+        let mut bundles = Vec::new();
+        let mut records = Vec::new();
+        let mut size = overhead;
+
+        for message in messages {
+            let len = 4 + message.encoded_len();
+            if !records.is_empty() && size + len > max_size {
+                bundles.push(SubrosaMessage::from_records(std::mem::take(&mut records)));
+                size = overhead;
+            }
+            size += len;
+            records.push(message);
+        }
+
+        if !records.is_empty() {
+            bundles.push(SubrosaMessage::from_records(records));
+        }
+
+        bundles
+    }
+
+    fn from_records(mut records: Vec<SubrosaMessage>) -> SubrosaMessage {
+        if records.len() == 1 {
+            records.pop().unwrap()
+        } else {
+            SubrosaMessage::Bundle(records)
+        }
+    }
+
     pub(crate) fn parse(message: &[u8]) -> Result<SubrosaMessage> {
-        let (t, message): (proto::TypePrefix, _) = parse_length_delimited(message)?;
-        let typelen = t.encoded_len();
-        if message.len() < (4 + typelen) {
+        Self::parse_message(message, true, None)
+    }
+
+    /// Parses a message into its records along with the bytes of each, so they
+    /// can be passed on exactly as received. A message that isn't a bundle is
+    /// its only record.
+    pub(crate) fn parse_records(message: &[u8]) -> Result<Vec<(SubrosaMessage, Vec<u8>)>> {
+        let mut raw = Vec::new();
+        Ok(match Self::parse_message(message, true, Some(&mut raw))? {
+            SubrosaMessage::Bundle(records) => records.into_iter().zip(raw).collect(),
+            record => vec![(record, message.to_vec())],
+        })
+    }
+
+    /// Parses one message, collecting the bytes of each bundled record into `raw`
+    fn parse_message(
+        message: &[u8],
+        allow_bundle: bool,
+        mut raw: Option<&mut Vec<Vec<u8>>>,
+    ) -> Result<SubrosaMessage> {
+        let (t, payload): (proto::TypePrefix, _) = parse_length_delimited(message)?;
+
+        let known = proto::PostType::try_from(t.post_type)
+            .ok()
+            .zip(proto::Compression::try_from(t.compression).ok());
+        let (post_type, compression) = match known {
+            Some(known) if t.capabilities & !CAPABILITIES == 0 => known,
+            _ => {
+                return Ok(SubrosaMessage::Unknown {
+                    post_type: t.post_type,
+                    version: t.version,
+                    raw: message.to_vec(),
+                })
+            }
+        };
+
+        // records inside a bundle are never compressed on their own, only the
+        // bundle as a whole, so the size cap applies once per message
+        let compressed = compression != proto::Compression::None;
+        if compressed && (t.capabilities & CAP_COMPRESSION == 0 || !allow_bundle) {
             return Err(crate::error::SubrosaErr::ParseError);
         }
 
-        let r = match t.post_type() {
-            proto::PostType::Type => {
-                SubrosaMessage::MessageType(parse_length_delimited(message)?.0)
-            }
-            proto::PostType::Post => SubrosaMessage::Post(parse_length_delimited(message)?.0),
-            proto::PostType::User => SubrosaMessage::User(parse_length_delimited(message)?.0),
-            proto::PostType::Newsgroup => {
-                SubrosaMessage::Newsgroup(parse_length_delimited(message)?.0)
+        let (payload, _) = split_length_delimited(payload)?;
+        let payload = decompress(compression, payload)?;
+
+        let r = match post_type {
+            proto::PostType::Type => SubrosaMessage::MessageType(Ser::decode(&*payload)?),
+            proto::PostType::Post => SubrosaMessage::Post(Ser::decode(&*payload)?),
+            proto::PostType::User => SubrosaMessage::User(Ser::decode(&*payload)?),
+            proto::PostType::Newsgroup => SubrosaMessage::Newsgroup(Ser::decode(&*payload)?),
+            proto::PostType::Edit => SubrosaMessage::Edit(Ser::decode(&*payload)?),
+            proto::PostType::Retract => SubrosaMessage::Retract(Ser::decode(&*payload)?),
+            proto::PostType::GroupKey => SubrosaMessage::GroupKey(Ser::decode(&*payload)?),
+            proto::PostType::Bundle if allow_bundle && t.capabilities & CAP_BUNDLE != 0 => {
+                let mut payload = &*payload;
+                let mut records = Vec::new();
+                while !payload.is_empty() {
+                    let (record, rest) = split_length_delimited(payload)?;
+                    records.push(Self::parse_message(record, false, None)?);
+                    if let Some(raw) = raw.as_mut() {
+                        raw.push(record.to_vec());
+                    }
+                    payload = rest;
+                }
+                SubrosaMessage::Bundle(records)
             }
+            proto::PostType::Bundle => return Err(crate::error::SubrosaErr::ParseError),
         };
 
         Ok(r)
     }
 
+    fn post_type(&self) -> Option<proto::PostType> {
+        match self {
+            SubrosaMessage::MessageType(_) => Some(proto::PostType::Type),
+            SubrosaMessage::Newsgroup(_) => Some(proto::PostType::Newsgroup),
+            SubrosaMessage::Post(_) => Some(proto::PostType::Post),
+            SubrosaMessage::User(_) => Some(proto::PostType::User),
+            SubrosaMessage::Edit(_) => Some(proto::PostType::Edit),
+            SubrosaMessage::Retract(_) => Some(proto::PostType::Retract),
+            SubrosaMessage::GroupKey(_) => Some(proto::PostType::GroupKey),
+            SubrosaMessage::Bundle(_) => Some(proto::PostType::Bundle),
+            SubrosaMessage::Unknown { .. } => None,
+        }
+    }
+
+    fn prefix(post_type: proto::PostType, compression: proto::Compression) -> proto::TypePrefix {
+        let mut capabilities = match compression {
+            proto::Compression::None => 0,
+            _ => CAP_COMPRESSION,
+        };
+        if post_type == proto::PostType::Bundle {
+            capabilities |= CAP_BUNDLE;
+        }
+        proto::TypePrefix {
+            post_type: post_type.into(),
+            version: PROTOCOL_VERSION,
+            capabilities,
+            compression: compression.into(),
+        }
+    }
+
+    fn payload_len(&self) -> usize {
+        match self {
+            SubrosaMessage::MessageType(m) => m.encoded_len(),
+            SubrosaMessage::Newsgroup(m) => m.encoded_len(),
+            SubrosaMessage::Post(m) => m.encoded_len(),
+            SubrosaMessage::User(m) => m.encoded_len(),
+            SubrosaMessage::Edit(m) => m.encoded_len(),
+            SubrosaMessage::Retract(m) => m.encoded_len(),
+            SubrosaMessage::GroupKey(m) => m.encoded_len(),
+            SubrosaMessage::Bundle(records) => records.iter().map(|v| 4 + v.encoded_len()).sum(),
+            SubrosaMessage::Unknown { raw, .. } => raw.len(),
+        }
+    }
+
+    /// Length of the message without compression, compressed messages are never larger
+    pub(crate) fn encoded_len(&self) -> usize {
+        match self.post_type() {
+            Some(t) => {
+                8 + Self::prefix(t, proto::Compression::None).encoded_len() + self.payload_len()
+            }
+            None => self.payload_len(),
+        }
+    }
+
     pub(crate) fn encode_to_vec(&self) -> Result<Vec<u8>> {
         let mut v = Vec::new();
         self.encode(&mut v)?;
@@ -93,33 +263,62 @@ impl SubrosaMessage {
     where
         T: BufMut,
     {
-        let (t, l) = match self {
-            SubrosaMessage::MessageType(m) => (proto::PostType::Type, m.encoded_len()),
-            SubrosaMessage::Newsgroup(m) => (proto::PostType::Newsgroup, m.encoded_len()),
-            SubrosaMessage::Post(m) => (proto::PostType::Post, m.encoded_len()),
-            SubrosaMessage::User(m) => (proto::PostType::User, m.encoded_len()),
-        };
+        self.encode_message(writer, true)
+    }
 
-        println!("wrote size {}", l);
+    fn encode_message<T>(&self, writer: &mut T, compress: bool) -> Result<()>
+    where
+        T: BufMut,
+    {
+        let Some(t) = self.post_type() else {
+            if let SubrosaMessage::Unknown { raw, .. } = self {
+                writer.put_slice(raw);
+            }
+            return Ok(());
+        };
 
-        let tp = proto::TypePrefix {
-            post_type: t.into(),
+        let mut payload = Vec::with_capacity(self.payload_len());
+        match self {
+            SubrosaMessage::MessageType(m) => m.encode(&mut payload)?,
+            SubrosaMessage::Newsgroup(m) => m.encode(&mut payload)?,
+            SubrosaMessage::Post(m) => m.encode(&mut payload)?,
+            SubrosaMessage::User(m) => m.encode(&mut payload)?,
+            SubrosaMessage::Edit(m) => m.encode(&mut payload)?,
+            SubrosaMessage::Retract(m) => m.encode(&mut payload)?,
+            SubrosaMessage::GroupKey(m) => m.encode(&mut payload)?,
+            SubrosaMessage::Bundle(records) => {
+                for record in records {
+                    if let SubrosaMessage::Bundle(_) = record {
+                        return Err(crate::error::SubrosaErr::ParseError);
+                    }
+                    payload.put_i32(record.encoded_len() as i32);
+                    record.encode_message(&mut payload, false)?;
+                }
+            }
+            SubrosaMessage::Unknown { .. } => unreachable!(),
         };
-        writer.put_i32(tp.encoded_len() as i32);
-        tp.encode(writer)?;
 
-        if l > i32::MAX as usize {
-            return Err(crate::error::SubrosaErr::ParseError);
+        let mut tp = Self::prefix(t, proto::Compression::None);
+        if compress {
+            let compressed = miniz_oxide::deflate::compress_to_vec_zlib(&payload, 6);
+            let prefix = Self::prefix(t, proto::Compression::Deflate);
+            // only worth it if the larger prefix is paid for
+            if prefix.encoded_len() + compressed.len() < tp.encoded_len() + payload.len() {
+                tp = prefix;
+                payload = compressed;
+            }
         }
 
-        writer.put_i32(l as i32);
+        log::trace!("wrote size {}", payload.len());
 
-        match self {
-            SubrosaMessage::MessageType(m) => m.encode(writer)?,
-            SubrosaMessage::Newsgroup(m) => m.encode(writer)?,
-            SubrosaMessage::Post(m) => m.encode(writer)?,
-            SubrosaMessage::User(m) => m.encode(writer)?,
-        };
+        if payload.len() > i32::MAX as usize {
+            return Err(crate::error::SubrosaErr::ParseError);
+        }
+
+        writer.put_i32(tp.encoded_len() as i32);
+        tp.encode(writer)?;
+        writer.put_i32(payload.len() as i32);
+        writer.put_slice(&payload);
 
         Ok(())
     }
@@ -127,9 +326,16 @@ impl SubrosaMessage {
 
 #[cfg(test)]
 mod test {
+    use prost::{bytes::BufMut, Message};
     use uuid::Uuid;
 
-    use crate::{api::proto::ToUuid, proto};
+    use crate::{
+        api::proto::{
+            ToUuid, CAP_BUNDLE, CAP_COMPRESSION, MAX_DECOMPRESSED_SIZE, PROTOCOL_VERSION,
+        },
+        error::SubrosaErr,
+        proto,
+    };
 
     use super::SubrosaMessage;
 
@@ -140,6 +346,8 @@ mod test {
             parent_option: None,
             uuid: Some(Uuid::new_v4().as_proto()),
             description: "test description".to_owned(),
+            owner: None,
+            owner_salt: Vec::new(),
         });
 
         let mut out = vec![];
@@ -148,4 +356,248 @@ mod test {
 
         SubrosaMessage::parse(&out).unwrap();
     }
+
+    fn test_group(name: &str) -> SubrosaMessage {
+        SubrosaMessage::Newsgroup(proto::NewsGroup {
+            name: name.to_owned(),
+            parent_option: None,
+            uuid: Some(Uuid::new_v4().as_proto()),
+            description: "test description".to_owned(),
+            owner: None,
+            owner_salt: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn bundle_roundtrip() {
+        let messages = (0..10)
+            .map(|v| test_group(&format!("group {}", v)))
+            .collect::<Vec<_>>();
+        let size = messages[0].encoded_len();
+
+        let bundles = SubrosaMessage::bundle(messages.clone(), size * 4);
+        assert!(bundles.len() > 1);
+
+        let mut unpacked = Vec::new();
+        for bundle in bundles {
+            let encoded = bundle.encode_to_vec().unwrap();
+            assert!(encoded.len() <= size * 4);
+            assert!(encoded.len() <= bundle.encoded_len());
+            match SubrosaMessage::parse(&encoded).unwrap() {
+                SubrosaMessage::Bundle(records) => unpacked.extend(records),
+                m => unpacked.push(m),
+            }
+        }
+
+        let names = unpacked
+            .into_iter()
+            .map(|v| match v {
+                SubrosaMessage::Newsgroup(g) => g.name,
+                _ => panic!("unexpected message"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names.len(), 10);
+        assert_eq!(names[9], "group 9");
+
+        let single = SubrosaMessage::bundle(vec![test_group("alone")], 1);
+        assert!(matches!(single[..], [SubrosaMessage::Newsgroup(_)]));
+
+        // a size of 0, the default, turns bundling off
+        let unbundled = SubrosaMessage::bundle(vec![test_group("a"), test_group("b")], 0);
+        assert!(matches!(
+            unbundled[..],
+            [SubrosaMessage::Newsgroup(_), SubrosaMessage::Newsgroup(_)]
+        ));
+    }
+
+    #[test]
+    fn bundle_needs_capability() {
+        let bundle = SubrosaMessage::Bundle(vec![test_group("a"), test_group("b")]);
+        let mut payload = vec![];
+        if let SubrosaMessage::Bundle(records) = &bundle {
+            for record in records {
+                let record = record.encode_to_vec().unwrap();
+                payload.put_i32(record.len() as i32);
+                payload.put_slice(&record);
+            }
+        }
+
+        let prefix = |capabilities| proto::TypePrefix {
+            post_type: proto::PostType::Bundle.into(),
+            version: PROTOCOL_VERSION,
+            capabilities,
+            compression: 0,
+        };
+        let records = SubrosaMessage::parse(&encode_raw(prefix(CAP_BUNDLE), &payload)).unwrap();
+        assert!(matches!(records, SubrosaMessage::Bundle(v) if v.len() == 2));
+        assert!(matches!(
+            SubrosaMessage::parse(&encode_raw(prefix(0), &payload)),
+            Err(SubrosaErr::ParseError)
+        ));
+
+        // the bit is set on everything this client bundles
+        let encoded = bundle.encode_to_vec().unwrap();
+        let (t, _): (proto::TypePrefix, _) = super::parse_length_delimited(&encoded).unwrap();
+        assert_ne!(t.capabilities & CAP_BUNDLE, 0);
+    }
+
+    #[test]
+    fn records_keep_raw_bytes() {
+        let records = vec![test_group("a"), test_group("b")];
+        let a = records[0].encode_to_vec().unwrap();
+        let b = records[1].encode_to_vec().unwrap();
+        let bundle = SubrosaMessage::Bundle(records).encode_to_vec().unwrap();
+        let records = SubrosaMessage::parse_records(&bundle).unwrap();
+        let raw = records.into_iter().map(|(_, v)| v).collect::<Vec<_>>();
+        assert_eq!(raw, vec![a.clone(), b]);
+
+        let single = SubrosaMessage::parse_records(&a).unwrap();
+        assert_eq!(single.len(), 1);
+        assert_eq!(single[0].1, a);
+    }
+
+    #[test]
+    fn nested_bundle_rejected() {
+        let inner = SubrosaMessage::Bundle(vec![test_group("a"), test_group("b")]);
+        let outer = SubrosaMessage::Bundle(vec![inner, test_group("c")]);
+        assert!(outer.encode_to_vec().is_err());
+    }
+
+    fn encode_raw(prefix: proto::TypePrefix, payload: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        out.put_i32(prefix.encoded_len() as i32);
+        prefix.encode(&mut out).unwrap();
+        out.put_i32(payload.len() as i32);
+        out.put_slice(payload);
+        out
+    }
+
+    #[test]
+    fn unknown_type_preserved() {
+        let raw = encode_raw(
+            proto::TypePrefix {
+                post_type: 99,
+                version: PROTOCOL_VERSION + 1,
+                capabilities: 0,
+                compression: 0,
+            },
+            &[1, 2, 3],
+        );
+
+        let m = SubrosaMessage::parse(&raw).unwrap();
+        assert!(matches!(
+            m,
+            SubrosaMessage::Unknown {
+                post_type: 99,
+                version,
+                ..
+            } if version == PROTOCOL_VERSION + 1
+        ));
+        assert_eq!(m.encode_to_vec().unwrap(), raw);
+    }
+
+    #[test]
+    fn unknown_capability_preserved() {
+        let user = proto::User {
+            identity: Some(Uuid::new_v4().as_proto()),
+            name: "test".to_owned(),
+            bio: "".to_owned(),
+            image: None,
+            version: 0,
+            sig: vec![],
+        };
+        let prefix = proto::TypePrefix {
+            post_type: proto::PostType::User.into(),
+            version: PROTOCOL_VERSION,
+            capabilities: 1 << 63,
+            compression: 0,
+        };
+
+        let raw = encode_raw(prefix, &user.encode_to_vec());
+        assert!(matches!(
+            SubrosaMessage::parse(&raw).unwrap(),
+            SubrosaMessage::Unknown { .. }
+        ));
+
+        let raw = encode_raw(
+            proto::TypePrefix {
+                capabilities: 0,
+                version: PROTOCOL_VERSION + 1,
+                ..prefix
+            },
+            &user.encode_to_vec(),
+        );
+        assert!(matches!(
+            SubrosaMessage::parse(&raw).unwrap(),
+            SubrosaMessage::User(_)
+        ));
+    }
+
+    fn test_post(body: String) -> proto::Post {
+        proto::Post {
+            uuid: Some(Uuid::new_v4().as_proto()),
+            header: "header".to_owned(),
+            body,
+            parent: None,
+            sig: vec![],
+            author_or: None,
+            reply_to: None,
+            attachments: vec![],
+            sealed: None,
+        }
+    }
+
+    #[test]
+    fn compression_roundtrip() {
+        let body = "all work and no play makes jack a dull boy\n".repeat(100);
+        let post = SubrosaMessage::Post(test_post(body.clone()));
+
+        let encoded = post.encode_to_vec().unwrap();
+        assert!(encoded.len() < body.len() / 4);
+        assert!(encoded.len() <= post.encoded_len());
+
+        match SubrosaMessage::parse(&encoded).unwrap() {
+            SubrosaMessage::Post(p) => assert_eq!(p.body, body),
+            _ => panic!("unexpected message"),
+        }
+
+        // incompressible payloads are sent as is
+        let small = test_group("a");
+        assert_eq!(small.encode_to_vec().unwrap().len(), small.encoded_len());
+    }
+
+    #[test]
+    fn decompression_bomb_rejected() {
+        let payload =
+            miniz_oxide::deflate::compress_to_vec_zlib(&vec![0u8; MAX_DECOMPRESSED_SIZE + 1], 6);
+        let prefix = proto::TypePrefix {
+            post_type: proto::PostType::Post.into(),
+            version: PROTOCOL_VERSION,
+            capabilities: CAP_COMPRESSION,
+            compression: proto::Compression::Deflate.into(),
+        };
+
+        let raw = encode_raw(prefix, &payload);
+        assert!(matches!(
+            SubrosaMessage::parse(&raw),
+            Err(SubrosaErr::PayloadTooLarge)
+        ));
+
+        // compressed records inside a compressed bundle would multiply the limit
+        let post = SubrosaMessage::Post(test_post("a".repeat(1000)));
+        let record = post.encode_to_vec().unwrap();
+        let mut bundle = vec![];
+        bundle.put_i32(record.len() as i32);
+        bundle.put_slice(&record);
+        let raw = encode_raw(
+            proto::TypePrefix {
+                post_type: proto::PostType::Bundle.into(),
+                capabilities: 0,
+                compression: 0,
+                ..prefix
+            },
+            &bundle,
+        );
+        assert!(SubrosaMessage::parse(&raw).is_err());
+    }
 }
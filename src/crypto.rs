@@ -0,0 +1,134 @@
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{
+    error::{Result, SubrosaErr},
+    proto,
+};
+
+/// Parses an ed25519 secret key, either a 32 byte seed or a 64 byte libsodium
+/// style keypair
+pub(crate) fn signing_key(secret_key: &[u8]) -> Result<SigningKey> {
+    match secret_key.len() {
+        32 => Ok(SigningKey::from_bytes(secret_key.try_into().unwrap())),
+        64 => SigningKey::from_keypair_bytes(secret_key.try_into().unwrap())
+            .map_err(|_| SubrosaErr::InvalidKey),
+        _ => Err(SubrosaErr::InvalidKey),
+    }
+}
+
+/// Generates a new symmetric key for a private newsgroup
+pub(crate) fn group_key() -> Vec<u8> {
+    ChaCha20Poly1305::generate_key(&mut OsRng).to_vec()
+}
+
+/// Generates the salt a private newsgroup's uuid is derived from
+pub(crate) fn group_salt() -> Vec<u8> {
+    let mut salt = vec![0; 16];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// Derives the uuid of a private newsgroup from its owner, so a copy of the
+/// group naming someone else as owner can't take its place
+pub(crate) fn owned_group_uuid(owner: &Uuid, salt: &[u8]) -> Uuid {
+    let digest = Sha256::new()
+        .chain_update(owner.as_bytes())
+        .chain_update(salt)
+        .finalize();
+    uuid::Builder::from_random_bytes(digest[..16].try_into().unwrap()).into_uuid()
+}
+
+fn cipher(key: &[u8]) -> Result<ChaCha20Poly1305> {
+    if key.len() != 32 {
+        return Err(SubrosaErr::InvalidKey);
+    }
+    Ok(ChaCha20Poly1305::new(Key::from_slice(key)))
+}
+
+/// Encrypts `msg` with a symmetric key. `aad` is authenticated but not
+/// encrypted and has to match on decryption.
+pub(crate) fn seal(key: &[u8], msg: &[u8], aad: &[u8]) -> Result<proto::Sealed> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher(key)?
+        .encrypt(&nonce, Payload { msg, aad })
+        .map_err(|_| SubrosaErr::CryptoError)?;
+
+    Ok(proto::Sealed {
+        nonce: nonce.to_vec(),
+        ciphertext,
+    })
+}
+
+pub(crate) fn open(key: &[u8], sealed: &proto::Sealed, aad: &[u8]) -> Result<Vec<u8>> {
+    if sealed.nonce.len() != 12 {
+        return Err(SubrosaErr::CryptoError);
+    }
+
+    cipher(key)?
+        .decrypt(
+            Nonce::from_slice(&sealed.nonce),
+            Payload {
+                msg: &sealed.ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| SubrosaErr::CryptoError)
+}
+
+/// Key used to wrap a group key, derived from an x25519 exchange between an
+/// ephemeral key and the recipient's ed25519 identity key
+fn wrapping_key(shared: &[u8], ephemeral: &[u8], recipient: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(shared);
+    hasher.update(ephemeral);
+    hasher.update(recipient);
+    hasher.finalize().to_vec()
+}
+
+/// Encrypts a group key to an identity's ed25519 public key. Returns the
+/// ephemeral public key the recipient needs to open it.
+pub(crate) fn seal_to(
+    public_key: &[u8],
+    msg: &[u8],
+    aad: &[u8],
+) -> Result<(Vec<u8>, proto::Sealed)> {
+    let recipient = public_key
+        .try_into()
+        .ok()
+        .and_then(|v| VerifyingKey::from_bytes(v).ok())
+        .ok_or(SubrosaErr::InvalidKey)?
+        .to_montgomery()
+        .to_bytes();
+
+    let ephemeral = StaticSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(&PublicKey::from(recipient));
+
+    let key = wrapping_key(shared.as_bytes(), ephemeral_public.as_bytes(), &recipient);
+    Ok((ephemeral_public.to_bytes().to_vec(), seal(&key, msg, aad)?))
+}
+
+/// Opens a group key sealed with [`seal_to`] using the recipient's ed25519 secret key
+pub(crate) fn open_from(
+    secret_key: &[u8],
+    ephemeral: &[u8],
+    sealed: &proto::Sealed,
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let secret_key = signing_key(secret_key)?;
+    let recipient = secret_key.verifying_key().to_montgomery().to_bytes();
+    let ephemeral: [u8; 32] = ephemeral.try_into().map_err(|_| SubrosaErr::CryptoError)?;
+
+    let shared = StaticSecret::from(secret_key.to_scalar_bytes())
+        .diffie_hellman(&PublicKey::from(ephemeral));
+
+    let key = wrapping_key(shared.as_bytes(), &ephemeral, &recipient);
+    open(&key, sealed, aad)
+}
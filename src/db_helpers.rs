@@ -1,7 +1,11 @@
-use crate::{api::db::entities::FromRow, error::Result};
+use crate::{
+    api::db::entities::FromRow,
+    error::{Result, SubrosaErr},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use fallible_iterator::{FallibleIterator, IteratorExt};
 use flutter_rust_bridge::frb;
-use rusqlite::Row;
+use rusqlite::{types::Value, Row, Statement};
 
 impl FromRow for i64 {
     fn from_row(row: &Row) -> Result<Self> {
@@ -29,3 +33,115 @@ where
         [T::from_row(&self)].into_iter().transpose_into_fallible()
     }
 }
+
+/// Index of the column backing a field of a derived `FromRow` entity
+pub(crate) fn column_index(stmt: &Statement, column: &str, entity: &'static str) -> Result<usize> {
+    stmt.column_index(column)
+        .map_err(|_| SubrosaErr::MissingColumn {
+            column: column.to_owned(),
+            entity,
+        })
+}
+
+/// Rejects query results with columns that don't map to a field of the entity
+pub(crate) fn check_columns(stmt: &Statement, fields: &[&str], entity: &'static str) -> Result<()> {
+    match stmt
+        .column_names()
+        .into_iter()
+        .find(|v| !fields.iter().any(|f| f.eq_ignore_ascii_case(v)))
+    {
+        Some(column) => Err(SubrosaErr::UnknownColumn {
+            column: column.to_owned(),
+            entity,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Encodes the keyset of the last row of a page as an opaque continuation token
+pub(crate) fn encode_cursor(key: &[Value]) -> String {
+    let mut buf = Vec::new();
+    for value in key {
+        match value {
+            Value::Null => buf.push(0),
+            Value::Integer(v) => {
+                buf.push(1);
+                buf.extend_from_slice(&v.to_be_bytes());
+            }
+            Value::Real(v) => {
+                buf.push(2);
+                buf.extend_from_slice(&v.to_be_bytes());
+            }
+            Value::Text(v) => {
+                buf.push(3);
+                buf.extend_from_slice(&(v.len() as u32).to_be_bytes());
+                buf.extend_from_slice(v.as_bytes());
+            }
+            Value::Blob(v) => {
+                buf.push(4);
+                buf.extend_from_slice(&(v.len() as u32).to_be_bytes());
+                buf.extend_from_slice(v);
+            }
+        }
+    }
+    URL_SAFE_NO_PAD.encode(buf)
+}
+
+/// Decodes a token from [`encode_cursor`], no token starts at the first page
+pub(crate) fn decode_cursor<const N: usize>(cursor: Option<&str>) -> Result<[Value; N]> {
+    let Some(cursor) = cursor else {
+        return Ok(std::array::from_fn(|_| Value::Null));
+    };
+
+    let buf = URL_SAFE_NO_PAD
+        .decode(cursor)
+        .map_err(|_| SubrosaErr::InvalidCursor)?;
+    let mut rest = buf.as_slice();
+    let mut take = |n: usize| -> Result<&[u8]> {
+        if rest.len() < n {
+            return Err(SubrosaErr::InvalidCursor);
+        }
+        let (head, tail) = rest.split_at(n);
+        rest = tail;
+        Ok(head)
+    };
+
+    let mut key = Vec::with_capacity(N);
+    for _ in 0..N {
+        let value = match take(1)?[0] {
+            0 => Value::Null,
+            1 => Value::Integer(i64::from_be_bytes(take(8)?.try_into().unwrap())),
+            2 => Value::Real(f64::from_be_bytes(take(8)?.try_into().unwrap())),
+            tag @ (3 | 4) => {
+                let len = u32::from_be_bytes(take(4)?.try_into().unwrap()) as usize;
+                let bytes = take(len)?.to_vec();
+                if tag == 3 {
+                    Value::Text(String::from_utf8(bytes).map_err(|_| SubrosaErr::InvalidCursor)?)
+                } else {
+                    Value::Blob(bytes)
+                }
+            }
+            _ => return Err(SubrosaErr::InvalidCursor),
+        };
+        key.push(value);
+    }
+
+    if !rest.is_empty() {
+        return Err(SubrosaErr::InvalidCursor);
+    }
+    Ok(key.try_into().unwrap())
+}
+
+/// Turns user input into an FTS5 query matching every word, the last of which
+/// may be incomplete. Words are quoted so FTS5 operators in the input are
+/// searched for literally. `None` when there is nothing to search for.
+pub(crate) fn fts_query(text: &str) -> Option<String> {
+    let words = text
+        .split_whitespace()
+        .map(|v| format!("\"{}\"", v.replace('"', "\"\"")))
+        .collect::<Vec<_>>();
+    if words.is_empty() {
+        return None;
+    }
+    Some(words.join(" ") + "*")
+}
@@ -4,20 +4,51 @@ pub(crate) type Result<T> = std::result::Result<T, SubrosaErr>;
 pub enum SubrosaErr {
     #[error("Invalid row")]
     InvalidRow,
+    #[error("Column `{column}` of {entity} missing from query result")]
+    MissingColumn {
+        column: String,
+        entity: &'static str,
+    },
+    #[error("Query returned column `{column}` unknown to {entity}")]
+    UnknownColumn {
+        column: String,
+        entity: &'static str,
+    },
     #[error("{0}")]
     SqliteError(#[from] rusqlite::Error),
     #[error("{0}")]
     MigrationError(#[from] rusqlite_migration::Error),
+    #[error("Database schema version {0} is newer than this release supports")]
+    SchemaTooNew(usize),
     #[error("{0}")]
     DecodeError(#[from] prost::DecodeError),
     #[error("{0}")]
     EncodeError(#[from] prost::EncodeError),
     #[error("Message parse error")]
     ParseError,
+    #[error("Decompressed payload exceeds size limit")]
+    PayloadTooLarge,
+    #[error("Invalid signing key")]
+    InvalidKey,
+    #[error("No signing key for post author")]
+    MissingKey,
+    #[error("Unknown post")]
+    UnknownPost,
+    #[error("Encryption error")]
+    CryptoError,
+    #[error("Invalid page cursor")]
+    InvalidCursor,
+    #[error("Page limit must be at least one")]
+    InvalidPageLimit,
+    #[error("{0}")]
+    TaskError(#[from] tokio::task::JoinError),
 }
 
 impl From<SubrosaErr> for rusqlite::Error {
-    fn from(_: SubrosaErr) -> Self {
-        rusqlite::Error::InvalidQuery
+    fn from(value: SubrosaErr) -> Self {
+        match value {
+            SubrosaErr::SqliteError(err) => err,
+            err => rusqlite::Error::UserFunctionError(Box::new(err)),
+        }
     }
 }
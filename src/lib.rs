@@ -3,6 +3,7 @@
 pub use crate::scatterbrain::api::types::GetType;
 use flutter_rust_bridge::frb;
 pub mod api;
+pub(crate) mod crypto;
 pub(crate) mod db_helpers;
 pub(crate) mod error;
 mod frb_generated;